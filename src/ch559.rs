@@ -2,17 +2,36 @@
 // Use of this source code is governed by a BSD-style license that can be found
 // in the LICENSE file.
 use rand::prelude::*;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(any(feature = "rusb", feature = "nusb"))]
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod chip;
+mod legacy;
 mod progress_bar;
+mod request;
+use crate::ch559::chip::{Chip, Family};
 use crate::ch559::progress_bar::ProgressBar;
+pub use crate::ch559::progress_bar::{MultiProgress, Output, ProgressLine};
+use crate::image::{Format, Image};
+use crate::transport::serial::LineStep;
+#[cfg(feature = "serial")]
+use crate::transport::SerialTransport;
+use crate::transport::Transport;
+#[cfg(any(feature = "rusb", feature = "nusb"))]
+use crate::transport::{Selector, UsbTransport};
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("failed to erase")]
     Erase,
+    #[error("sectors should be in 0..{0}")]
+    SectorRange(u32),
+    #[error("bootloader v2 erases sectors from 0 only")]
+    SectorStart,
     #[error("IO error")]
     Io(#[from] std::io::Error),
     #[error("failed to do a bulk write all data")]
@@ -20,11 +39,32 @@ pub enum Error {
     #[error("failed to do a bulk write")]
     BulkWrite,
     #[error("failed to do a bulk read response ({0})")]
-    BulkRead(rusb::Error),
+    BulkRead(String),
+    #[cfg(feature = "serial")]
+    #[error("failed to open the serial port ({0})")]
+    SerialOpen(serialport::Error),
+    #[error("invalid serial response")]
+    SerialResponse,
     #[error("failed to reset key")]
     ResetKey,
     #[error("unexpected EOF")]
     Eof,
+    #[error("invalid Intel HEX record at line {0}")]
+    IntelHex(usize),
+    #[error("invalid S-record at line {0}")]
+    SRecord(usize),
+    #[error("invalid ELF file")]
+    Elf,
+    #[error("invalid UF2 file")]
+    Uf2,
+    #[error("UF2 file is for another family (0x{0:08x})")]
+    Uf2Family(u32),
+    #[error("invalid fleet CSV at line {0}")]
+    Fleet(usize),
+    #[error("invalid template placeholder ({0})")]
+    Template(String),
+    #[error("unsupported output format")]
+    UnsupportedFormat,
     #[error("failed to detect EPs")]
     DetectEp,
     #[error("failed to check interfaces")]
@@ -37,6 +77,10 @@ pub enum Error {
     ClaimInterface,
     #[error("failed to receive a valid response on detect")]
     InvalidResponse,
+    #[error("unsupported chip (type: {0:02x}, id: {1:02x})")]
+    UnsupportedChip(u8, u8),
+    #[error("not supported on {0}")]
+    NotSupported(&'static str),
     #[error("{0} on detect")]
     OnDetect(Box<Error>),
     #[error("read size is too large")]
@@ -47,67 +91,379 @@ pub enum Error {
     Flash,
     #[error("failed to verify")]
     Verify,
+    #[error("image is empty, use fullfill to fill the whole area")]
+    EmptyImage,
+    #[error("code runs over data area from 0x{0:X}")]
+    DataOverwrite(usize),
+    #[error("not blank at 0x{0:x}")]
+    NotBlank(usize),
+    #[error("read back data differs at 0x{0:04x}")]
+    ReadBack(usize),
     #[error("not a regular file")]
     InvalidFile,
-    #[error("file size should be 0x400")]
-    FileSize,
+    #[error("failed to edit ({0})")]
+    Editor(String),
+    #[error("invalid field ({0})")]
+    Field(String),
+    #[error("invalid snapshot ({0})")]
+    Snapshot(String),
+    #[error("invalid manifest ({0})")]
+    Manifest(String),
+    #[error("invalid script ({0})")]
+    Script(String),
+    #[error("invalid DTR/RTS step ({0})")]
+    LineSteps(String),
+    #[error("invalid bundle ({0})")]
+    Bundle(String),
+    #[error("failed to decompress ({0})")]
+    Decompress(String),
+    #[error("failed to download ({0})")]
+    Download(String),
+    #[error("SHA-256 mismatch, got {0}")]
+    Checksum(String),
+    #[error("file size should be 0x{0:x}")]
+    FileSize(usize),
+    #[error("range is out of the data area")]
+    DataRange,
     #[error("file size is too large for data")]
     TooLargeDataSize,
     #[error("file size is too large for code")]
     TooLargeCodeSize,
     #[error("failed to initialize")]
     Initialize(Box<Error>),
-    #[error("CH559 Not Found")]
+    #[error("CH559 Not Found, {}", crate::transport::NOT_FOUND_HINT)]
     NotFound,
+    #[error("failed to list USB devices")]
+    ListDevices,
+    #[error("failed to open the USB device")]
+    Open,
+    #[error(
+        "USB device is found, but access is denied, {}",
+        crate::transport::ACCESS_HINT
+    )]
+    AccessDenied,
+    #[error(
+        "USB device is found, but no usable driver is bound, {}",
+        crate::transport::DRIVER_HINT
+    )]
+    NoDriver,
+    #[error("failed to switch the hub port power")]
+    PortPower,
+    #[error("failed to reset the USB device")]
+    Reset,
+    #[error("failed to use the WCH vendor driver ({0})")]
+    VendorDriver(String),
     #[error("failed to write config")]
     WriteConfig,
+    #[error("config {0:02x} may disable the bootloader entry")]
+    RiskyConfig(u8),
+    #[error("config is read back as {1:02x} instead of {0:02x}")]
+    ConfigReadBack(u16, u16),
     #[error("unsupported bootloader version")]
     WriteConfigVersion,
 }
 
+// Bootloader protocol. Bootloader v1.x uses a shorter command framing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    V1,
+    V2,
+}
+
+// Values that unused area is filled with on fullfill.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Fill {
+    // Pseudo random values from the seed.
+    #[default]
+    Random,
+    Byte(u8),
+    // A pattern repeated from address 0 so that it can be found in dumps.
+    Pattern(Vec<u8>),
+}
+
+// Result of a compare that scans the whole image instead of stopping at the
+// first mismatch.
+#[derive(Debug, Default)]
+pub struct Mismatch {
+    // Addresses of 0x38 bytes chunks that differ.
+    pub chunks: Vec<usize>,
+    // Number of differing bytes. The program area can not be read, so this
+    // is counted in 8 bytes units there.
+    pub bytes: usize,
+    // Differing bytes. All bytes in differing 8 bytes units are listed for
+    // the program area, and their actual values are unknown.
+    pub differences: Vec<Difference>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Difference {
+    pub address: usize,
+    pub expected: u8,
+    pub actual: Option<u8>,
+}
+
+impl Mismatch {
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    // Writes differing bytes into a CSV file of "address,expected,actual".
+    // The actual value is left empty if unknown.
+    pub fn save(&self, filename: &str) -> Result<(), Error> {
+        let mut text = String::from("address,expected,actual\n");
+        for difference in &self.differences {
+            text.push_str(&format!(
+                "0x{:04x},0x{:02x},{}\n",
+                difference.address,
+                difference.expected,
+                difference
+                    .actual
+                    .map(|actual| format!("0x{:02x}", actual))
+                    .unwrap_or_default()
+            ));
+        }
+        std::fs::write(filename, text)?;
+        Ok(())
+    }
+}
+
+// Receives warnings that do not stop an operation.
+pub type Warning = Box<dyn Fn(&str) + Send>;
+
 pub struct Ch559 {
-    handle: rusb::DeviceHandle<rusb::GlobalContext>,
-    ep_in: u8,
-    ep_out: u8,
-    chip_id: u8,
+    transport: Box<dyn Transport>,
+    chip: &'static Chip,
+    protocol: Protocol,
     version: String,
-    sum: u8,
+    rom_config: Option<u8>,
+    uid: Vec<u8>,
+    key: [u8; 8],
     key_is_reset: bool,
     seed: u64,
+    fill: Fill,
+    fill_over_data: bool,
+    allow_data_overwrite: bool,
+    allow_risky_config: bool,
+    progress: Output,
+    warning: Option<Warning>,
+    ignore: Vec<(usize, usize)>,
+    written: [Vec<u8>; 2],
+    images: [Vec<u8>; 2],
 }
 
 impl Ch559 {
+    #[cfg(any(feature = "rusb", feature = "nusb"))]
     pub fn new() -> Result<Self, Error> {
-        const VID: u16 = 0x4348;
-        const PID: u16 = 0x55e0;
-        if let Some(handle) = rusb::open_device_with_vid_pid(VID, PID) {
-            let mut ch559 = Ch559 {
-                handle,
-                ep_in: 0,
-                ep_out: 0,
-                chip_id: 0,
-                version: String::from("unknown"),
-                sum: 0,
-                key_is_reset: false,
-                seed: 1,
-            };
-            ch559
-                .initialize()
-                .map_err(|e| Error::Initialize(Box::new(e)))?;
-            Ok(ch559)
-        } else {
-            Err(Error::NotFound)
+        Ch559::with_transport(Box::new(UsbTransport::open()?))
+    }
+
+    // Connects to the first bootloader device that `selector` matches.
+    #[cfg(any(feature = "rusb", feature = "nusb"))]
+    pub fn open(selector: &Selector) -> Result<Self, Error> {
+        Ch559::with_transport(Box::new(UsbTransport::open_with(selector)?))
+    }
+
+    // Waits until the bootloader device that `selector` chooses appears, and
+    // connects to it. Waits forever if `timeout` is None.
+    #[cfg(any(feature = "rusb", feature = "nusb"))]
+    pub fn wait(selector: &Selector, timeout: Option<Duration>) -> Result<Self, Error> {
+        let start = Instant::now();
+        loop {
+            if UsbTransport::list(selector)?.len() > selector.index.unwrap_or(0) {
+                return Ch559::open(selector);
+            }
+            if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                return Err(Error::NotFound);
+            }
+            std::thread::sleep(Duration::from_millis(200));
         }
     }
 
+    // Connects to a bootloader over the serial ISP, e.g. "/dev/ttyUSB0".
+    pub fn new_serial(path: &str) -> Result<Self, Error> {
+        Ch559::new_serial_with(path, &[])
+    }
+
+    // Connects over the serial ISP after driving DTR and RTS as `steps`
+    // specify, e.g. to enter the bootloader on boards wired for it.
+    #[cfg(feature = "serial")]
+    pub fn new_serial_with(path: &str, steps: &[LineStep]) -> Result<Self, Error> {
+        Ch559::with_transport(Box::new(SerialTransport::open_with(path, steps)?))
+    }
+
+    #[cfg(not(feature = "serial"))]
+    pub fn new_serial_with(_path: &str, _steps: &[LineStep]) -> Result<Self, Error> {
+        Err(Error::NotSupported("builds without the serial feature"))
+    }
+
+    // Connects to the bootloader device that the WCH vendor driver assigns
+    // `index` to, on Windows.
+    #[cfg(feature = "wch")]
+    pub fn open_wch(index: u32) -> Result<Self, Error> {
+        Ch559::with_transport(Box::new(crate::transport::WchTransport::open(index)?))
+    }
+
+    #[cfg(not(feature = "wch"))]
+    pub fn open_wch(_index: u32) -> Result<Self, Error> {
+        Err(Error::VendorDriver(String::from(
+            "built without the wch feature",
+        )))
+    }
+
+    pub fn with_transport(transport: Box<dyn Transport>) -> Result<Self, Error> {
+        let mut ch559 = Ch559 {
+            transport,
+            chip: &chip::CH559,
+            protocol: Protocol::V2,
+            version: String::from("unknown"),
+            rom_config: None,
+            uid: Vec::new(),
+            key: [0; 8],
+            key_is_reset: false,
+            seed: 1,
+            fill: Fill::Random,
+            fill_over_data: false,
+            allow_data_overwrite: false,
+            allow_risky_config: false,
+            progress: Output::Stdout,
+            warning: None,
+            ignore: Vec::new(),
+            written: [Vec::new(), Vec::new()],
+            images: [Vec::new(), Vec::new()],
+        };
+        ch559
+            .initialize()
+            .map_err(|e| Error::Initialize(Box::new(e)))?;
+        Ok(ch559)
+    }
+
     pub fn set_seed(&mut self, seed: u64) {
         self.seed = seed;
     }
 
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Returns bytes that the last write to the program or data area left in
+    // flash, including fill. Chunks that are not written are left erased.
+    pub fn written(&self, data_region: bool) -> &[u8] {
+        &self.written[data_region as usize]
+    }
+
+    // Returns the image that the last write to the program or data area sent
+    // to the device, without fill. Unspecified bytes are 0xff.
+    pub fn image(&self, data_region: bool) -> &[u8] {
+        &self.images[data_region as usize]
+    }
+
+    // An empty pattern falls back to random values.
+    pub fn set_fill(&mut self, fill: Fill) {
+        self.fill = match fill {
+            Fill::Pattern(pattern) if pattern.is_empty() => Fill::Random,
+            fill => fill,
+        };
+    }
+
+    pub fn fill(&self) -> &Fill {
+        &self.fill
+    }
+
+    // Lets fullfill run over the data area after code that runs over the
+    // program area. The data area is preserved by default.
+    pub fn set_fill_over_data(&mut self, fill_over_data: bool) {
+        self.fill_over_data = fill_over_data;
+    }
+
+    // Allows writing code that runs over the program area into the data
+    // area. Such writes are refused by default as they destroy stored data.
+    pub fn set_allow_data_overwrite(&mut self, allow: bool) {
+        self.allow_data_overwrite = allow;
+    }
+
+    // Allows writing config values that `chip::config_risks` warns about.
+    pub fn set_allow_risky_config(&mut self, allow: bool) {
+        self.allow_risky_config = allow;
+    }
+
+    // Draws progress on `output` instead of stdout.
+    pub fn set_progress(&mut self, output: Output) {
+        self.progress = output;
+    }
+
+    // Reports warnings, e.g. on code that runs over the data area, to
+    // `warning`. Warnings are dropped by default.
+    pub fn set_warning(&mut self, warning: Warning) {
+        self.warning = Some(warning);
+    }
+
+    // Excludes `(address, length)` ranges from compares. The bootloader
+    // verifies 8 bytes at once, so the 8 bytes units that overlap the ranges
+    // are skipped as a whole.
+    pub fn set_ignore(&mut self, ranges: Vec<(usize, usize)>) {
+        self.ignore = ranges;
+    }
+
+    pub fn chip(&self) -> &'static Chip {
+        self.chip
+    }
+
+    pub fn chip_id(&self) -> u8 {
+        self.chip.id
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    // Returns the chip unique ID that the v2 bootloader reports, or an empty
+    // slice on v1.
+    pub fn uid(&self) -> &[u8] {
+        &self.uid
+    }
+
+    // Returns the chip unique ID in hex, e.g. "12345678", in the same form as
+    // fleet files use.
+    pub fn uid_string(&self) -> String {
+        self.uid.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // Returns true if the bootloader is one that this tool is validated
+    // against. v1 bootloaders have no key to derive.
+    pub fn is_tested_version(&self) -> bool {
+        self.protocol == Protocol::V1 || TESTED_VERSIONS.contains(&self.version.as_str())
+    }
+
+    // Returns ROM_CFG[17:8] that the v2 bootloader reports.
+    pub fn rom_config(&self) -> Option<u8> {
+        self.rom_config
+    }
+
     pub fn erase(&mut self) -> Result<(), Error> {
+        if self.protocol == Protocol::V1 {
+            return self.erase_v1();
+        }
+        self.erase_sectors(0, self.chip.erase_size)
+    }
+
+    // Erases `count` sectors of 1KB from sector `start`. The v2 bootloader
+    // only erases from the bottom, so `start` should be 0 on v2.
+    pub fn erase_sectors(&mut self, start: u32, count: u32) -> Result<(), Error> {
+        if start + count > self.chip.erase_size {
+            return Err(Error::SectorRange(self.chip.erase_size));
+        }
+        if self.protocol == Protocol::V1 {
+            return self.erase_sectors_v1(start, count);
+        }
+        if start != 0 {
+            return Err(Error::SectorStart);
+        }
         self.reset_key()?;
-        const ERASE_SIZE: u8 = 60;
-        let request = [0xa4, 0x01, 0x00, ERASE_SIZE];
+        let request = request::erase(self.chip, count);
         let mut response: [u8; 6] = [0; 6];
         self.send_receive(&request, &mut response)?;
         if 0 != response[4] {
@@ -117,8 +473,11 @@ impl Ch559 {
     }
 
     pub fn erase_data(&mut self) -> Result<(), Error> {
+        if self.chip.data_size == 0 || self.protocol == Protocol::V1 {
+            return Err(Error::NotSupported(self.chip.name));
+        }
         self.reset_key()?;
-        let request = [0xa9, 0x00, 0x00, 0x00];
+        let request = request::erase_data(self.chip);
         let mut response: [u8; 6] = [0; 6];
         self.send_receive(&request, &mut response)?;
         if 0 != response[4] {
@@ -127,68 +486,158 @@ impl Ch559 {
         Ok(())
     }
 
-    pub fn read_data(&mut self, filename: &String) -> Result<(), Error> {
-        let mut file = File::create(filename)?;
+    // Erases both the program area and the data area. The data area is
+    // skipped on chips or bootloaders that can not erase it.
+    pub fn erase_all(&mut self) -> Result<(), Error> {
+        let data_size = if self.protocol == Protocol::V1 {
+            0
+        } else {
+            self.chip.data_size
+        };
+        let mut bar = ProgressBar::new(self.chip.code_size + data_size, self.progress.clone());
+        self.erase()?;
+        bar.progress(self.chip.code_size);
+        if data_size != 0 {
+            self.erase_data()?;
+            bar.progress(self.chip.code_size + data_size);
+        }
+        Ok(())
+    }
+
+    pub fn read_data(&mut self, filename: &String, format: Format) -> Result<(), Error> {
+        let data = self.read_data_image()?;
+        data.save(filename, format, self.chip.data_addr)
+    }
+
+    pub fn read_data_image(&mut self) -> Result<Image, Error> {
+        self.read_data_range(0, self.chip.data_size)
+    }
+
+    // Reads `length` bytes from `offset` in the data area. The first byte of
+    // the returned image is at `offset`.
+    pub fn read_data_range(&mut self, offset: usize, length: usize) -> Result<Image, Error> {
+        if self.chip.data_size == 0 || self.protocol == Protocol::V1 {
+            return Err(Error::NotSupported(self.chip.name));
+        }
+        if offset + length > self.chip.data_size {
+            return Err(Error::DataRange);
+        }
         self.reset_key()?;
-        let mut bar = ProgressBar::new(0x400);
-        for offset in (0..0x400).step_by(0x38) {
-            bar.progress(offset);
-            let remaining_size = 0x400 - offset;
-            let size: usize = if remaining_size > 0x38 {
-                0x38
-            } else {
-                remaining_size
-            };
+        let mut data: Vec<u8> = Vec::with_capacity(length);
+        let mut bar = ProgressBar::new(length, self.progress.clone());
+        for done in (0..length).step_by(0x38) {
+            bar.progress(done);
+            let size = (length - done).min(0x38);
             let mut response: Vec<u8> = vec![0; size];
-            self.read_data_in_range(offset as u16, &mut response)?;
-            file.write_all(&response)?;
-            bar.progress(offset + size);
+            self.read_data_in_range((offset + done) as u16, &mut response)?;
+            data.extend_from_slice(&response);
+            bar.progress(done + size);
         }
-        Ok(())
+        Ok(Image::from_binary(data))
     }
 
     pub fn write(
         &mut self,
-        filename: &String,
+        filename: &str,
         write: bool,
         data_region: bool,
         fullfill: bool,
+        format: Option<Format>,
     ) -> Result<(), Error> {
-        let mut file = File::open(filename)?;
-        let metadata = file.metadata()?;
-        if !metadata.is_file() {
-            return Err(Error::InvalidFile);
+        let image = Image::load(filename, format)?;
+        self.write_image(image, write, data_region, fullfill)
+    }
+
+    // Writes `bytes` at `offset` in the data area. Other bytes are read back
+    // so that they are preserved.
+    pub fn write_data_at(&mut self, offset: usize, bytes: &[u8]) -> Result<(), Error> {
+        self.write_data_image_at(offset, &Image::from_binary(bytes.to_vec()))
+    }
+
+    // Writes specified bytes of `patch` at `offset` in the data area, and
+    // preserves the others.
+    pub fn write_data_image_at(&mut self, offset: usize, patch: &Image) -> Result<(), Error> {
+        if offset + patch.len() > self.chip.data_size {
+            return Err(Error::TooLargeDataSize);
         }
-        let file_length = metadata.len() as usize;
-        if data_region {
-            if !fullfill && 0x400 != file_length {
-                return Err(Error::FileSize);
+        let mut image = self.read_data_image()?;
+        for addr in 0..patch.len() {
+            if let Some(byte) = patch.get(addr) {
+                image.set(offset + addr, &[byte]);
             }
-            if file_length > 0x400 {
-                return Err(Error::TooLargeDataSize);
+        }
+        self.update_data_image(image).map(|_| ())
+    }
+
+    // Writes `image` to the data area by programming only the chunks that
+    // differ from the current contents. Bytes that `image` does not specify
+    // are kept. Flash bits can not be set without an erase, so the whole data
+    // area is erased and written instead if needed. Returns the number of
+    // chunks written.
+    pub fn update_data_image(&mut self, image: Image) -> Result<usize, Error> {
+        let (image, length) = prepare_image(self.chip, self.protocol, image, true, Fullfill::None)?;
+        let current = self.read_data_image()?;
+        let target: Vec<u8> = (0..length)
+            .map(|addr| image.get(addr).or(current.get(addr)).unwrap_or(0xff))
+            .collect();
+        let image = Image::from_binary(target.clone());
+        let current = |addr| current.get(addr).unwrap_or(0xff);
+        if (0..length).any(|addr| target[addr] & !current(addr) != 0) {
+            self.erase_data()?;
+            self.write_image(image.clone(), true, true, false)?;
+            self.write_image(image, false, true, false)?;
+            return Ok(length.div_ceil(0x38));
+        }
+        self.reset_key()?;
+        let mut written = 0;
+        let mut bar = ProgressBar::new(length, self.progress.clone());
+        for offset in (0..length).step_by(0x38) {
+            bar.progress(offset);
+            let size = (length - offset).min(0x38);
+            let data = &target[offset..offset + size];
+            if (0..size).any(|i| data[i] != current(offset + i)) {
+                self.write_verify_in_range(offset as u32, data, true, true)?;
+                written += 1;
             }
-        } else {
-            if file_length > 0xf400 {
-                return Err(Error::TooLargeCodeSize);
+            bar.progress(offset + size);
+        }
+        drop(bar);
+        self.read_back(&image)?;
+        self.images[1] = target.clone();
+        self.written[1] = target;
+        Ok(written)
+    }
+
+    pub fn write_image(
+        &mut self,
+        image: Image,
+        write: bool,
+        data_region: bool,
+        fullfill: bool,
+    ) -> Result<(), Error> {
+        let (image, length) = prepare_image(
+            self.chip,
+            self.protocol,
+            image,
+            data_region,
+            self.fill_area(fullfill),
+        )?;
+        if write && !data_region && image.len() > self.chip.code_size {
+            if !self.allow_data_overwrite {
+                return Err(Error::DataOverwrite(self.chip.code_size));
             }
-            if file_length > 0xf000 {
-                println!("code will run over data region as file size is larger than 0xF000");
+            if let Some(warning) = self.warning.as_ref() {
+                warning(&format!(
+                    "code will run over data region as file size is larger than 0x{:X}",
+                    self.chip.code_size
+                ));
             }
         }
         self.reset_key()?;
-        let length = if fullfill {
-            if data_region {
-                0x400
-            } else if file_length > 0xf000 {
-                0xf400
-            } else {
-                0xf000
-            }
-        } else {
-            file_length
-        };
-        let mut bar = ProgressBar::new(length);
+        let base = self.base(data_region);
+        let mut bar = ProgressBar::new(length, self.progress.clone());
         let mut rng = SmallRng::seed_from_u64(self.seed);
+        let mut written = Vec::with_capacity(length);
         for offset in (0..length).step_by(0x38) {
             bar.progress(offset);
             let remaining_size = length - offset;
@@ -197,43 +646,290 @@ impl Ch559 {
             } else {
                 remaining_size
             };
-            let mut data: Vec<u8> = vec![0; size];
-            let read_size = if offset > file_length {
-                0
-            } else if offset + size > file_length {
-                file_length - offset
-            } else {
-                size
+            let Some(data) = chunk(&image, offset, size, fullfill, &self.fill, &mut rng) else {
+                written.resize(offset + size, 0xff);
+                bar.progress(offset + size);
+                continue;
             };
-            if 0 != read_size {
-                let size = file.read(&mut data)?;
-                if read_size != size {
-                    return Err(Error::Eof);
+            written.extend_from_slice(&data);
+            // Programming 0xff leaves flash bits as they are, so such chunks
+            // are skipped on writes. Compares still check them.
+            if write && data.iter().all(|byte| *byte == 0xff) {
+                bar.progress(offset + size);
+                continue;
+            }
+            if !write && self.is_ignored(base + offset, size) {
+                let units = self.differing_units(offset, &data, data_region)?;
+                if units.iter().any(|addr| !self.is_ignored(base + addr, 8)) {
+                    return Err(Error::Verify);
                 }
+            } else {
+                self.write_verify_in_range(offset as u32, &data, write, data_region)?;
             }
-            if read_size != size {
-                for i in read_size..size {
-                    data[i] = rng.gen::<u8>();
+            bar.progress(offset + size);
+        }
+        // The bootloader may report success even if the data flash is not
+        // programmed, so data writes are checked by reading them back.
+        if write && data_region {
+            drop(bar);
+            self.read_back(&image)?;
+        }
+        if write {
+            self.written[data_region as usize] = written;
+            self.images[data_region as usize] = (0..image.len())
+                .map(|addr| image.get(addr).unwrap_or(0xff))
+                .collect();
+        }
+        Ok(())
+    }
+
+    // Checks that the program or data area is erased. The first non-blank
+    // address is reported in 8 bytes granularity for the program area as
+    // it can not be read but only verified.
+    pub fn blank_check(&mut self, data_region: bool) -> Result<(), Error> {
+        if data_region {
+            let data = self.read_data_image()?;
+            return match (0..data.len()).find(|addr| data.get(*addr) != Some(0xff)) {
+                Some(addr) => Err(Error::NotBlank(self.chip.data_addr + addr)),
+                None => Ok(()),
+            };
+        }
+        self.reset_key()?;
+        let length = self.chip.code_size;
+        let mut bar = ProgressBar::new(length, self.progress.clone());
+        for offset in (0..length).step_by(0x38) {
+            bar.progress(offset);
+            let size = (length - offset).min(0x38);
+            match self.write_verify_in_range(offset as u32, &vec![0xff; size], false, false) {
+                Ok(()) => {}
+                Err(Error::Verify) => {
+                    drop(bar);
+                    let units = self.differing_units(offset, &vec![0xff; size], false)?;
+                    let addr = units.first().copied().unwrap_or(offset);
+                    return Err(Error::NotBlank(self.chip.code_base + addr));
                 }
+                Err(error) => return Err(error),
             }
-            self.write_verify_in_range(offset as u16, &data, write, data_region)?;
             bar.progress(offset + size);
         }
         Ok(())
     }
 
+    pub fn verify(
+        &mut self,
+        filename: &str,
+        data_region: bool,
+        fullfill: bool,
+        format: Option<Format>,
+    ) -> Result<Mismatch, Error> {
+        let image = Image::load(filename, format)?;
+        self.verify_image(image, data_region, fullfill)
+    }
+
+    // Compares the whole `image` with the flash, and reports all differing
+    // chunks rather than failing at the first one.
+    pub fn verify_image(
+        &mut self,
+        image: Image,
+        data_region: bool,
+        fullfill: bool,
+    ) -> Result<Mismatch, Error> {
+        let (image, length) = prepare_image(
+            self.chip,
+            self.protocol,
+            image,
+            data_region,
+            self.fill_area(fullfill),
+        )?;
+        // The data area can be read to count differing bytes exactly.
+        let data = if data_region {
+            Some(self.read_data_image()?)
+        } else {
+            None
+        };
+        self.reset_key()?;
+        let base = self.base(data_region);
+        let mut mismatch = Mismatch::default();
+        let mut bar = ProgressBar::new(length, self.progress.clone());
+        let mut rng = SmallRng::seed_from_u64(self.seed);
+        for offset in (0..length).step_by(0x38) {
+            bar.progress(offset);
+            let size = (length - offset).min(0x38);
+            let Some(expected) = chunk(&image, offset, size, fullfill, &self.fill, &mut rng) else {
+                bar.progress(offset + size);
+                continue;
+            };
+            let differences: Vec<Difference> = match data.as_ref() {
+                Some(data) => (0..size)
+                    .filter(|i| data.get(offset + i) != Some(expected[*i]))
+                    .filter(|i| !self.is_ignored(base + offset + i, 1))
+                    .map(|i| Difference {
+                        address: base + offset + i,
+                        expected: expected[i],
+                        actual: data.get(offset + i),
+                    })
+                    .collect(),
+                None => match self.write_verify_in_range(offset as u32, &expected, false, false) {
+                    Ok(()) => Vec::new(),
+                    Err(Error::Verify) => self
+                        .differing_units(offset, &expected, false)?
+                        .into_iter()
+                        .filter(|addr| !self.is_ignored(base + addr, 8))
+                        .flat_map(|addr| addr..(addr + 8).min(offset + size))
+                        .map(|addr| Difference {
+                            address: base + addr,
+                            expected: expected[addr - offset],
+                            actual: None,
+                        })
+                        .collect(),
+                    Err(error) => return Err(error),
+                },
+            };
+            if !differences.is_empty() {
+                mismatch.chunks.push(base + offset);
+                mismatch.bytes += differences.len();
+                mismatch.differences.extend(differences);
+            }
+            bar.progress(offset + size);
+        }
+        Ok(mismatch)
+    }
+
+    // Returns whether bytes that `image` specifies match the flash. Other
+    // bytes are not compared, e.g. ones filled with random values before.
+    // The program area can be verified only in 8 bytes units, and a unit that
+    // is partially specified matches only if the rest is 0xff.
+    pub fn compare_specified(
+        &mut self,
+        mut image: Image,
+        data_region: bool,
+    ) -> Result<bool, Error> {
+        let base = self.base(data_region);
+        if image.start().is_some_and(|start| start >= base) {
+            image.rebase(base);
+        }
+        if data_region {
+            let data = self.read_data_image()?;
+            return Ok((0..image.len()).all(|addr| {
+                image.get(addr).is_none()
+                    || image.get(addr) == data.get(addr)
+                    || self.is_ignored(base + addr, 1)
+            }));
+        }
+        match self.write_image(image, false, false, false) {
+            Ok(()) => Ok(true),
+            Err(Error::Verify) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    // Narrows down a differing chunk at `offset` to 8 bytes units, and
+    // returns the offsets of the units that differ.
+    fn differing_units(
+        &mut self,
+        offset: usize,
+        data: &[u8],
+        data_region: bool,
+    ) -> Result<Vec<usize>, Error> {
+        let mut units = Vec::new();
+        for start in (0..data.len()).step_by(8) {
+            let end = (start + 8).min(data.len());
+            let addr = offset + start;
+            let unit = &data[start..end];
+            match self.write_verify_in_range(addr as u32, unit, false, data_region) {
+                Ok(()) => {}
+                Err(Error::Verify) => units.push(addr),
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(units)
+    }
+
+    fn fill_area(&self, fullfill: bool) -> Fullfill {
+        if !fullfill {
+            Fullfill::None
+        } else if self.fill_over_data {
+            Fullfill::OverData
+        } else {
+            Fullfill::Region
+        }
+    }
+
+    // Returns the address that offsets in the region are relative to.
+    fn base(&self, data_region: bool) -> usize {
+        if data_region {
+            self.chip.data_addr
+        } else {
+            self.chip.code_base
+        }
+    }
+
+    // Returns true if any of `size` bytes from `addr` is excluded from
+    // compares.
+    fn is_ignored(&self, addr: usize, size: usize) -> bool {
+        self.ignore
+            .iter()
+            .any(|(start, length)| addr < start + length && *start < addr + size)
+    }
+
+    // Compares specified bytes of `image` with the data area.
+    fn read_back(&mut self, image: &Image) -> Result<(), Error> {
+        let data = self.read_data_image()?;
+        for offset in 0..image.len() {
+            if image
+                .get(offset)
+                .is_some_and(|byte| data.get(offset) != Some(byte))
+            {
+                return Err(Error::ReadBack(self.chip.data_addr + offset));
+            }
+        }
+        Ok(())
+    }
+
     pub fn boot(&mut self) -> Result<(), Error> {
-        let request = [0xa2, 0x01, 0x00, 0x01];
-        self.send(&request)?;
+        if self.protocol == Protocol::V1 {
+            return self.boot_v1();
+        }
+        self.send(&request::BOOT)?;
         Ok(())
     }
 
+    // Writes BOOT_CFG[15:8]. BOOT_CFG[7:0] is written as ff as the vendor tool
+    // does.
     pub fn write_config(&mut self, config: u8) -> Result<(), Error> {
+        self.send_config(0xff, config)?;
+        // The bootloader may ignore the write, so it is checked by reading the
+        // config back.
+        let actual = self.read_config()?;
+        if actual != config {
+            return Err(Error::ConfigReadBack(config.into(), actual.into()));
+        }
+        Ok(())
+    }
+
+    // Writes the whole BOOT_CFG[15:0].
+    pub fn write_config_word(&mut self, config: u16) -> Result<(), Error> {
+        let [high, low] = config.to_be_bytes();
+        self.send_config(low, high)?;
+        let actual = self.read_config_word()?;
+        if actual != config {
+            return Err(Error::ConfigReadBack(config, actual));
+        }
+        Ok(())
+    }
+
+    fn send_config(&mut self, low: u8, high: u8) -> Result<(), Error> {
+        if self.chip.family != Family::Ch55x || self.protocol == Protocol::V1 {
+            return Err(Error::NotSupported(self.chip.name));
+        }
+        if !self.allow_risky_config && !chip::config_risks(high).is_empty() {
+            return Err(Error::RiskyConfig(high));
+        }
         let mut response: [u8; 6] = [0; 6];
-        if self.version.eq("2.31") || self.version.eq("2.40") {
+        if TESTED_VERSIONS.contains(&self.version.as_str()) {
             let request = [
-                0xa8, 0x0e, 0x00, 0x07, 0x00, 0xff, 0xff, 0xff, 0xff, 0x03, 0x00, 0x00, 0x00, 0xff,
-                config, 0xff, 0x97,
+                0xa8, 0x0e, 0x00, 0x07, 0x00, 0xff, 0xff, 0xff, 0xff, 0x03, 0x00, 0x00, 0x00, low,
+                high, 0xff, 0x97,
             ];
             self.send_receive(&request, &mut response)?;
         } else {
@@ -245,146 +941,88 @@ impl Ch559 {
         Ok(())
     }
 
-    fn initialize(&mut self) -> Result<(), Error> {
-        let device = self.handle.device();
-        let config = device.config_descriptor(0);
-        let config_number;
-        let interface_number;
-        if let Ok(config) = config {
-            config_number = config.number();
-            if let Some(interface) = config.interfaces().next() {
-                interface_number = interface.number();
-                if let Some(desc) = interface.descriptors().next() {
-                    let mut ep_in_found = false;
-                    let mut ep_in_type = rusb::TransferType::Bulk;
-                    let mut ep_out_found = false;
-                    let mut ep_out_type = rusb::TransferType::Bulk;
-                    for ep in desc.endpoint_descriptors() {
-                        match ep.direction() {
-                            rusb::Direction::In => {
-                                self.ep_in = ep.address();
-                                ep_in_type = ep.transfer_type();
-                                ep_in_found = true;
-                            }
-                            rusb::Direction::Out => {
-                                self.ep_out = ep.address();
-                                ep_out_type = ep.transfer_type();
-                                ep_out_found = true;
-                            }
-                        }
-                    }
-                    if !ep_in_found
-                        || !ep_out_found
-                        || ep_in_type != rusb::TransferType::Bulk
-                        || ep_out_type != rusb::TransferType::Bulk
-                    {
-                        return Err(Error::DetectEp);
-                    }
-                }
-            } else {
-                return Err(Error::CheckInterface);
-            }
-        } else {
-            return Err(Error::CheckConfiguration);
-        }
-        if self.handle.set_active_configuration(config_number).is_err() {
-            return Err(Error::ActivateConfiguration);
+    // Protects the flash from being read by programmers.
+    pub fn protect(&mut self) -> Result<(), Error> {
+        let config = self.read_config()?;
+        self.write_config(config & !chip::CODE_PROTECT)
+    }
+
+    // Removes the code protection. Both the program area and the data area are
+    // erased first so that protected contents never become readable.
+    pub fn unprotect(&mut self) -> Result<(), Error> {
+        let config = self.read_config()?;
+        self.erase_all()?;
+        self.write_config(config | chip::CODE_PROTECT)
+    }
+
+    // Reads BOOT_CFG[15:8] from the bootloader. The value reported on detect is
+    // updated as well.
+    pub fn read_config(&mut self) -> Result<u8, Error> {
+        Ok(self.read_config_word()?.to_be_bytes()[0])
+    }
+
+    // Reads the whole BOOT_CFG[15:0].
+    pub fn read_config_word(&mut self) -> Result<u16, Error> {
+        if self.chip.family != Family::Ch55x || self.protocol == Protocol::V1 {
+            return Err(Error::NotSupported(self.chip.name));
         }
-        if self.handle.claim_interface(interface_number).is_err() {
-            return Err(Error::ClaimInterface);
+        let mut response: [u8; 30] = [0; 30];
+        self.send_receive(&request::IDENTIFY, &mut response)?;
+        if response[0] != request::IDENTIFY[0] {
+            return Err(Error::InvalidResponse);
         }
-        let detect_request = [
-            0xa1, 0x12, 0x00, 0x59, 0x11, 0x4d, 0x43, 0x55, 0x20, 0x49, 0x53, 0x50, 0x20, 0x26,
-            0x20, 0x57, 0x43, 0x48, 0x2e, 0x43, 0x4e,
-        ];
+        self.rom_config = Some(response[15]);
+        Ok(u16::from_be_bytes([response[15], response[14]]))
+    }
+
+    fn initialize(&mut self) -> Result<(), Error> {
+        // Falls back to the v1 protocol for legacy bootloaders.
+        self.detect().or_else(|e| self.detect_v1().map_err(|_| e))
+    }
+
+    fn detect(&mut self) -> Result<(), Error> {
         let mut detect_response: [u8; 6] = [0; 6];
-        self.send_receive(&detect_request, &mut detect_response)
+        self.send_receive(&request::DETECT, &mut detect_response)
             .map_err(|e| Error::OnDetect(Box::new(e)))?;
-        if detect_response[4] != 0x59 {
-            return Err(Error::InvalidResponse);
-        }
-        self.chip_id = detect_response[4];
-        let identify_request = [0xa7, 0x02, 0x00, 0x1f, 0x00];
+        dump_response("detect", &detect_response);
+        self.chip = request::detected_chip(&detect_response)?;
         let mut identify_response: [u8; 30] = [0; 30];
-        self.send_receive(&identify_request, &mut identify_response)
+        self.send_receive(&request::IDENTIFY, &mut identify_response)
             .map_err(|e| Error::OnDetect(Box::new(e)))?;
-        self.version = format!(
-            "{}.{}{}",
-            identify_response[19], identify_response[20], identify_response[21],
-        );
-
-        println!(
-            "CH559 Found (BootLoader: v{}, ROM_CFG[17:8]: {:02x})",
-            self.version, identify_response[15]
-        );
-        self.sum = identify_response[22]
-            .wrapping_add(identify_response[23])
-            .wrapping_add(identify_response[24])
-            .wrapping_add(identify_response[25]);
+        dump_response("identify", &identify_response);
+        (self.version, self.uid) = request::identified(self.chip, &identify_response);
+        self.rom_config = Some(identify_response[15]);
         Ok(())
     }
 
-    fn reset_key(&mut self) -> Result<(), Error> {
-        if self.key_is_reset {
+    pub fn reset_key(&mut self) -> Result<(), Error> {
+        if self.key_is_reset || self.protocol == Protocol::V1 {
             return Ok(());
         }
-        let mut request = [0; 0x33];
-        request[0] = 0xa3;
-        request[1] = 0x30;
-        request[2] = 0x00;
-        for i in 3..0x33 {
-            request[i] = self.sum;
-        }
+        let (request, key) = request::reset_key(self.chip, &self.version, &self.uid);
         let mut response = [0; 6];
         self.send_receive(&request, &mut response)?;
-        if response[4] != self.chip_id {
+        if response[4] != request::key_checksum(&key) {
             return Err(Error::ResetKey);
         }
+        self.key = key;
         self.key_is_reset = true;
         Ok(())
     }
 
-    fn send(&mut self, request: &[u8]) -> Result<(), Error> {
-        let size = self
-            .handle
-            .write_bulk(self.ep_out, request, core::time::Duration::new(1, 0))
-            .map_err(|_| Error::BulkWrite)?;
-        if size != request.len() {
-            return Err(Error::BulkWriteAll);
-        }
-        Ok(())
+    pub fn send(&mut self, request: &[u8]) -> Result<(), Error> {
+        self.transport.send(request)
     }
 
-    fn send_receive(&mut self, request: &[u8], response: &mut [u8]) -> Result<(), Error> {
-        let size = self
-            .handle
-            .write_bulk(self.ep_out, request, core::time::Duration::new(1, 0))
-            .map_err(|_| Error::BulkWrite)?;
-        if size != request.len() {
-            return Err(Error::BulkWriteAll);
-        }
-        self.handle
-            .read_bulk(self.ep_in, response, core::time::Duration::new(1, 0))
-            .map_err(Error::BulkRead)?;
-        Ok(())
+    pub fn send_receive(&mut self, request: &[u8], response: &mut [u8]) -> Result<(), Error> {
+        self.transport.send(request)?;
+        self.transport.receive(response)
     }
 
-    // `addr` is an offset from 0xF000 (DATA_FLASH_ADDR)
+    // `addr` is an offset from DATA_FLASH_ADDR.
     // reset_key() should be called beforehand.
-    fn read_data_in_range(&mut self, addr: u16, buffer: &mut [u8]) -> Result<(), Error> {
-        if buffer.len() > 0x38 {
-            return Err(Error::TooLargeReadSize);
-        }
-        let request = [
-            0xab,
-            0x00,
-            0x00,
-            addr as u8,
-            (addr >> 8) as u8,
-            0x00,
-            0x00,
-            buffer.len() as u8,
-        ];
+    pub fn read_data_in_range(&mut self, addr: u16, buffer: &mut [u8]) -> Result<(), Error> {
+        let request = request::read_data(addr, buffer.len())?;
         let mut response: Vec<u8> = vec![0; buffer.len() + 6];
         self.send_receive(&request, &mut response)?;
         if 0 != response[4] {
@@ -394,44 +1032,19 @@ impl Ch559 {
         Ok(())
     }
 
-    // `addr` is an offset from 0xF000 (DATA_FLASH_ADDR) if `data_region` is true.
+    // `addr` is an offset from DATA_FLASH_ADDR if `data_region` is true.
     // reset_key() should be called beforehand.
-    fn write_verify_in_range(
+    pub fn write_verify_in_range(
         &mut self,
-        addr: u16,
+        addr: u32,
         data: &[u8],
         write: bool,
         data_region: bool,
     ) -> Result<(), Error> {
-        if data.len() > 0x38 {
-            return Err(Error::TooLargeReadSize);
-        }
-        let write_command = if data_region { 0xaa } else { 0xa5 };
-        let length = (data.len() + 7) & !7;
-        let mut request: Vec<u8> = Vec::with_capacity(8 + length);
-        let address = if data_region && !write {
-            addr + 0xF000
-        } else {
-            addr
-        };
-        request.push(if write { write_command } else { 0xa6 });
-        request.push((length + 5) as u8);
-        request.push(0);
-        request.push(address as u8);
-        request.push((address >> 8) as u8);
-        request.push(0);
-        request.push(0);
-        request.push(length as u8);
-        for i in 0..length {
-            if i < data.len() {
-                request.push(data[i]);
-            } else {
-                request.push(0xff);
-            }
-            if 7 == (i & 7) {
-                request[8 + i] ^= self.chip_id;
-            }
+        if self.protocol == Protocol::V1 {
+            return self.write_verify_in_range_v1(addr, data, write);
         }
+        let request = request::write_verify(self.chip, &self.key, addr, data, write, data_region)?;
         let mut response: [u8; 6] = [0; 6];
         self.send_receive(&request, &mut response)?;
         if 0 != response[4] {
@@ -441,3 +1054,110 @@ impl Ch559 {
         Ok(())
     }
 }
+
+// v2 bootloader versions that this tool is validated against.
+pub static TESTED_VERSIONS: [&str; 2] = ["2.31", "2.40"];
+
+static DUMP_RESPONSES: AtomicBool = AtomicBool::new(false);
+
+// Prints raw responses of detect requests to stderr while connecting, i.e. data
+// that is needed to support a new bootloader revision.
+pub fn set_dump_responses(dump: bool) {
+    DUMP_RESPONSES.store(dump, Ordering::Relaxed);
+}
+
+pub(crate) fn dump_response(name: &str, response: &[u8]) {
+    if DUMP_RESPONSES.load(Ordering::Relaxed) {
+        let bytes: Vec<String> = response.iter().map(|b| format!("{:02x}", b)).collect();
+        eprintln!("{}: {}", name, bytes.join(" "));
+    }
+}
+
+// Area that fullfill covers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Fullfill {
+    None,
+    // Up to the end of the target region. Code that runs over the program
+    // area is filled only up to its end so that the data area is preserved.
+    Region,
+    // Also fills the data area after code that runs over the program area.
+    OverData,
+}
+
+// Places an image for the target region and checks its size. Returns the
+// image and the length to write.
+pub(crate) fn prepare_image(
+    chip: &'static Chip,
+    protocol: Protocol,
+    mut image: Image,
+    data_region: bool,
+    fullfill: Fullfill,
+) -> Result<(Image, usize), Error> {
+    if data_region && (chip.data_size == 0 || protocol == Protocol::V1) {
+        return Err(Error::NotSupported(chip.name));
+    }
+    // Images that are linked at DATA_FLASH_ADDR are placed from the top of
+    // the data region.
+    let base = if data_region {
+        chip.data_addr
+    } else {
+        chip.code_base
+    };
+    if image.start().is_some_and(|start| start >= base) {
+        image.rebase(base);
+    }
+    // An empty image has nothing to write unless fullfill fills the region.
+    if fullfill == Fullfill::None && image.start().is_none() {
+        return Err(Error::EmptyImage);
+    }
+    let file_length = image.len();
+    if data_region {
+        if fullfill == Fullfill::None && chip.data_size != file_length {
+            return Err(Error::FileSize(chip.data_size));
+        }
+        if file_length > chip.data_size {
+            return Err(Error::TooLargeDataSize);
+        }
+    } else {
+        if file_length > chip.code_limit() {
+            return Err(Error::TooLargeCodeSize);
+        }
+    }
+    let length = match fullfill {
+        Fullfill::None => file_length,
+        _ if data_region => chip.data_size,
+        Fullfill::Region => file_length.max(chip.code_size),
+        Fullfill::OverData if file_length > chip.code_size => chip.code_limit(),
+        Fullfill::OverData => chip.code_size,
+    };
+    Ok((image, length))
+}
+
+// Returns data to write at `offset`, or None if the range can be skipped.
+// Unspecified bytes are filled as `fill` specifies on `fullfill`.
+pub(crate) fn chunk(
+    image: &Image,
+    offset: usize,
+    size: usize,
+    fullfill: bool,
+    fill: &Fill,
+    rng: &mut SmallRng,
+) -> Option<Vec<u8>> {
+    let specified = (offset..offset + size).any(|addr| image.get(addr).is_some());
+    if !specified && !fullfill {
+        return None;
+    }
+    Some(
+        (offset..offset + size)
+            .map(|addr| match image.get(addr) {
+                Some(byte) => byte,
+                None if fullfill => match fill {
+                    Fill::Random => rng.gen::<u8>(),
+                    Fill::Byte(byte) => *byte,
+                    Fill::Pattern(pattern) => pattern[addr % pattern.len()],
+                },
+                None => 0xff,
+            })
+            .collect(),
+    )
+}
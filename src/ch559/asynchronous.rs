@@ -0,0 +1,304 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use async_channel::{Receiver, Sender};
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+
+use crate::ch559::chip::{self, Chip};
+use crate::ch559::{chunk, dump_response, prepare_image, request, Error, Fill, Fullfill, Protocol};
+use crate::image::Image;
+use crate::transport::AsyncTransport;
+
+// Reports how many bytes of the running operation are processed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+}
+
+// Non-blocking counterpart of `Ch559` for GUI and server integrations. Only
+// the v2 protocol is supported.
+pub struct AsyncCh559<T: AsyncTransport> {
+    transport: T,
+    chip: &'static Chip,
+    version: String,
+    uid: Vec<u8>,
+    key: [u8; 8],
+    key_is_reset: bool,
+    seed: u64,
+    fill: Fill,
+    allow_data_overwrite: bool,
+    progress: Option<Sender<Progress>>,
+}
+
+impl<T: AsyncTransport> AsyncCh559<T> {
+    pub async fn with_transport(transport: T) -> Result<Self, Error> {
+        let mut ch559 = AsyncCh559 {
+            transport,
+            chip: &chip::CH559,
+            version: String::from("unknown"),
+            uid: Vec::new(),
+            key: [0; 8],
+            key_is_reset: false,
+            seed: 1,
+            fill: Fill::Random,
+            allow_data_overwrite: false,
+            progress: None,
+        };
+        ch559
+            .detect()
+            .await
+            .map_err(|e| Error::Initialize(Box::new(e)))?;
+        Ok(ch559)
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    // An empty pattern falls back to random values.
+    pub fn set_fill(&mut self, fill: Fill) {
+        self.fill = match fill {
+            Fill::Pattern(pattern) if pattern.is_empty() => Fill::Random,
+            fill => fill,
+        };
+    }
+
+    // Allows writing code that runs over the program area into the data
+    // area.
+    pub fn set_allow_data_overwrite(&mut self, allow: bool) {
+        self.allow_data_overwrite = allow;
+    }
+
+    pub fn chip(&self) -> &'static Chip {
+        self.chip
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    // Returns the chip unique ID that the bootloader reports.
+    pub fn uid(&self) -> &[u8] {
+        &self.uid
+    }
+
+    // Returns a receiver that is notified of the progress of following
+    // operations. A previously returned receiver is closed.
+    pub fn progress(&mut self) -> Receiver<Progress> {
+        let (sender, receiver) = async_channel::unbounded();
+        self.progress = Some(sender);
+        receiver
+    }
+
+    pub async fn erase(&mut self) -> Result<(), Error> {
+        self.reset_key().await?;
+        let response = self
+            .send_receive(request::erase(self.chip, self.chip.erase_size), 6)
+            .await?;
+        if 0 != response[4] {
+            return Err(Error::Erase);
+        }
+        Ok(())
+    }
+
+    pub async fn erase_data(&mut self) -> Result<(), Error> {
+        if self.chip.data_size == 0 {
+            return Err(Error::NotSupported(self.chip.name));
+        }
+        self.reset_key().await?;
+        let request = request::erase_data(self.chip).to_vec();
+        let response = self.send_receive(request, 6).await?;
+        if 0 != response[4] {
+            return Err(Error::Erase);
+        }
+        Ok(())
+    }
+
+    pub async fn read_data_image(&mut self) -> Result<Image, Error> {
+        if self.chip.data_size == 0 {
+            return Err(Error::NotSupported(self.chip.name));
+        }
+        self.reset_key().await?;
+        let data_size = self.chip.data_size;
+        let mut data: Vec<u8> = Vec::with_capacity(data_size);
+        for offset in (0..data_size).step_by(0x38) {
+            self.report(offset, data_size);
+            let size = (data_size - offset).min(0x38);
+            let request = request::read_data(offset as u16, size)?.to_vec();
+            let response = self.send_receive(request, size + 6).await?;
+            if 0 != response[4] {
+                return Err(Error::Read);
+            }
+            data.extend_from_slice(&response[6..size + 6]);
+        }
+        self.report(data_size, data_size);
+        Ok(Image::from_binary(data))
+    }
+
+    pub async fn write_image(
+        &mut self,
+        image: Image,
+        write: bool,
+        data_region: bool,
+        fullfill: bool,
+    ) -> Result<(), Error> {
+        let area = if fullfill {
+            Fullfill::Region
+        } else {
+            Fullfill::None
+        };
+        let (image, length) = prepare_image(self.chip, Protocol::V2, image, data_region, area)?;
+        if write && !data_region && image.len() > self.chip.code_size && !self.allow_data_overwrite
+        {
+            return Err(Error::DataOverwrite(self.chip.code_size));
+        }
+        self.reset_key().await?;
+        let mut rng = SmallRng::seed_from_u64(self.seed);
+        for offset in (0..length).step_by(0x38) {
+            self.report(offset, length);
+            let size = (length - offset).min(0x38);
+            let Some(data) = chunk(&image, offset, size, fullfill, &self.fill, &mut rng) else {
+                continue;
+            };
+            // Programming 0xff leaves flash bits as they are.
+            if write && data.iter().all(|byte| *byte == 0xff) {
+                continue;
+            }
+            let request = request::write_verify(
+                self.chip,
+                &self.key,
+                offset as u32,
+                &data,
+                write,
+                data_region,
+            )?;
+            let response = self.send_receive(request, 6).await?;
+            if 0 != response[4] {
+                let err = if write { Error::Flash } else { Error::Verify };
+                return Err(err);
+            }
+        }
+        self.report(length, length);
+        Ok(())
+    }
+
+    pub async fn boot(&mut self) -> Result<(), Error> {
+        self.transport.send(request::BOOT.to_vec()).await
+    }
+
+    async fn detect(&mut self) -> Result<(), Error> {
+        let response = self.send_receive(request::DETECT.to_vec(), 6).await?;
+        dump_response("detect", &response);
+        let mut detect_response: [u8; 6] = [0; 6];
+        detect_response.copy_from_slice(&response[..6]);
+        self.chip = request::detected_chip(&detect_response)?;
+        let response = self.send_receive(request::IDENTIFY.to_vec(), 30).await?;
+        dump_response("identify", &response);
+        let mut identify_response: [u8; 30] = [0; 30];
+        identify_response.copy_from_slice(&response[..30]);
+        (self.version, self.uid) = request::identified(self.chip, &identify_response);
+        Ok(())
+    }
+
+    async fn reset_key(&mut self) -> Result<(), Error> {
+        if self.key_is_reset {
+            return Ok(());
+        }
+        let (request, key) = request::reset_key(self.chip, &self.version, &self.uid);
+        let response = self.send_receive(request, 6).await?;
+        if response[4] != request::key_checksum(&key) {
+            return Err(Error::ResetKey);
+        }
+        self.key = key;
+        self.key_is_reset = true;
+        Ok(())
+    }
+
+    // Transports may return fewer bytes than `size`, e.g. nusb returns bytes
+    // actually received, and such short responses are refused so that callers
+    // can index the response safely.
+    async fn send_receive(&mut self, request: Vec<u8>, size: usize) -> Result<Vec<u8>, Error> {
+        self.transport.send(request).await?;
+        let response = self.transport.receive(size).await?;
+        if response.len() < size {
+            return Err(Error::InvalidResponse);
+        }
+        Ok(response)
+    }
+
+    fn report(&self, done: usize, total: usize) {
+        if let Some(sender) = &self.progress {
+            // Nobody may be listening, and that is fine.
+            let _ = sender.try_send(Progress { done, total });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::task::{Context, Poll, Waker};
+
+    // Returns queued responses as they are, however short they are.
+    struct ShortTransport {
+        responses: Vec<Vec<u8>>,
+    }
+
+    impl AsyncTransport for ShortTransport {
+        async fn send(&mut self, _request: Vec<u8>) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn receive(&mut self, _size: usize) -> Result<Vec<u8>, Error> {
+            Ok(self.responses.remove(0))
+        }
+    }
+
+    // Runs a future that never waits, as the transport above does not.
+    fn run<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let mut context = Context::from_waker(Waker::noop());
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("pending"),
+        }
+    }
+
+    fn connect(responses: Vec<Vec<u8>>) -> Result<AsyncCh559<ShortTransport>, Error> {
+        run(AsyncCh559::with_transport(ShortTransport { responses }))
+    }
+
+    #[test]
+    fn short_detect_response() {
+        let error = connect(vec![vec![0xa1, 0x00]]).err().unwrap();
+        let Error::Initialize(error) = error else {
+            panic!("{}", error);
+        };
+        assert!(matches!(*error, Error::InvalidResponse));
+    }
+
+    #[test]
+    fn short_identify_response() {
+        let detect = vec![0xa1, 0x00, 0x02, 0x00, 0x59, 0x11];
+        let error = connect(vec![detect, vec![0xa7; 10]]).err().unwrap();
+        let Error::Initialize(error) = error else {
+            panic!("{}", error);
+        };
+        assert!(matches!(*error, Error::InvalidResponse));
+    }
+
+    #[test]
+    fn short_read_response() {
+        let detect = vec![0xa1, 0x00, 0x02, 0x00, 0x59, 0x11];
+        let mut identify = vec![0; 30];
+        identify[19..22].copy_from_slice(&[2, 3, 1]);
+        let reset_key = vec![0xa3, 0x00, 0x02, 0x00, 0x59, 0x00];
+        let read = vec![0xab, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00];
+        let mut ch559 = connect(vec![detect, identify, reset_key, read]).unwrap();
+        let result = run(ch559.read_data_image());
+        assert!(matches!(result, Err(Error::InvalidResponse)));
+    }
+}
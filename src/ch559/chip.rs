@@ -0,0 +1,295 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Family {
+    // 8051 based CH54x/CH55x parts.
+    Ch55x,
+    // ARM and RISC-V based CH32F/CH32V parts.
+    Ch32,
+}
+
+pub struct Chip {
+    pub name: &'static str,
+    pub family: Family,
+    // Device type and chip ID reported on detect.
+    pub device_type: u8,
+    pub id: u8,
+    // Address that firmware for the program area is linked at.
+    pub code_base: usize,
+    // Size of the program area.
+    pub code_size: usize,
+    // Number of 1KB blocks to erase for the program area.
+    pub erase_size: u32,
+    // DATA_FLASH_ADDR and its size. `data_size` is 0 if the chip has no data
+    // flash.
+    pub data_addr: usize,
+    pub data_size: usize,
+    // Number of 1KB blocks to erase for the data area, or 0 to let the
+    // bootloader erase its default size.
+    pub data_erase_size: u8,
+}
+
+impl Chip {
+    // Returns the largest program that can be written. Code may run over the
+    // data region if it is placed right after the program area.
+    pub fn code_limit(&self) -> usize {
+        if self.data_size != 0 && self.data_addr == self.code_size {
+            self.code_size + self.data_size
+        } else {
+            self.code_size
+        }
+    }
+
+    // Returns true if `expected` is the name of the chip ignoring case, e.g.
+    // "ch559", or its chip ID in hex, e.g. "59".
+    pub fn is(&self, expected: &str) -> bool {
+        self.name.eq_ignore_ascii_case(expected)
+            || u8::from_str_radix(expected.trim_start_matches("0x"), 16) == Ok(self.id)
+    }
+}
+
+pub static CH549: Chip = Chip {
+    name: "CH549",
+    family: Family::Ch55x,
+    device_type: 0x12,
+    id: 0x49,
+    code_base: 0,
+    code_size: 0xe800,
+    erase_size: 58,
+    data_addr: 0xe800,
+    data_size: 0x800,
+    data_erase_size: 2,
+};
+
+pub static CH551: Chip = Chip {
+    name: "CH551",
+    family: Family::Ch55x,
+    device_type: 0x11,
+    id: 0x51,
+    code_base: 0,
+    code_size: 0x2800,
+    erase_size: 10,
+    data_addr: 0xc000,
+    data_size: 0x80,
+    data_erase_size: 0,
+};
+
+pub static CH552: Chip = Chip {
+    name: "CH552",
+    family: Family::Ch55x,
+    device_type: 0x11,
+    id: 0x52,
+    code_base: 0,
+    code_size: 0x3800,
+    erase_size: 14,
+    data_addr: 0xc000,
+    data_size: 0x80,
+    data_erase_size: 0,
+};
+
+pub static CH554: Chip = Chip {
+    name: "CH554",
+    family: Family::Ch55x,
+    device_type: 0x11,
+    id: 0x54,
+    code_base: 0,
+    code_size: 0x3800,
+    erase_size: 14,
+    data_addr: 0xc000,
+    data_size: 0x80,
+    data_erase_size: 0,
+};
+
+pub static CH558: Chip = Chip {
+    name: "CH558",
+    family: Family::Ch55x,
+    device_type: 0x11,
+    id: 0x58,
+    code_base: 0,
+    code_size: 0x8000,
+    erase_size: 32,
+    data_addr: 0xf000,
+    data_size: 0x400,
+    data_erase_size: 0,
+};
+
+pub static CH559: Chip = Chip {
+    name: "CH559",
+    family: Family::Ch55x,
+    device_type: 0x11,
+    id: 0x59,
+    code_base: 0,
+    code_size: 0xf000,
+    erase_size: 60,
+    data_addr: 0xf000,
+    data_size: 0x400,
+    data_erase_size: 0,
+};
+
+pub static CH32F103: Chip = Chip {
+    name: "CH32F103",
+    family: Family::Ch32,
+    device_type: 0x14,
+    id: 0x3f,
+    code_base: 0x08000000,
+    code_size: 0x10000,
+    erase_size: 64,
+    data_addr: 0,
+    data_size: 0,
+    data_erase_size: 0,
+};
+
+pub static CH32V103: Chip = Chip {
+    name: "CH32V103",
+    family: Family::Ch32,
+    device_type: 0x15,
+    id: 0x3f,
+    code_base: 0x08000000,
+    code_size: 0x10000,
+    erase_size: 64,
+    data_addr: 0,
+    data_size: 0,
+    data_erase_size: 0,
+};
+
+pub static CH32V203: Chip = Chip {
+    name: "CH32V203",
+    family: Family::Ch32,
+    device_type: 0x19,
+    id: 0x30,
+    code_base: 0x08000000,
+    code_size: 0x10000,
+    erase_size: 64,
+    data_addr: 0,
+    data_size: 0,
+    data_erase_size: 0,
+};
+
+pub static CH32V307: Chip = Chip {
+    name: "CH32V307",
+    family: Family::Ch32,
+    device_type: 0x17,
+    id: 0x70,
+    code_base: 0x08000000,
+    code_size: 0x40000,
+    erase_size: 256,
+    data_addr: 0,
+    data_size: 0,
+    data_erase_size: 0,
+};
+
+pub static CHIPS: [&Chip; 10] = [
+    &CH549, &CH551, &CH552, &CH554, &CH558, &CH559, &CH32F103, &CH32V103, &CH32V203, &CH32V307,
+];
+
+pub fn find(device_type: u8, id: u8) -> Option<&'static Chip> {
+    CHIPS
+        .iter()
+        .copied()
+        .find(|chip| chip.device_type == device_type && chip.id == id)
+}
+
+// A bit of BOOT_CFG[15:8] on CH55x parts, and what it means when it is
+// cleared and set. `bit` counts from bit 8.
+pub struct ConfigBit {
+    pub name: &'static str,
+    pub bit: u8,
+    pub cleared: &'static str,
+    pub set: &'static str,
+}
+
+// Code_Protect in BOOT_CFG[15:8]. The flash is protected while it is cleared.
+pub const CODE_PROTECT: u8 = 1 << 7;
+
+pub static CONFIG_BITS: [ConfigBit; 6] = [
+    ConfigBit {
+        name: "Code_Protect",
+        bit: 7,
+        cleared: "flash can not be read by programmers",
+        set: "flash can be read by programmers",
+    },
+    ConfigBit {
+        name: "No_Boot_Load",
+        bit: 6,
+        cleared: "boots the application at 0x0000",
+        set: "boots the bootloader that checks the entry pin",
+    },
+    ConfigBit {
+        name: "En_Long_Reset",
+        bit: 5,
+        cleared: "standard reset",
+        set: "long reset with additional 87ms",
+    },
+    ConfigBit {
+        name: "XT_OSC_STRONG",
+        bit: 4,
+        cleared: "standard crystal oscillator drive",
+        set: "enhanced crystal oscillator drive",
+    },
+    ConfigBit {
+        name: "En_P5.7_RESET",
+        bit: 3,
+        cleared: "P5.7 is not a reset input",
+        set: "P5.7 is a reset input",
+    },
+    ConfigBit {
+        name: "En_P0_Pullup",
+        bit: 2,
+        cleared: "P0 is not pulled up on reset",
+        set: "P0 is pulled up on reset",
+    },
+];
+
+// Returns reasons why writing `config` may leave the chip unable to enter the
+// bootloader again, if any.
+pub fn config_risks(config: u8) -> Vec<&'static str> {
+    let mut risks = Vec::new();
+    if config & (1 << 6) == 0 {
+        risks.push(
+            "No_Boot_Load is cleared, so the bootloader no longer runs on reset and \
+             the entry pin is ignored. Only the application can enter the bootloader.",
+        );
+    }
+    if config & 0x03 != 0x02 {
+        risks.push("Bits 9:8 are not 10, and the chip may not start as expected.");
+    }
+    risks
+}
+
+// Looks up a config bit by name, ignoring case.
+pub fn config_bit(name: &str) -> Option<&'static ConfigBit> {
+    CONFIG_BITS
+        .iter()
+        .find(|bit| bit.name.eq_ignore_ascii_case(name))
+}
+
+// Describes BOOT_CFG[15:8] in a line per bit. Bits 9:8 should be 10.
+pub fn describe_config(config: u8) -> Vec<String> {
+    let line = |bits: String, name: &str, value: String, meaning: &str| {
+        format!("{:<8}{:<14}{:>2}: {}", bits, name, value, meaning)
+    };
+    let mut lines: Vec<String> = CONFIG_BITS
+        .iter()
+        .map(|bit| {
+            let set = config & (1 << bit.bit) != 0;
+            let meaning = if set { bit.set } else { bit.cleared };
+            line(
+                format!("bit {}", bit.bit + 8),
+                bit.name,
+                u8::from(set).to_string(),
+                meaning,
+            )
+        })
+        .collect();
+    let must = config & 0x03;
+    let meaning = if must == 0x02 { "ok" } else { "should be 10" };
+    lines.push(line(
+        String::from("bit 9:8"),
+        "Must_10",
+        format!("{:02b}", must),
+        meaning,
+    ));
+    lines
+}
@@ -0,0 +1,87 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use crate::ch559::chip::{self, Family};
+use crate::ch559::{dump_response, Ch559, Error, Protocol};
+
+// Bootloader v1.x protocol. Requests are framed as [command, length, ...] and
+// responses are [status, 0]. Only the program area is supported.
+impl Ch559 {
+    pub(super) fn detect_v1(&mut self) -> Result<(), Error> {
+        // "USB DBG CH559 & ISP"
+        let detect_request = [
+            0xa2, 0x13, 0x55, 0x53, 0x42, 0x20, 0x44, 0x42, 0x47, 0x20, 0x43, 0x48, 0x35, 0x35,
+            0x39, 0x20, 0x26, 0x20, 0x49, 0x53, 0x50, 0x00,
+        ];
+        let mut detect_response: [u8; 2] = [0; 2];
+        self.send_receive(&detect_request, &mut detect_response)
+            .map_err(|e| Error::OnDetect(Box::new(e)))?;
+        dump_response("detect_v1", &detect_response);
+        let id = detect_response[0];
+        self.chip = chip::CHIPS
+            .iter()
+            .copied()
+            .find(|chip| chip.family == Family::Ch55x && chip.id == id)
+            .ok_or(Error::UnsupportedChip(0, id))?;
+        let version_request = [0xbb, 0x00];
+        let mut version_response: [u8; 2] = [0; 2];
+        self.send_receive(&version_request, &mut version_response)
+            .map_err(|e| Error::OnDetect(Box::new(e)))?;
+        dump_response("version_v1", &version_response);
+        self.version = format!(
+            "{}.{}",
+            version_response[0] >> 4,
+            version_response[0] & 0x0f
+        );
+        self.protocol = Protocol::V1;
+        Ok(())
+    }
+
+    pub(super) fn erase_v1(&mut self) -> Result<(), Error> {
+        self.erase_sectors_v1(0, (self.chip.code_size >> 10) as u32)
+    }
+
+    pub(super) fn erase_sectors_v1(&mut self, start: u32, count: u32) -> Result<(), Error> {
+        let request = [0xa6, 0x04, 0x00, 0x00, 0x00, 0x00];
+        let mut response: [u8; 2] = [0; 2];
+        self.send_receive(&request, &mut response)?;
+        for block in start..start + count {
+            let request = [0xa9, 0x02, 0x00, (block * 4) as u8];
+            self.send_receive(&request, &mut response)?;
+            if 0 != response[0] {
+                return Err(Error::Erase);
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn write_verify_in_range_v1(
+        &mut self,
+        addr: u32,
+        data: &[u8],
+        write: bool,
+    ) -> Result<(), Error> {
+        if data.len() > 0x38 {
+            return Err(Error::TooLargeReadSize);
+        }
+        let mut request: Vec<u8> = Vec::with_capacity(4 + data.len());
+        request.push(if write { 0xa8 } else { 0xa7 });
+        request.push((data.len() + 2) as u8);
+        request.push(addr as u8);
+        request.push((addr >> 8) as u8);
+        request.extend_from_slice(data);
+        let mut response: [u8; 2] = [0; 2];
+        self.send_receive(&request, &mut response)?;
+        if 0 != response[0] {
+            let err = if write { Error::Flash } else { Error::Verify };
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    pub(super) fn boot_v1(&mut self) -> Result<(), Error> {
+        let request = [0xa5, 0x02, 0x01, 0x00];
+        self.send(&request)?;
+        Ok(())
+    }
+}
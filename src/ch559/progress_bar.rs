@@ -1,35 +1,168 @@
 // Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
 // Use of this source code is governed by a BSD-style license that can be found
 // in the LICENSE file.
-use std::io::{stdout, Write};
+use std::io::{stderr, stdout, Write};
+use std::sync::{Arc, Mutex};
+
+// Where progress bars are drawn.
+#[derive(Clone, Default)]
+pub enum Output {
+    #[default]
+    Stdout,
+    // Keeps stdout for data, e.g. a dump that is piped to another tool.
+    Stderr,
+    Line(ProgressLine),
+}
 
 pub struct ProgressBar {
     size: usize,
     progress: usize,
+    output: Output,
 }
 
 impl ProgressBar {
-    pub fn new(size: usize) -> Self {
-        print!(
-            "[__________________________________________________] ({} bytes)\r[",
-            size
-        );
-        ProgressBar { size, progress: 0 }
+    pub fn new(size: usize, output: Output) -> Self {
+        let bar = ProgressBar {
+            size,
+            progress: 0,
+            output,
+        };
+        match &bar.output {
+            Output::Line(line) => line.update(0, size),
+            _ => bar.print(&format!(
+                "[__________________________________________________] ({} bytes)\r[",
+                size
+            )),
+        }
+        bar
     }
 
     pub fn progress(&mut self, progress: usize) {
-        let current = self.progress * 50 / self.size;
+        if let Output::Line(line) = &self.output {
+            self.progress = progress;
+            line.update(progress, self.size);
+            return;
+        }
+        let current = (self.progress * 50).checked_div(self.size).unwrap_or(0);
         self.progress = progress;
-        let updated = self.progress * 50 / self.size;
-        for _ in current..updated {
-            print!("#");
+        let updated = (self.progress * 50).checked_div(self.size).unwrap_or(0);
+        self.print(&"#".repeat(updated.saturating_sub(current)));
+    }
+
+    fn print(&self, text: &str) {
+        match self.output {
+            Output::Stdout => {
+                print!("{}", text);
+                stdout().flush().unwrap();
+            }
+            Output::Stderr => {
+                eprint!("{}", text);
+                stderr().flush().unwrap();
+            }
+            Output::Line(_) => {}
         }
-        stdout().flush().unwrap();
     }
 }
 
 impl Drop for ProgressBar {
     fn drop(&mut self) {
-        println!();
+        self.print("\n");
+    }
+}
+
+struct Line {
+    label: String,
+    size: usize,
+    progress: usize,
+    status: String,
+}
+
+#[derive(Default)]
+struct Lines {
+    lines: Vec<Line>,
+    drawn: usize,
+}
+
+impl Lines {
+    // Redraws all lines in place.
+    fn draw(&mut self) {
+        let mut out = stdout().lock();
+        if self.drawn > 0 {
+            let _ = write!(out, "\x1b[{}A", self.drawn);
+        }
+        for line in &self.lines {
+            let filled = filled(line.progress, line.size);
+            let _ = writeln!(
+                out,
+                "\x1b[2K{} [{}{}] {}",
+                line.label,
+                "#".repeat(filled),
+                "_".repeat(50 - filled),
+                line.status
+            );
+        }
+        self.drawn = self.lines.len();
+        let _ = out.flush();
+    }
+}
+
+fn filled(progress: usize, size: usize) -> usize {
+    (progress.min(size) * 50).checked_div(size).unwrap_or(0)
+}
+
+// Renders a labeled bar per device so that operations running on several
+// devices at once do not garble the output. Nothing else should be printed
+// while bars are shown.
+#[derive(Clone, Default)]
+pub struct MultiProgress {
+    lines: Arc<Mutex<Lines>>,
+}
+
+impl MultiProgress {
+    pub fn new() -> Self {
+        MultiProgress::default()
+    }
+
+    // Adds a bar at the bottom.
+    pub fn add(&self, label: &str) -> ProgressLine {
+        let mut lines = self.lines.lock().unwrap();
+        lines.lines.push(Line {
+            label: label.to_string(),
+            size: 0,
+            progress: 0,
+            status: String::new(),
+        });
+        lines.draw();
+        ProgressLine {
+            lines: self.lines.clone(),
+            index: lines.lines.len() - 1,
+        }
+    }
+}
+
+// A bar owned by a `MultiProgress`.
+#[derive(Clone)]
+pub struct ProgressLine {
+    lines: Arc<Mutex<Lines>>,
+    index: usize,
+}
+
+impl ProgressLine {
+    pub fn update(&self, progress: usize, size: usize) {
+        let mut lines = self.lines.lock().unwrap();
+        let line = &mut lines.lines[self.index];
+        let changed = line.size != size || filled(line.progress, size) != filled(progress, size);
+        line.size = size;
+        line.progress = progress;
+        if changed {
+            lines.draw();
+        }
+    }
+
+    // Shows a short message next to the bar, e.g. the last operation result.
+    pub fn set_status(&self, status: &str) {
+        let mut lines = self.lines.lock().unwrap();
+        lines.lines[self.index].status = status.to_string();
+        lines.draw();
     }
 }
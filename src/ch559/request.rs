@@ -0,0 +1,142 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use crate::ch559::chip::{self, Chip, Family};
+use crate::ch559::Error;
+
+// Request builders and response parsers of the v2 protocol, shared by the
+// blocking and async APIs.
+
+// "MCU ISP & WCH.CN"
+pub const DETECT: [u8; 21] = [
+    0xa1, 0x12, 0x00, 0x59, 0x11, 0x4d, 0x43, 0x55, 0x20, 0x49, 0x53, 0x50, 0x20, 0x26, 0x20, 0x57,
+    0x43, 0x48, 0x2e, 0x43, 0x4e,
+];
+
+pub const IDENTIFY: [u8; 5] = [0xa7, 0x02, 0x00, 0x1f, 0x00];
+
+pub const BOOT: [u8; 4] = [0xa2, 0x01, 0x00, 0x01];
+
+pub fn detected_chip(response: &[u8; 6]) -> Result<&'static Chip, Error> {
+    if response[0] != DETECT[0] {
+        return Err(Error::InvalidResponse);
+    }
+    chip::find(response[5], response[4]).ok_or(Error::UnsupportedChip(response[5], response[4]))
+}
+
+// Returns the bootloader version and the chip unique ID.
+pub fn identified(chip: &Chip, response: &[u8; 30]) -> (String, Vec<u8>) {
+    let version = format!("{}.{}{}", response[19], response[20], response[21]);
+    // CH55x reports a 4 bytes UID while CH32 reports a 8 bytes UID.
+    let uid_size = match chip.family {
+        Family::Ch55x => 4,
+        Family::Ch32 => 8,
+    };
+    (version, response[22..22 + uid_size].to_vec())
+}
+
+// Returns the bootloader version as a number, e.g. 231 for v2.31.
+pub fn version_code(version: &str) -> u32 {
+    version.replace('.', "").parse().unwrap_or(0)
+}
+
+// Returns a reset key request and the XOR key that the bootloader derives.
+pub fn reset_key(chip: &Chip, version: &str, uid: &[u8]) -> (Vec<u8>, [u8; 8]) {
+    let sum = uid.iter().fold(0u8, |a, b| a.wrapping_add(*b));
+    // Bootloader v2.4.0 and later derive the key from the UID in the same
+    // way as CH32 parts do.
+    let uid_key = chip.family == Family::Ch32 || version_code(version) >= 240;
+    if uid_key {
+        let mut request = vec![0; 0x21];
+        request[0..3].copy_from_slice(&[0xa3, 0x1e, 0x00]);
+        let mut key = [sum; 8];
+        key[7] = key[7].wrapping_add(chip.id);
+        (request, key)
+    } else {
+        let mut request = vec![sum; 0x33];
+        request[0..3].copy_from_slice(&[0xa3, 0x30, 0x00]);
+        let mut key = [0; 8];
+        key[7] = chip.id;
+        (request, key)
+    }
+}
+
+pub fn key_checksum(key: &[u8; 8]) -> u8 {
+    key.iter().fold(0, |a, b| a.wrapping_add(*b))
+}
+
+// Erases `size` sectors of 1KB from the bottom of the code flash.
+pub fn erase(chip: &Chip, size: u32) -> Vec<u8> {
+    match chip.family {
+        Family::Ch55x => vec![0xa4, 0x01, 0x00, size as u8],
+        Family::Ch32 => vec![
+            0xa4,
+            0x04,
+            0x00,
+            size as u8,
+            (size >> 8) as u8,
+            (size >> 16) as u8,
+            (size >> 24) as u8,
+        ],
+    }
+}
+
+pub fn erase_data(chip: &Chip) -> [u8; 4] {
+    [0xa9, 0x00, 0x00, chip.data_erase_size]
+}
+
+// `addr` is an offset from DATA_FLASH_ADDR.
+pub fn read_data(addr: u16, size: usize) -> Result<[u8; 8], Error> {
+    if size > 0x38 {
+        return Err(Error::TooLargeReadSize);
+    }
+    Ok([
+        0xab,
+        0x00,
+        0x00,
+        addr as u8,
+        (addr >> 8) as u8,
+        0x00,
+        0x00,
+        size as u8,
+    ])
+}
+
+// `addr` is an offset from DATA_FLASH_ADDR if `data_region` is true.
+pub fn write_verify(
+    chip: &Chip,
+    key: &[u8; 8],
+    addr: u32,
+    data: &[u8],
+    write: bool,
+    data_region: bool,
+) -> Result<Vec<u8>, Error> {
+    if data.len() > 0x38 {
+        return Err(Error::TooLargeReadSize);
+    }
+    let write_command = if data_region { 0xaa } else { 0xa5 };
+    let length = (data.len() + 7) & !7;
+    let mut request: Vec<u8> = Vec::with_capacity(8 + length);
+    let address = if data_region && !write {
+        addr + chip.data_addr as u32
+    } else {
+        addr
+    };
+    request.push(if write { write_command } else { 0xa6 });
+    request.push((length + 5) as u8);
+    request.push(0);
+    request.push(address as u8);
+    request.push((address >> 8) as u8);
+    request.push((address >> 16) as u8);
+    request.push((address >> 24) as u8);
+    request.push(length as u8);
+    for i in 0..length {
+        if i < data.len() {
+            request.push(data[i]);
+        } else {
+            request.push(0xff);
+        }
+        request[8 + i] ^= key[i & 7];
+    }
+    Ok(request)
+}
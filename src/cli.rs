@@ -0,0 +1,26 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use ch559flasher::transport::Location;
+
+pub mod doctor;
+pub mod flash;
+pub mod fleet;
+pub mod hexdump;
+pub mod monitor;
+pub mod operation;
+pub mod options;
+pub mod production;
+pub mod run;
+pub mod script;
+pub mod shell;
+pub mod udev;
+pub mod usb;
+
+// Names a device by its bus, address, and port path.
+pub fn label(location: &Location) -> String {
+    format!(
+        "{:03}:{:03} {}",
+        location.bus, location.address, location.port_path
+    )
+}
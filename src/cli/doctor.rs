@@ -0,0 +1,115 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use ch559flasher::transport;
+use ch559flasher::transport::serial::find_ports;
+use ch559flasher::Error;
+
+use crate::cli::label;
+use crate::cli::options::{selector, Options};
+use crate::cli::usb::{self, UsbTransport};
+
+// Prints connected bootloader devices, one per line.
+pub fn list(options: &Options) {
+    let selector = selector(options);
+    let locations = match UsbTransport::list(&selector) {
+        Ok(locations) => locations,
+        Err(error) => {
+            println!("list: {}", error);
+            std::process::exit(exitcode::IOERR);
+        }
+    };
+    for location in locations {
+        let device = label(&location);
+        match usb::open(&selector.at(&location)) {
+            Ok(ch559) => println!(
+                "{} {} (id: {:02x}, BootLoader: v{}, UID: {})",
+                device,
+                ch559.chip().name,
+                ch559.chip_id(),
+                ch559.version(),
+                ch559.uid_string()
+            ),
+            Err(error) => println!("{} {}", device, error),
+        }
+    }
+}
+
+// Checks the USB library, devices, permissions, and driver conflicts, and
+// prints how to fix problems found. Returns the exit code.
+pub fn doctor(options: &Options) -> i32 {
+    println!("backend: {}", UsbTransport::backend());
+    let selector = selector(options);
+    let locations = match UsbTransport::list(&selector) {
+        Ok(locations) => locations,
+        Err(error) => {
+            println!("list: {}", error);
+            println!("  fix: check that the USB library is installed and works");
+            return exitcode::UNAVAILABLE;
+        }
+    };
+    println!("devices: {}", locations.len());
+    if locations.is_empty() {
+        println!("  fix: enter the bootloader, e.g. connect USB with the entry pin pulled");
+        println!("  fix: {}", transport::NOT_FOUND_HINT);
+        return exitcode::UNAVAILABLE;
+    }
+    let mut code = exitcode::OK;
+    for location in locations {
+        let device = label(&location);
+        let selector = selector.at(&location);
+        if let Ok(true) = UsbTransport::kernel_driver_active(&selector) {
+            println!("{} kernel driver is bound to the interface", device);
+            println!("  fix: {}", transport::DRIVER_HINT);
+            code = exitcode::UNAVAILABLE;
+            continue;
+        }
+        match usb::open(&selector) {
+            Ok(ch559) => println!(
+                "{} ok, {} (BootLoader: v{})",
+                device,
+                ch559.chip().name,
+                ch559.version()
+            ),
+            Err(error) => {
+                println!("{} {}", device, error);
+                // Access and driver errors carry their own hints.
+                match error {
+                    Error::AccessDenied | Error::NoDriver => {}
+                    Error::ClaimInterface => {
+                        println!("  fix: close other applications that use the device")
+                    }
+                    _ => println!("  fix: reconnect the device in the bootloader mode"),
+                }
+                code = exitcode::UNAVAILABLE;
+            }
+        }
+    }
+    code
+}
+
+// Returns the serial port of the only WCH USB serial adapter connected, or
+// exits listing candidates if it is not unique.
+pub fn find_port() -> String {
+    let mut ports = match find_ports() {
+        Ok(ports) => ports,
+        Err(error) => {
+            println!("port: {}", error);
+            std::process::exit(exitcode::IOERR);
+        }
+    };
+    if ports.len() == 1 {
+        let (port, adapter) = ports.remove(0);
+        println!("port: {} ({})", port, adapter);
+        return port;
+    }
+    if ports.is_empty() {
+        println!("port: no CH340/CH343 adapter is found");
+    } else {
+        println!("port: several adapters are found, choose one with --port");
+        for (port, adapter) in ports {
+            println!("  {} ({})", port, adapter);
+        }
+    }
+    std::process::exit(exitcode::USAGE);
+}
@@ -0,0 +1,498 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use ch559flasher::ch559::{self, Output, ProgressLine};
+use ch559flasher::image::{self, Format};
+use ch559flasher::production::{sha256, Record};
+use ch559flasher::transport::{Location, Selector};
+use ch559flasher::{Ch559, Error, Fill, Image, Mismatch};
+use clap::ArgMatches;
+use std::time::{Duration, Instant};
+
+use crate::cli::fleet::fleet;
+use crate::cli::label;
+use crate::cli::operation::{operations, Operation};
+use crate::cli::options::{
+    ignore_ranges, parse_version, random_fill, selector, stdout_is_data, Options,
+};
+use crate::cli::production::{image_crc32, run_hook, written_sha256};
+use crate::cli::run::run;
+use crate::cli::usb::{self, UsbTransport};
+
+// Checks that SHA-256 of every input file is one of the expected ones so that
+// a stale or corrupted file is refused before anything is erased.
+fn check_sha256(options: &Options) -> Result<(), Error> {
+    if options.expect_sha256.is_empty() {
+        return Ok(());
+    }
+    let images = [
+        &options.write_program,
+        &options.compare_program,
+        &options.verify,
+        &options.write_data,
+        &options.compare_data,
+        &options.verify_data,
+    ];
+    let mut digests = Vec::new();
+    for filename in images.into_iter().flatten() {
+        digests.push(sha256(&image::read(filename)?));
+    }
+    // Templates are read as local files.
+    if let Some(filename) = options.data_template.as_ref() {
+        digests.push(sha256(&std::fs::read(filename)?));
+    }
+    for actual in digests {
+        let expected = |hash: &String| actual.eq_ignore_ascii_case(hash);
+        if !options.expect_sha256.iter().any(expected) {
+            return Err(Error::Checksum(actual));
+        }
+    }
+    Ok(())
+}
+
+// Loads a program image, and truncates and pads it as requested. Sizes are
+// counted from the bottom of the program area.
+pub fn load_program(
+    ch559: &Ch559,
+    filename: &str,
+    options: &Options,
+    format: Option<Format>,
+) -> Result<Image, Error> {
+    let bytes = image::read(filename)?;
+    let mut image = Image::parse(image::decompress(bytes)?, format)?;
+    let base = ch559.chip().code_base;
+    if image.start().is_some_and(|start| start >= base) {
+        image.rebase(base);
+    }
+    if let Some(size) = options.truncate_at {
+        image.truncate(size);
+    }
+    if let Some(size) = options.pad_to {
+        image.pad(size, 0xff);
+    }
+    Ok(image)
+}
+
+// Loads a data image. Addresses at or above the data area start are rebased
+// to be relative to the data area.
+pub fn load_data_at(ch559: &Ch559, filename: &str, format: Option<Format>) -> Result<Image, Error> {
+    let mut image = Image::load(filename, format)?;
+    let base = ch559.chip().data_addr;
+    if image.start().is_some_and(|start| start >= base) {
+        image.rebase(base);
+    }
+    Ok(image)
+}
+
+// Loads a data image. A short image is padded with 0xff to the size of the
+// data area on --force.
+pub fn load_data(
+    ch559: &Ch559,
+    filename: &str,
+    options: &Options,
+    format: Option<Format>,
+    log: &dyn Fn(&str),
+) -> Result<Image, Error> {
+    let mut image = load_data_at(ch559, filename, format)?;
+    let data_size = ch559.chip().data_size;
+    if options.force && !options.fullfill && image.len() < data_size {
+        log(&format!(
+            "warning: {} has 0x{:x} bytes, and is padded with 0xff to 0x{:x}",
+            filename,
+            image.len(),
+            data_size
+        ));
+        image.pad(data_size, 0xff);
+    }
+    Ok(image)
+}
+
+// Logs a verify result with all differing chunk addresses, and writes the
+// differing bytes into the diff file if requested.
+pub fn verify(
+    name: &str,
+    result: Result<Mismatch, Error>,
+    options: &Options,
+    log: &dyn Fn(&str),
+) -> Result<(), exitcode::ExitCode> {
+    match result {
+        Ok(mismatch) if mismatch.is_empty() => log(&format!("{}: complete", name)),
+        Ok(mismatch) => {
+            if let Some(filename) = options.diff.as_ref() {
+                if let Err(error) = mismatch.save(filename) {
+                    log(&format!("{}: {}", name, error));
+                    return Err(exitcode::IOERR);
+                }
+            }
+            log(&format!(
+                "{}: {} bytes differ in {} chunks",
+                name,
+                mismatch.bytes,
+                mismatch.chunks.len()
+            ));
+            for addr in mismatch.chunks {
+                log(&format!("  0x{:04x}", addr));
+            }
+            return Err(exitcode::DATAERR);
+        }
+        Err(error) => {
+            log(&format!("{}: {}", name, error));
+            return Err(exitcode::IOERR);
+        }
+    }
+    Ok(())
+}
+
+// Polls the bus until the booted application enumerates as `vid`:`pid`.
+pub fn wait_app(
+    vid: u16,
+    pid: u16,
+    timeout: u64,
+    log: &dyn Fn(&str),
+) -> Result<(), exitcode::ExitCode> {
+    let selector = Selector {
+        vid: Some(vid),
+        pid: Some(pid),
+        ..Default::default()
+    };
+    let start = Instant::now();
+    loop {
+        match UsbTransport::list(&selector) {
+            Ok(locations) if !locations.is_empty() => {
+                log(&format!(
+                    "boot: application {:04x}:{:04x} found in {:.1}s",
+                    vid,
+                    pid,
+                    start.elapsed().as_secs_f64()
+                ));
+                return Ok(());
+            }
+            Ok(_) => (),
+            Err(error) => {
+                log(&format!("boot: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        }
+        if start.elapsed() >= Duration::from_secs(timeout) {
+            log(&format!(
+                "boot: application {:04x}:{:04x} not found in {}s",
+                vid, pid, timeout
+            ));
+            return Err(exitcode::IOERR);
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+}
+
+// Runs all requested operations on a device, and stops on the first failure.
+pub fn flash(
+    ch559: &mut Ch559,
+    options: &Options,
+    matches: &ArgMatches,
+    log: &dyn Fn(&str),
+) -> Result<(), exitcode::ExitCode> {
+    let mut found = format!(
+        "{} Found (BootLoader: v{}",
+        ch559.chip().name,
+        ch559.version()
+    );
+    if let Some(config) = ch559.rom_config() {
+        found += &format!(", ROM_CFG[17:8]: {:02x}", config);
+    }
+    // v1 bootloaders do not report the UID.
+    if !ch559.uid().is_empty() {
+        found += &format!(", UID: {}", ch559.uid_string());
+    }
+    log(&format!("{})", found));
+    // A different chip is refused before anything is erased.
+    if let Some(expected) = options.expect_chip.as_ref() {
+        if !ch559.chip().is(expected) {
+            log(&format!(
+                "expect_chip: {} found instead of {}",
+                ch559.chip().name,
+                expected
+            ));
+            return Err(exitcode::DATAERR);
+        }
+    }
+    if let Some(min) = options.min_bootloader {
+        if parse_version(ch559.version()).map_or(true, |version| version < min) {
+            log(&format!(
+                "min_bootloader: v{} is older than v{}.{:02}",
+                ch559.version(),
+                min.0,
+                min.1
+            ));
+            return Err(exitcode::DATAERR);
+        }
+    }
+    if !ch559.is_tested_version() {
+        log(&format!(
+            "warning: bootloader v{} is not tested, and the key may be derived \
+             wrongly (tested: v{})",
+            ch559.version(),
+            ch559::TESTED_VERSIONS.join(", v")
+        ));
+        if !options.allow_untested_bootloader {
+            log("  use --allow-untested-bootloader to proceed anyway");
+            return Err(exitcode::USAGE);
+        }
+    }
+    let fleet_options;
+    let options = match options.fleet.as_ref() {
+        Some(filename) => {
+            fleet_options = fleet(ch559, options, filename, log)?;
+            &fleet_options
+        }
+        None => options,
+    };
+    if let Some(seed) = options.seed {
+        log(&format!("random seed: {}", seed));
+        ch559.set_seed(seed);
+    } else if random_fill(options) {
+        // Picks a new seed, and shows it so that the fill can be reproduced.
+        let seed = rand::random::<u64>();
+        log(&format!(
+            "random seed: {} (use --seed {} to compare later)",
+            seed, seed
+        ));
+        ch559.set_seed(seed);
+    }
+    if stdout_is_data(options) {
+        ch559.set_progress(Output::Stderr);
+    }
+    ch559.set_fill_over_data(options.fullfill_data);
+    ch559.set_allow_data_overwrite(options.allow_overwrite_dataflash);
+    ch559.set_allow_risky_config(options.i_know_what_i_am_doing);
+    if let Some(byte) = options.fill_byte {
+        ch559.set_fill(Fill::Byte(byte));
+    }
+    if let Some(filename) = options.fill_pattern.as_ref() {
+        match std::fs::read(filename) {
+            Ok(pattern) if !pattern.is_empty() => ch559.set_fill(Fill::Pattern(pattern)),
+            Ok(_) => {
+                log("fill_pattern: empty pattern");
+                return Err(exitcode::DATAERR);
+            }
+            Err(error) => {
+                log(&format!("fill_pattern: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        }
+    }
+    match ignore_ranges(options) {
+        Ok(ranges) => ch559.set_ignore(ranges),
+        Err(error) => {
+            log(&format!("ignore: {}", error));
+            return Err(exitcode::DATAERR);
+        }
+    }
+    match check_sha256(options) {
+        Ok(()) => (),
+        Err(error @ Error::Checksum(_)) => {
+            log(&format!("expect_sha256: {}", error));
+            return Err(exitcode::DATAERR);
+        }
+        Err(error) => {
+            log(&format!("expect_sha256: {}", error));
+            return Err(exitcode::IOERR);
+        }
+    }
+    let (program_unchanged, data_unchanged) = if options.if_changed {
+        match unchanged(ch559, options, log) {
+            Ok(unchanged) => unchanged,
+            Err(error) => {
+                log(&format!("if_changed: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        }
+    } else {
+        (false, false)
+    };
+    for operation in operations(options, matches) {
+        let skip = match operation {
+            Operation::Erase | Operation::WriteProgram(_) => program_unchanged,
+            Operation::EraseData | Operation::WriteData(_) => data_unchanged,
+            _ => false,
+        };
+        if !skip {
+            run(ch559, &operation, options, log)?;
+        }
+    }
+    if random_fill(options) {
+        log(&format!("random seed: {}", ch559.seed()));
+    }
+    Ok(())
+}
+
+// Compares files to write with the flash in advance, and returns whether the
+// program and data areas already match them. An area that is erased
+// explicitly is written anyway.
+fn unchanged(
+    ch559: &mut Ch559,
+    options: &Options,
+    log: &dyn Fn(&str),
+) -> Result<(bool, bool), Error> {
+    let format: Option<Format> = options.format.map(|format| format.into());
+    // A random fill without a seed differs from the one written before, and
+    // then only bytes in the files are compared.
+    let specified_only = random_fill(options) && options.seed.is_none();
+    let matches = |ch559: &mut Ch559, image: Image, data_region: bool| {
+        if specified_only {
+            return ch559.compare_specified(image, data_region);
+        }
+        match ch559.write_image(image, false, data_region, options.fullfill) {
+            Ok(()) => Ok(true),
+            Err(Error::Verify) => Ok(false),
+            Err(error) => Err(error),
+        }
+    };
+    let mut program = false;
+    if let Some(filename) = options.write_program.as_ref() {
+        if !options.erase && !options.erase_all && options.erase_sectors.is_none() {
+            let image = load_program(ch559, filename, options, format)?;
+            program = matches(ch559, image, false)?;
+        }
+    }
+    if program {
+        log("write: skipped as program area already matches");
+    }
+    let mut data = false;
+    if let Some(filename) = options.write_data.as_ref() {
+        if !options.erase_data && !options.erase_all {
+            let image = load_data(ch559, filename, options, format, log)?;
+            data = matches(ch559, image, true)?;
+        }
+    }
+    if data {
+        log("write_data: skipped as data area already matches");
+    }
+    Ok((program, data))
+}
+
+// Opens the device at `location` and runs requested operations on it. The
+// result is appended to the production log if requested.
+pub fn flash_at(
+    location: &Location,
+    options: &Options,
+    matches: &ArgMatches,
+    log: &dyn Fn(&str),
+    line: Option<ProgressLine>,
+) -> Result<(), exitcode::ExitCode> {
+    let start = Instant::now();
+    let mut record = Record {
+        device: label(location),
+        ..Default::default()
+    };
+    let mut uid = String::new();
+    let result = match usb::open(&selector(options).at(location)) {
+        Ok(mut ch559) => {
+            record.chip = ch559.chip().name.to_string();
+            record.chip_id = Some(ch559.chip_id());
+            record.version = ch559.version().to_string();
+            uid = ch559.uid_string();
+            match line {
+                Some(line) => {
+                    ch559.set_progress(Output::Line(line.clone()));
+                    ch559.set_warning(Box::new(move |message| {
+                        line.set_status(&format!("warning: {}", message))
+                    }));
+                }
+                None => ch559.set_warning(Box::new(|message| println!("warning: {}", message))),
+            }
+            let result = flash(&mut ch559, options, matches, log);
+            if random_fill(options) {
+                record.seed = Some(ch559.seed());
+            }
+            record.image_crc32 = image_crc32(&ch559);
+            record.written_sha256 = written_sha256(&ch559);
+            result
+        }
+        Err(error) => {
+            log(&error.to_string());
+            Err(exitcode::IOERR)
+        }
+    };
+    record.passed = result.is_ok();
+    if let Some(filename) = options.log.as_ref() {
+        if let Err(error) = record.append(filename) {
+            log(&format!("log: {}", error));
+        }
+    }
+    run_hook(options, &record, &uid, start.elapsed(), log);
+    result
+}
+
+#[cfg(all(test, feature = "simulator"))]
+mod tests {
+    use super::*;
+    use ch559flasher::ch559::chip;
+    use ch559flasher::transport::simulator::Simulator;
+    use clap::Parser;
+
+    #[test]
+    fn if_changed() {
+        let path = std::env::temp_dir().join(format!("ch559flasher-{}.bin", std::process::id()));
+        std::fs::write(&path, [1, 2, 3]).unwrap();
+        let filename = path.to_str().unwrap();
+        let parse = |args: &[&str]| {
+            Options::parse_from(
+                ["ch559flasher", "--if-changed", "-w", filename]
+                    .iter()
+                    .chain(args),
+            )
+        };
+        let mut ch559 = Ch559::with_transport(Box::new(Simulator::new(&chip::CH559))).unwrap();
+        ch559.set_progress(Output::Stderr);
+        let log = |_: &str| {};
+        let options = parse(&[]);
+        assert_eq!(
+            unchanged(&mut ch559, &options, &log).unwrap(),
+            (false, false)
+        );
+        ch559
+            .write_image(Image::from_binary(vec![1, 2, 3]), true, false, false)
+            .unwrap();
+        assert_eq!(
+            unchanged(&mut ch559, &options, &log).unwrap(),
+            (true, false)
+        );
+        // A random fill without a seed compares bytes in the file only.
+        let options = parse(&["--fullfill"]);
+        assert_eq!(
+            unchanged(&mut ch559, &options, &log).unwrap(),
+            (true, false)
+        );
+        // The fill is compared otherwise, and 00 does not match erased bytes.
+        ch559.set_fill(Fill::Byte(0));
+        let options = parse(&["--fullfill", "--fill-byte", "00"]);
+        assert_eq!(
+            unchanged(&mut ch559, &options, &log).unwrap(),
+            (false, false)
+        );
+        // An explicit erase writes anyway.
+        let options = parse(&["-e"]);
+        assert_eq!(
+            unchanged(&mut ch559, &options, &log).unwrap(),
+            (false, false)
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn expect_sha256() {
+        let path = std::env::temp_dir().join(format!("ch559flasher-{}.sha", std::process::id()));
+        std::fs::write(&path, b"abc").unwrap();
+        let filename = path.to_str().unwrap();
+        let abc = "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD";
+        let check =
+            |args: &[&str]| check_sha256(&Options::parse_from(["ch559flasher"].iter().chain(args)));
+        assert!(check(&["-W", filename, "--expect-sha256", abc]).is_ok());
+        assert!(check(&["-W", filename, "--expect-sha256", "00"]).is_err());
+        assert!(check(&["--data-template", filename, "--sha256", "00"]).is_err());
+        assert!(
+            check(&["-w", filename, "-W", filename, "--sha256", "00", "--sha256", abc]).is_ok()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
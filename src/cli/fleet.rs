@@ -0,0 +1,182 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use ch559flasher::ch559::{MultiProgress, ProgressLine};
+use ch559flasher::production::Fleet;
+use ch559flasher::transport::{Location, Selector};
+use ch559flasher::Ch559;
+use clap::ArgMatches;
+use std::time::Duration;
+
+use crate::cli::flash::flash_at;
+use crate::cli::label;
+use crate::cli::options::{selector, Options};
+use crate::cli::usb::UsbTransport;
+
+// Returns options to write and compare images that the fleet CSV file maps
+// the device to.
+pub fn fleet(
+    ch559: &Ch559,
+    options: &Options,
+    filename: &str,
+    log: &dyn Fn(&str),
+) -> Result<Options, exitcode::ExitCode> {
+    let fleet = Fleet::load(filename).map_err(|error| {
+        log(&format!("fleet: {}", error));
+        exitcode::DATAERR
+    })?;
+    let uid = ch559.uid_string();
+    let Some(entry) = fleet.lookup(ch559.uid()) else {
+        log(&format!("fleet: no entry for UID {}", uid));
+        return Err(exitcode::DATAERR);
+    };
+    log(&format!("fleet: UID {}", uid));
+    Ok(Options {
+        write_program: entry.program.clone(),
+        compare_program: entry.program.clone(),
+        write_data: entry.data.clone(),
+        compare_data: entry.data.clone(),
+        ..options.clone()
+    })
+}
+
+// Runs requested operations on every connected bootloader device, and exits
+// with a summary. Devices are flashed in turn, or at once on `--parallel`.
+pub fn all(options: &Options, matches: &ArgMatches) -> ! {
+    let locations = match UsbTransport::list(&selector(options)) {
+        Ok(locations) => locations,
+        Err(error) => {
+            println!("all: {}", error);
+            std::process::exit(exitcode::IOERR);
+        }
+    };
+    if locations.is_empty() {
+        println!("all: no device found");
+        std::process::exit(exitcode::UNAVAILABLE);
+    }
+    let failed = if options.parallel {
+        let width = locations.iter().map(|l| label(l).len()).max().unwrap_or(0);
+        let multi = MultiProgress::new();
+        let lines: Vec<ProgressLine> = locations
+            .iter()
+            .map(|location| multi.add(&format!("{:<width$}", label(location))))
+            .collect();
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = locations
+                .iter()
+                .zip(lines)
+                .map(|(location, line)| {
+                    scope.spawn(move || {
+                        let log = |message: &str| line.set_status(message);
+                        let result = flash_at(location, options, matches, &log, Some(line.clone()));
+                        if result.is_ok() {
+                            line.set_status("complete");
+                        }
+                        result
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join())
+                .filter(|result| !matches!(result, Ok(Ok(()))))
+                .count()
+        })
+    } else {
+        let mut failed = 0;
+        for location in &locations {
+            println!("{}", label(location));
+            let log = |message: &str| println!("{}", message);
+            match flash_at(location, options, matches, &log, None) {
+                Ok(()) => println!("device: complete"),
+                Err(_) => {
+                    println!("device: failed");
+                    failed += 1;
+                }
+            }
+        }
+        failed
+    };
+    println!(
+        "all: {} passed, {} failed",
+        locations.len() - failed,
+        failed
+    );
+    if failed > 0 {
+        std::process::exit(exitcode::IOERR);
+    }
+    std::process::exit(exitcode::OK);
+}
+
+// Runs requested operations on each bootloader device as it is connected,
+// until interrupted.
+pub fn daemon(options: &Options, matches: &ArgMatches) -> ! {
+    let receiver = match UsbTransport::watch(&selector(options)) {
+        Ok(receiver) => receiver,
+        Err(error) => {
+            println!("daemon: {}", error);
+            std::process::exit(exitcode::UNAVAILABLE);
+        }
+    };
+    println!("daemon: waiting for devices");
+    for location in receiver {
+        println!("{}", label(&location));
+        let log = |message: &str| println!("{}", message);
+        match flash_at(&location, options, matches, &log, None) {
+            Ok(()) => println!("device: complete"),
+            Err(_) => println!("device: failed"),
+        }
+    }
+    std::process::exit(exitcode::OK);
+}
+
+// Polls until a device that `selector` matches is connected, or until no such
+// device is connected if `connected` is false.
+fn poll(selector: &Selector, connected: bool) -> Option<Location> {
+    loop {
+        let mut locations = match UsbTransport::list(selector) {
+            Ok(locations) => locations,
+            Err(error) => {
+                println!("loop: {}", error);
+                std::process::exit(exitcode::IOERR);
+            }
+        };
+        let index = selector.index.unwrap_or(0);
+        if connected && locations.len() > index {
+            return Some(locations.swap_remove(index));
+        }
+        if !connected && locations.is_empty() {
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+}
+
+// Manufacturing loop that waits for a device, runs requested operations on
+// it, and waits for its removal, until interrupted.
+pub fn repeat(options: &Options, matches: &ArgMatches) -> ! {
+    let selector = selector(options);
+    let mut passed = 0;
+    let mut failed = 0;
+    loop {
+        println!("loop: waiting for a device");
+        let Some(location) = poll(&selector, true) else {
+            continue;
+        };
+        println!("{}", label(&location));
+        let log = |message: &str| println!("{}", message);
+        match flash_at(&location, options, matches, &log, None) {
+            Ok(()) => {
+                println!("device: complete");
+                passed += 1;
+            }
+            Err(_) => {
+                println!("device: failed");
+                failed += 1;
+            }
+        }
+        println!("loop: {} passed, {} failed", passed, failed);
+        println!("loop: waiting for removal");
+        poll(&selector.at(&location), false);
+    }
+}
@@ -0,0 +1,38 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+
+// Formats bytes in the canonical hex and ASCII layout of `hexdump -C`. Lines
+// that repeat the previous one are folded into a "*".
+pub fn hexdump(bytes: &[u8], base: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut previous: Option<&[u8]> = None;
+    for (index, row) in bytes.chunks(16).enumerate() {
+        if previous == Some(row) {
+            if lines.last().is_none_or(|line| line != "*") {
+                lines.push(String::from("*"));
+            }
+            continue;
+        }
+        previous = Some(row);
+        let mut line = format!("{:08x} ", base + index * 16);
+        for (i, byte) in row.iter().enumerate() {
+            if i % 8 == 0 {
+                line.push(' ');
+            }
+            line.push_str(&format!("{:02x} ", byte));
+        }
+        line.push_str(&" ".repeat((16 - row.len()) * 3 + usize::from(row.len() <= 8)));
+        let ascii: String = row
+            .iter()
+            .map(|byte| match byte {
+                0x20..=0x7e => *byte as char,
+                _ => '.',
+            })
+            .collect();
+        line.push_str(&format!(" |{}|", ascii));
+        lines.push(line);
+    }
+    lines.push(format!("{:08x}", base + bytes.len()));
+    lines
+}
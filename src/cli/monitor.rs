@@ -0,0 +1,64 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+#[cfg(feature = "serial")]
+use std::io::{stdout, Read, Write};
+#[cfg(feature = "serial")]
+use std::time::{Duration, Instant};
+
+// Echoes output of the serial port until the process is interrupted. The port
+// may be provided by the booted application, and is retried until `timeout`.
+#[cfg(feature = "serial")]
+pub fn monitor(
+    port: &str,
+    baud: u32,
+    timeout: u64,
+    log: &dyn Fn(&str),
+) -> Result<(), exitcode::ExitCode> {
+    let start = Instant::now();
+    let mut serial = loop {
+        match serialport::new(port, baud)
+            .timeout(Duration::from_millis(100))
+            .open()
+        {
+            Ok(serial) => break serial,
+            Err(_) if start.elapsed() < Duration::from_secs(timeout) => {
+                std::thread::sleep(Duration::from_millis(200));
+            }
+            Err(error) => {
+                log(&format!("monitor: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        }
+    };
+    log(&format!(
+        "monitor: {} at {} baud, Ctrl-C to quit",
+        port, baud
+    ));
+    let mut buffer = [0; 256];
+    loop {
+        match serial.read(&mut buffer) {
+            Ok(size) => {
+                let mut out = stdout().lock();
+                let _ = out.write_all(&buffer[..size]);
+                let _ = out.flush();
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::TimedOut => (),
+            Err(error) => {
+                log(&format!("monitor: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "serial"))]
+pub fn monitor(
+    _port: &str,
+    _baud: u32,
+    _timeout: u64,
+    log: &dyn Fn(&str),
+) -> Result<(), exitcode::ExitCode> {
+    log("monitor: not supported on builds without the serial feature");
+    Err(exitcode::UNAVAILABLE)
+}
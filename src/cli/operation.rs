@@ -0,0 +1,334 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory};
+
+use crate::cli::options::Options;
+
+pub enum Operation {
+    Erase,
+    EraseAll,
+    EraseSectors(u32, u32),
+    BlankCheck,
+    WriteProgram(String),
+    CompareProgram(String),
+    Verify(String),
+    EraseData,
+    BlankCheckData,
+    ChecksumData,
+    DumpData,
+    EditData,
+    DataGet,
+    DataSet,
+    DataReadField,
+    DataWriteField,
+    ReadData(String),
+    WriteData(String),
+    CompareData(String),
+    VerifyData(String),
+    WriteTemplate(String),
+    WriteSerial,
+    Info,
+    Shell,
+    ReadConfig,
+    Protect,
+    Unprotect,
+    WriteConfig(String),
+    ModifyConfig,
+    Bundle(String),
+    Snapshot(String),
+    Restore(String),
+    VerifyGolden(String),
+    Script(String),
+    Sleep(u64),
+    Wait,
+    Boot,
+}
+
+// Returns the index of an option in the command line, or None if it is not
+// given in the command line.
+fn command_line_index(matches: &ArgMatches, id: &str) -> Option<usize> {
+    match matches.value_source(id) {
+        Some(ValueSource::CommandLine) => matches.index_of(id),
+        _ => None,
+    }
+}
+
+// Index of operations that a project or fleet file supplies. They follow the
+// ones in the command line.
+const SUPPLIED: usize = usize::MAX - 1;
+
+// Writes, and explicit erases that replace their implicit erase.
+const EXPLICIT_ERASES: [(&str, &[&str]); 3] = [
+    ("write_program", &["erase", "erase_all", "erase_sectors"]),
+    ("write_data", &["erase_data", "erase_all"]),
+    ("data_template", &["erase_data", "erase_all"]),
+];
+
+// Checks that explicit erases come before writes in the command line, as an
+// erase after a write would wipe what is written.
+pub fn check_erase_order(options: &Options, matches: &ArgMatches) -> Result<(), String> {
+    let written = [
+        options.write_program.is_some(),
+        options.write_data.is_some(),
+        options.data_template.is_some(),
+    ];
+    let long = |id: &str| {
+        Options::command()
+            .get_arguments()
+            .find(|arg| arg.get_id() == id)
+            .and_then(|arg| arg.get_long())
+            .unwrap_or_default()
+            .to_string()
+    };
+    for ((write, erases), written) in EXPLICIT_ERASES.iter().zip(written) {
+        if !written {
+            continue;
+        }
+        let index = command_line_index(matches, write).unwrap_or(SUPPLIED);
+        for erase in erases.iter() {
+            if command_line_index(matches, erase).is_some_and(|i| i > index) {
+                return Err(format!(
+                    "--{} has to come before --{}",
+                    long(erase),
+                    long(write)
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Lists requested operations in the order given on the command line. Writes
+// are preceded by an implicit erase unless an explicit erase is requested
+// before them, see check_erase_order(). Supplied operations follow in the
+// order below.
+pub fn operations(options: &Options, matches: &ArgMatches) -> Vec<Operation> {
+    let index_of = |id: &str| command_line_index(matches, id);
+    let mut operations: Vec<(usize, Operation)> = Vec::new();
+    if let Some(index) = index_of("erase") {
+        operations.push((index, Operation::Erase));
+    }
+    if let Some(index) = index_of("erase_all") {
+        operations.push((index, Operation::EraseAll));
+    }
+    if let Some((start, count)) = options.erase_sectors {
+        let index = index_of("erase_sectors").unwrap_or(SUPPLIED);
+        operations.push((index, Operation::EraseSectors(start, count)));
+    }
+    if let Some(index) = index_of("blank_check") {
+        operations.push((index, Operation::BlankCheck));
+    }
+    if let Some(filename) = options.write_program.as_ref() {
+        let index = index_of("write_program").unwrap_or(SUPPLIED);
+        if !options.erase
+            && !options.erase_all
+            && options.erase_sectors.is_none()
+            && !options.no_erase
+        {
+            operations.push((index, Operation::Erase));
+        }
+        operations.push((index, Operation::WriteProgram(filename.clone())));
+    }
+    if let Some(filename) = options.compare_program.as_ref() {
+        let index = index_of("compare_program").unwrap_or(SUPPLIED);
+        operations.push((index, Operation::CompareProgram(filename.clone())));
+    }
+    if let Some(filename) = options.verify.as_ref() {
+        let index = index_of("verify").unwrap_or(SUPPLIED);
+        operations.push((index, Operation::Verify(filename.clone())));
+    }
+    if let Some(index) = index_of("erase_data") {
+        operations.push((index, Operation::EraseData));
+    }
+    if let Some(index) = index_of("blank_check_data") {
+        operations.push((index, Operation::BlankCheckData));
+    }
+    if let Some(index) = index_of("checksum_data") {
+        operations.push((index, Operation::ChecksumData));
+    }
+    if let Some(index) = index_of("dump_data") {
+        operations.push((index, Operation::DumpData));
+    }
+    if let Some(index) = index_of("edit_data") {
+        operations.push((index, Operation::EditData));
+    }
+    if let Some(index) = index_of("data_get") {
+        operations.push((index, Operation::DataGet));
+    }
+    if let Some(index) = index_of("data_set") {
+        operations.push((index, Operation::DataSet));
+    }
+    if let Some(index) = index_of("data_read_field") {
+        operations.push((index, Operation::DataReadField));
+    }
+    if let Some(index) = index_of("data_write_field") {
+        operations.push((index, Operation::DataWriteField));
+    }
+    if let Some(filename) = options.read_data.as_ref() {
+        let index = index_of("read_data").unwrap_or(SUPPLIED);
+        operations.push((index, Operation::ReadData(filename.clone())));
+    }
+    if let Some(filename) = options.write_data.as_ref() {
+        let index = index_of("write_data").unwrap_or(SUPPLIED);
+        // A partial write erases the data area by itself.
+        if !options.erase_data
+            && !options.erase_all
+            && options.write_data_at.is_none()
+            && !options.patch_data
+        {
+            operations.push((index, Operation::EraseData));
+        }
+        operations.push((index, Operation::WriteData(filename.clone())));
+    }
+    if let Some(filename) = options.compare_data.as_ref() {
+        let index = index_of("compare_data").unwrap_or(SUPPLIED);
+        operations.push((index, Operation::CompareData(filename.clone())));
+    }
+    if let Some(filename) = options.verify_data.as_ref() {
+        let index = index_of("verify_data").unwrap_or(SUPPLIED);
+        operations.push((index, Operation::VerifyData(filename.clone())));
+    }
+    if let Some(filename) = options.data_template.as_ref() {
+        let index = index_of("data_template").unwrap_or(SUPPLIED);
+        if !options.erase_data && !options.erase_all {
+            operations.push((index, Operation::EraseData));
+        }
+        operations.push((index, Operation::WriteTemplate(filename.clone())));
+    }
+    // A serial number goes into the template instead if a template is used.
+    if let Some(index) = index_of("serial").or(index_of("serial_counter")) {
+        if options.data_template.is_none() {
+            operations.push((index, Operation::WriteSerial));
+        }
+    }
+    if let Some(index) = index_of("info") {
+        operations.push((index, Operation::Info));
+    }
+    if let Some(index) = index_of("shell") {
+        operations.push((index, Operation::Shell));
+    }
+    if let Some(filename) = options.script.as_ref() {
+        let index = index_of("script").unwrap_or(SUPPLIED);
+        operations.push((index, Operation::Script(filename.clone())));
+    }
+    if let Some(index) = index_of("read_config") {
+        operations.push((index, Operation::ReadConfig));
+    }
+    if let Some(config) = options.config.as_ref() {
+        let index = index_of("config").unwrap_or(SUPPLIED);
+        operations.push((index, Operation::WriteConfig(config.clone())));
+    }
+    if let Some(index) = index_of("config_set").or(index_of("config_clear")) {
+        operations.push((index, Operation::ModifyConfig));
+    }
+    if let Some(index) = index_of("protect") {
+        operations.push((index, Operation::Protect));
+    }
+    // Unprotecting erases everything, and then goes first.
+    if options.unprotect {
+        operations.push((0, Operation::Unprotect));
+    }
+    if let Some(filename) = options.bundle.as_ref() {
+        let index = index_of("bundle").unwrap_or(SUPPLIED);
+        operations.push((index, Operation::Bundle(filename.clone())));
+    }
+    if let Some(filename) = options.snapshot.as_ref() {
+        let index = index_of("snapshot").unwrap_or(SUPPLIED);
+        operations.push((index, Operation::Snapshot(filename.clone())));
+    }
+    if let Some(filename) = options.restore.as_ref() {
+        let index = index_of("restore").unwrap_or(SUPPLIED);
+        operations.push((index, Operation::Restore(filename.clone())));
+    }
+    if let Some(filename) = options.verify_golden.as_ref() {
+        let index = index_of("verify_golden").unwrap_or(SUPPLIED);
+        operations.push((index, Operation::VerifyGolden(filename.clone())));
+    }
+    // Boot may come from a project file, and then goes last. It also follows
+    // supplied operations as they can not run after it.
+    if options.boot {
+        let index = match index_of("boot") {
+            Some(index) if operations.iter().all(|(index, _)| *index != SUPPLIED) => index,
+            _ => usize::MAX,
+        };
+        operations.push((index, Operation::Boot));
+    }
+    operations.sort_by_key(|(index, _)| *index);
+    operations
+        .into_iter()
+        .map(|(_, operation)| operation)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::FromArgMatches;
+
+    fn parse(args: &[&str]) -> (Options, ArgMatches) {
+        let matches = Options::command()
+            .try_get_matches_from(["ch559flasher"].iter().chain(args))
+            .unwrap();
+        (Options::from_arg_matches(&matches).unwrap(), matches)
+    }
+
+    #[test]
+    fn erase_order() {
+        let (options, matches) = parse(&["-w", "a.bin", "-e"]);
+        assert_eq!(
+            check_erase_order(&options, &matches).unwrap_err(),
+            "--erase has to come before --write-program"
+        );
+        let (options, matches) = parse(&["-W", "a.bin", "--erase-all"]);
+        assert!(check_erase_order(&options, &matches).is_err());
+        let (options, matches) = parse(&["-e", "-w", "a.bin", "-W", "b.bin"]);
+        assert!(check_erase_order(&options, &matches).is_ok());
+        // The explicit erase replaces the implicit one, and the data area is
+        // erased implicitly.
+        assert!(matches!(
+            operations(&options, &matches)[..],
+            [
+                Operation::Erase,
+                Operation::WriteProgram(_),
+                Operation::EraseData,
+                Operation::WriteData(_)
+            ]
+        ));
+    }
+
+    #[test]
+    fn supplied_order() {
+        let (mut options, matches) = parse(&["--boot", "-e", "--info"]);
+        options.compare_program = Some(String::from("a.bin"));
+        options.write_program = Some(String::from("a.bin"));
+        options.config = Some(String::from("4e"));
+        assert!(matches!(
+            operations(&options, &matches)[..],
+            [
+                Operation::Erase,
+                Operation::Info,
+                Operation::WriteProgram(_),
+                Operation::CompareProgram(_),
+                Operation::WriteConfig(_),
+                Operation::Boot
+            ]
+        ));
+    }
+
+    #[test]
+    fn command_line_order() {
+        let (options, matches) = parse(&["-c", "a.bin", "-w", "b.bin", "--boot", "-R", "c.bin"]);
+        assert!(matches!(
+            operations(&options, &matches)[..],
+            [
+                Operation::CompareProgram(_),
+                Operation::Erase,
+                Operation::WriteProgram(_),
+                Operation::Boot,
+                Operation::ReadData(_)
+            ]
+        ));
+    }
+}
@@ -0,0 +1,695 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use ch559flasher::ch559::chip;
+use ch559flasher::image::Format;
+use ch559flasher::production::Project;
+use ch559flasher::transport::serial::parse_line_steps;
+use ch559flasher::transport::Selector;
+use ch559flasher::Fill;
+use clap::{ArgGroup, Parser, ValueEnum};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum FileFormat {
+    Bin,
+    Hex,
+    Srec,
+    Elf,
+    Uf2,
+    C,
+    Rust,
+}
+
+impl From<FileFormat> for Format {
+    fn from(format: FileFormat) -> Self {
+        match format {
+            FileFormat::Bin => Format::Binary,
+            FileFormat::Hex => Format::IntelHex,
+            FileFormat::Srec => Format::SRecord,
+            FileFormat::Elf => Format::Elf,
+            FileFormat::Uf2 => Format::Uf2,
+            FileFormat::C => Format::CArray,
+            FileFormat::Rust => Format::RustArray,
+        }
+    }
+}
+
+#[derive(Clone, Parser)]
+#[command(author, version, about, long_about = None)]
+#[command(group(ArgGroup::new("program_writes").args(["write_program", "script"]).multiple(true)))]
+pub struct Options {
+    #[arg(short, long, help = "Erase program area")]
+    pub erase: bool,
+    #[arg(long, help = "Erase both program area and data area")]
+    pub erase_all: bool,
+    #[arg(
+        long,
+        value_name = "START:COUNT",
+        value_parser = parse_sectors,
+        help = "Erase 1KB sectors of program area (START is 0 on v2)"
+    )]
+    pub erase_sectors: Option<(u32, u32)>,
+    #[arg(long, help = "Check if program area is blank")]
+    pub blank_check: bool,
+    #[arg(short = 'w', long, help = "Write a specified file to program area")]
+    pub write_program: Option<String>,
+    #[arg(
+        long,
+        requires = "program_writes",
+        conflicts_with_all = ["erase", "erase_all", "erase_sectors"],
+        help = "Skip the erase before writing program area"
+    )]
+    pub no_erase: bool,
+    #[arg(long, help = "Erase and write only areas that differ from files")]
+    pub if_changed: bool,
+    #[arg(
+        long,
+        alias = "sha256",
+        value_name = "HEX",
+        help = "Check SHA-256 of input files before any erase"
+    )]
+    pub expect_sha256: Vec<String>,
+    #[arg(
+        long,
+        value_name = "CHIP",
+        value_parser = parse_chip,
+        help = "Abort unless the chip is CHIP or has the ID (i.e. ch559)"
+    )]
+    pub expect_chip: Option<String>,
+    #[arg(
+        long,
+        value_name = "VERSION",
+        value_parser = parse_version,
+        help = "Abort if the bootloader is older (i.e. 2.31)"
+    )]
+    pub min_bootloader: Option<(u32, u32)>,
+    #[arg(long, help = "Proceed even on untested bootloader versions")]
+    pub allow_untested_bootloader: bool,
+    #[arg(short = 'c', long, help = "Compare program area with a specified file")]
+    pub compare_program: Option<String>,
+    #[arg(long, help = "Compare whole images and summarize mismatches")]
+    pub keep_going: bool,
+    #[arg(
+        long,
+        value_name = "ADDR:LEN",
+        value_parser = parse_range,
+        help = "Exclude an address range in hex from compares"
+    )]
+    pub ignore: Vec<(usize, usize)>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Exclude ADDR:LEN ranges listed in a file"
+    )]
+    pub ignore_file: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write differing bytes of compares to a CSV file"
+    )]
+    pub diff: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Verify program area and report all mismatches"
+    )]
+    pub verify: Option<String>,
+
+    #[arg(short = 'E', long, help = "Erase data area")]
+    pub erase_data: bool,
+    #[arg(long, help = "Check if data area is blank")]
+    pub blank_check_data: bool,
+    #[arg(long, help = "Print SHA-256 and CRC-32 of data area")]
+    pub checksum_data: bool,
+    #[arg(long, help = "Print data area in hex and ASCII")]
+    pub dump_data: bool,
+    #[arg(long, help = "Edit data area in a hex editor on the terminal")]
+    pub edit_data: bool,
+    #[arg(
+        long,
+        value_name = "OFFSET:LEN",
+        value_parser = parse_range,
+        help = "Print bytes of data area in a range in hex"
+    )]
+    pub data_get: Vec<(usize, usize)>,
+    #[arg(
+        long,
+        value_name = "OFFSET=HEX",
+        value_parser = parse_bytes_at,
+        help = "Write bytes in hex to data area, keeping the rest"
+    )]
+    pub data_set: Vec<(usize, Vec<u8>)>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Name data area fields in a layout TOML"
+    )]
+    pub layout: Option<String>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        requires = "layout",
+        help = "Print a named field of data area"
+    )]
+    pub data_read_field: Vec<String>,
+    #[arg(
+        long,
+        value_name = "NAME=VALUE",
+        requires = "layout",
+        value_parser = parse_assignment,
+        help = "Write a named field of data area"
+    )]
+    pub data_write_field: Vec<(String, String)>,
+    #[arg(short = 'R', long, help = "Read data area to a specified file")]
+    pub read_data: Option<String>,
+    #[arg(
+        long,
+        value_enum,
+        hide_possible_values = true,
+        help = "Output format for read data (bin, hex, srec, c, rust)"
+    )]
+    pub read_format: Option<FileFormat>,
+    #[arg(
+        long,
+        value_name = "OFFSET:LEN",
+        value_parser = parse_range,
+        requires = "read_data",
+        help = "Read only a range of data area, in hex"
+    )]
+    pub read_data_range: Option<(usize, usize)>,
+    #[arg(short = 'W', long, help = "Write a specified file to data area")]
+    pub write_data: Option<String>,
+    #[arg(
+        long,
+        value_name = "OFFSET",
+        value_parser = parse_offset,
+        requires = "write_data",
+        help = "Write data at an offset in hex, keeping the rest"
+    )]
+    pub write_data_at: Option<usize>,
+    #[arg(
+        long,
+        requires = "write_data",
+        conflicts_with = "write_data_at",
+        help = "Write only data chunks that differ from the current"
+    )]
+    pub patch_data: bool,
+    #[arg(short = 'C', long, help = "Compare data area with a specified file")]
+    pub compare_data: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Verify data area and report all mismatches"
+    )]
+    pub verify_data: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        hide_possible_values = true,
+        help = "Input file format (bin, hex, srec, elf, uf2)"
+    )]
+    pub format: Option<FileFormat>,
+    #[arg(short, long, help = "Fullfill unused area with randomized values")]
+    pub fullfill: bool,
+    #[arg(
+        long,
+        requires = "fullfill",
+        requires = "allow_overwrite_dataflash",
+        help = "Fullfill over data area if code runs over it"
+    )]
+    pub fullfill_data: bool,
+    #[arg(long, help = "Pad data files that are smaller than data area")]
+    pub force: bool,
+    #[arg(long, help = "Allow code to run over data area")]
+    pub allow_overwrite_dataflash: bool,
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_offset,
+        help = "Pad program images with 0xff to a size in hex"
+    )]
+    pub pad_to: Option<usize>,
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_offset,
+        help = "Drop program image bytes from a size in hex"
+    )]
+    pub truncate_at: Option<usize>,
+    #[arg(short, long, help = "Random seed")]
+    pub seed: Option<u64>,
+    #[arg(
+        long,
+        value_name = "BYTE",
+        value_parser = parse_byte,
+        requires = "fullfill",
+        conflicts_with = "seed",
+        help = "Fullfill with a byte in hex instead of random values"
+    )]
+    pub fill_byte: Option<u8>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "fullfill",
+        conflicts_with_all = ["seed", "fill_byte"],
+        help = "Fullfill with a pattern repeated from a file"
+    )]
+    pub fill_pattern: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write a data area template with placeholders"
+    )]
+    pub data_template: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Look up images to write by chip UID in a CSV file"
+    )]
+    pub fleet: Option<String>,
+    #[arg(long, help = "Write a serial number string to data area")]
+    pub serial: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "serial",
+        help = "Write a serial number counted up in a file"
+    )]
+    pub serial_counter: Option<String>,
+    #[arg(
+        long,
+        value_name = "OFFSET",
+        value_parser = parse_offset,
+        default_value = "0",
+        help = "Data area offset for the serial number in hex"
+    )]
+    pub serial_offset: usize,
+
+    #[arg(long, help = "Show chip, bootloader, config, and sizes")]
+    pub info: bool,
+    #[arg(long, help = "Run commands typed on stdin, e.g. read 3c0 10")]
+    pub shell: bool,
+    #[arg(long, value_name = "FILE", help = "Run operations listed in a file")]
+    pub script: Option<String>,
+    #[arg(long, help = "Read BOOT_CFG[15:8]")]
+    pub read_config: bool,
+    #[arg(
+        short = 'g',
+        long,
+        help = "Write BOOT_CFG[15:8] (i.e. 4e) or [15:0] (4eff) in hex"
+    )]
+    pub config: Option<String>,
+    #[arg(
+        long,
+        requires = "config",
+        help = "Write --config as the whole BOOT_CFG[15:0]"
+    )]
+    pub config_word: bool,
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Set a BOOT_CFG bit by name (i.e. En_P5.7_RESET)"
+    )]
+    pub config_set: Vec<String>,
+    #[arg(long, value_name = "NAME", help = "Clear a BOOT_CFG bit by name")]
+    pub config_clear: Vec<String>,
+    #[arg(long, help = "Protect flash from being read by programmers")]
+    pub protect: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["protect", "if_changed"],
+        help = "Erase all flash, then remove the code protection"
+    )]
+    pub unprotect: bool,
+    #[arg(long, help = "Allow config that may disable the bootloader entry")]
+    pub i_know_what_i_am_doing: bool,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write all images and config in a .ch559pkg bundle"
+    )]
+    pub bundle: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Take files, config, and fill from a project TOML"
+    )]
+    pub project: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Save data area, config, and chip info to a tar file"
+    )]
+    pub snapshot: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write back data area and config from a snapshot"
+    )]
+    pub restore: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Check device against a golden set in a project TOML"
+    )]
+    pub verify_golden: Option<String>,
+
+    #[arg(short, long, help = "Boot application")]
+    pub boot: bool,
+    #[arg(
+        long,
+        value_name = "VID:PID",
+        value_parser = parse_ids,
+        help = "Wait for the application to appear on USB after boot"
+    )]
+    pub boot_app: Option<(u16, u16)>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "5",
+        help = "Time to wait for the application"
+    )]
+    pub boot_timeout: u64,
+    #[arg(
+        long,
+        value_name = "PORT[,BAUD]",
+        value_parser = parse_monitor,
+        help = "Show serial output after boot until Ctrl-C"
+    )]
+    pub monitor: Option<(String, u32)>,
+
+    #[arg(long, help = "Use serial ISP on a specified port, or auto")]
+    pub port: Option<String>,
+    #[arg(
+        long,
+        conflicts_with_all = ["port", "all", "daemon", "repeat", "wait"],
+        help = "Use the WCH vendor driver on Windows"
+    )]
+    pub wch: bool,
+    #[arg(
+        long,
+        value_name = "STEPS",
+        requires = "port",
+        value_parser = parse_serial_reset,
+        help = "Drive DTR/RTS before serial ISP (i.e. D0R1,100,R0)"
+    )]
+    pub serial_reset: Option<String>,
+    #[arg(
+        long,
+        value_name = "BUS:ADDR",
+        value_parser = parse_address,
+        help = "Select a USB device by bus and address"
+    )]
+    pub device_address: Option<(u8, u8)>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Select the Nth USB device found, from 0"
+    )]
+    pub device: Option<usize>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Select a USB device by port path (i.e. 1-2.4)"
+    )]
+    pub port_path: Option<String>,
+    #[arg(long, value_parser = parse_id, help = "USB vendor ID in hex (default: 4348)")]
+    pub vid: Option<u16>,
+    #[arg(long, value_parser = parse_id, help = "USB product ID in hex (default: 55e0)")]
+    pub pid: Option<u16>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        conflicts_with = "port",
+        help = "Wait for a device to appear, forever if no timeout"
+    )]
+    pub wait: Option<Option<u64>>,
+    #[arg(
+        long,
+        value_name = "VID:PID[:REQ]",
+        value_parser = parse_enter_isp,
+        conflicts_with_all = ["port", "all", "daemon", "repeat"],
+        help = "Ask an application to enter the bootloader first"
+    )]
+    pub enter_isp: Option<(u16, u16, u8)>,
+    #[arg(
+        long,
+        requires = "port_path",
+        conflicts_with_all = ["port", "all", "daemon", "repeat"],
+        help = "Power cycle the --port-path hub port first"
+    )]
+    pub power_cycle: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["port", "all", "daemon", "repeat", "power_cycle"],
+        help = "Reset the bootloader device on USB before using it"
+    )]
+    pub usb_reset: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["port", "wait", "all"],
+        help = "Run operations on each device that is connected"
+    )]
+    pub daemon: bool,
+    #[arg(
+        long = "loop",
+        conflicts_with_all = ["port", "wait", "all", "daemon"],
+        help = "Repeat operations on each device until interrupted"
+    )]
+    pub repeat: bool,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Append a CSV record per device to a file"
+    )]
+    pub log: Option<String>,
+    #[arg(
+        long,
+        value_name = "CMD",
+        help = "Run a command after a device passes, with CH559_* env"
+    )]
+    pub on_success: Option<String>,
+    #[arg(
+        long,
+        value_name = "CMD",
+        help = "Run a command after a device fails, with CH559_* env"
+    )]
+    pub on_failure: Option<String>,
+    #[arg(long, help = "List bootloader devices and exit")]
+    pub list: bool,
+    #[arg(long, help = "Diagnose USB access problems and exit")]
+    pub doctor: bool,
+    #[arg(long, help = "Print a udev rule for the bootloader and exit")]
+    pub setup_udev: bool,
+    #[arg(long, help = "Install the udev rule after confirmation and exit")]
+    pub install_udev: bool,
+    #[arg(
+        long,
+        value_name = "GROUP",
+        help = "Also grant the udev rule to a group (i.e. plugdev)"
+    )]
+    pub udev_group: Option<String>,
+    #[arg(long, help = "Print raw detect and identify responses to stderr")]
+    pub dump_detect: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["port", "device_address", "device", "port_path"],
+        help = "Run operations on every bootloader device"
+    )]
+    pub all: bool,
+    #[arg(long, requires = "all", help = "Flash all devices at once")]
+    pub parallel: bool,
+}
+
+fn parse_sectors(value: &str) -> Result<(u32, u32), String> {
+    let error = || format!("expected START:COUNT, got {}", value);
+    let (start, count) = value.split_once(':').ok_or_else(error)?;
+    let start = start.parse().map_err(|_| error())?;
+    let count = count.parse().map_err(|_| error())?;
+    Ok((start, count))
+}
+
+fn parse_range(value: &str) -> Result<(usize, usize), String> {
+    let error = || format!("expected OFFSET:LEN, got {}", value);
+    let (offset, length) = value.split_once(':').ok_or_else(error)?;
+    Ok((parse_offset(offset)?, parse_offset(length)?))
+}
+
+pub fn parse_offset(value: &str) -> Result<usize, String> {
+    let digits = value.trim_start_matches("0x");
+    usize::from_str_radix(digits, 16).map_err(|error| error.to_string())
+}
+
+pub fn parse_byte(value: &str) -> Result<u8, String> {
+    let digits = value.trim_start_matches("0x");
+    u8::from_str_radix(digits, 16).map_err(|error| error.to_string())
+}
+
+pub fn parse_bytes_at(value: &str) -> Result<(usize, Vec<u8>), String> {
+    let error = || format!("expected OFFSET=HEX, got {}", value);
+    let (offset, bytes) = value.split_once('=').ok_or_else(error)?;
+    let digits = bytes.trim_start_matches("0x");
+    if digits.is_empty() || digits.len() % 2 != 0 || !digits.is_ascii() {
+        return Err(error());
+    }
+    let bytes = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|error| error.to_string())?;
+    Ok((parse_offset(offset)?, bytes))
+}
+
+fn parse_assignment(value: &str) -> Result<(String, String), String> {
+    let (name, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=VALUE, got {}", value))?;
+    Ok((name.to_string(), value.to_string()))
+}
+
+fn parse_id(value: &str) -> Result<u16, String> {
+    let digits = value.trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|error| error.to_string())
+}
+
+fn parse_ids(value: &str) -> Result<(u16, u16), String> {
+    let (vid, pid) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected VID:PID, got {}", value))?;
+    Ok((parse_id(vid)?, parse_id(pid)?))
+}
+
+// Parses PORT[,BAUD], e.g. "/dev/ttyACM0,9600". BAUD is 115200 by default.
+fn parse_monitor(value: &str) -> Result<(String, u32), String> {
+    match value.split_once(',') {
+        Some((port, baud)) => Ok((
+            port.to_string(),
+            baud.parse()
+                .map_err(|_| format!("invalid baud rate {}", baud))?,
+        )),
+        None => Ok((value.to_string(), 115200)),
+    }
+}
+
+// Parses VID:PID[:REQ] where REQ is a vendor request number in hex, 0 by
+// default.
+fn parse_enter_isp(value: &str) -> Result<(u16, u16, u8), String> {
+    let (ids, request) = match value.rsplit_once(':') {
+        Some((ids, request)) if ids.contains(':') => (ids, parse_byte(request)?),
+        _ => (value, 0),
+    };
+    let (vid, pid) = parse_ids(ids)?;
+    Ok((vid, pid, request))
+}
+
+fn parse_serial_reset(value: &str) -> Result<String, String> {
+    parse_line_steps(value)
+        .map(|_| value.to_string())
+        .map_err(|error| error.to_string())
+}
+
+fn parse_chip(value: &str) -> Result<String, String> {
+    if chip::CHIPS.iter().any(|chip| chip.is(value)) {
+        Ok(value.to_string())
+    } else {
+        let names: Vec<&str> = chip::CHIPS.iter().map(|chip| chip.name).collect();
+        Err(format!("expected one of {} or a chip ID", names.join(", ")))
+    }
+}
+
+// Parses a bootloader version into numbers that can be compared, e.g. 2.31 to
+// (2, 31). The minor version is read as 2 digits so that 2.4 is 2.40.
+pub fn parse_version(value: &str) -> Result<(u32, u32), String> {
+    let error = || format!("expected MAJOR.MINOR, got {}", value);
+    let (major, minor) = value
+        .trim_start_matches('v')
+        .split_once('.')
+        .ok_or_else(error)?;
+    if minor.is_empty() || minor.len() > 2 {
+        return Err(error());
+    }
+    let major = major.parse().map_err(|_| error())?;
+    let minor = format!("{:0<2}", minor).parse().map_err(|_| error())?;
+    Ok((major, minor))
+}
+
+fn parse_address(value: &str) -> Result<(u8, u8), String> {
+    let error = || format!("expected BUS:ADDR, got {}", value);
+    let (bus, address) = value.split_once(':').ok_or_else(error)?;
+    let bus = bus.parse().map_err(|_| error())?;
+    let address = address.parse().map_err(|_| error())?;
+    Ok((bus, address))
+}
+
+// Returns address ranges to exclude from compares. The file lists a ADDR:LEN
+// range in hex per line, and lines starting with '#' are skipped.
+pub fn ignore_ranges(options: &Options) -> Result<Vec<(usize, usize)>, String> {
+    let mut ranges = options.ignore.clone();
+    if let Some(filename) = options.ignore_file.as_ref() {
+        let text = std::fs::read_to_string(filename).map_err(|error| error.to_string())?;
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            ranges.push(parse_range(line)?);
+        }
+    }
+    Ok(ranges)
+}
+
+// Returns true if the data area is dumped to stdout, where nothing else should
+// be printed.
+pub fn stdout_is_data(options: &Options) -> bool {
+    options.read_data.as_deref() == Some("-")
+}
+
+// Returns true if unused area is filled with random values.
+pub fn random_fill(options: &Options) -> bool {
+    options.fullfill && options.fill_byte.is_none() && options.fill_pattern.is_none()
+}
+
+pub fn selector(options: &Options) -> Selector {
+    Selector {
+        address: options.device_address,
+        index: options.device,
+        port_path: options.port_path.clone(),
+        vid: options.vid,
+        pid: options.pid,
+    }
+}
+
+// Fills options that are not given in the command line from a project file.
+pub fn apply_project(options: &mut Options, project: Project) {
+    if options.write_program.is_none() && options.compare_program.is_none() {
+        options.write_program = project.program.clone();
+        options.compare_program = project.program;
+    }
+    if options.write_data.is_none() && options.compare_data.is_none() {
+        options.write_data = project.data.clone();
+        options.compare_data = project.data;
+    }
+    if options.config.is_none() {
+        options.config = project.config.map(|config| format!("{:02x}", config));
+    }
+    // The fill comes from the project unless the command line names one.
+    if options.fill_byte.is_none() && options.fill_pattern.is_none() {
+        match project.fill {
+            Some(Fill::Random) => options.fullfill = true,
+            Some(Fill::Byte(byte)) => {
+                options.fullfill = true;
+                options.fill_byte = Some(byte);
+            }
+            Some(Fill::Pattern(_)) => {
+                options.fullfill = true;
+                options.fill_pattern = project.fill_pattern;
+            }
+            None => (),
+        }
+    }
+    if options.seed.is_none() && random_fill(options) {
+        options.seed = project.seed;
+    }
+    options.boot |= project.boot;
+}
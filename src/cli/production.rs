@@ -0,0 +1,309 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use ch559flasher::image::Format;
+use ch559flasher::production::{crc32, expand, sha256, Bundle, Project, Record, Snapshot};
+use ch559flasher::{Ch559, Error, Image, Mismatch};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::cli::flash::{load_data, load_program};
+use crate::cli::options::Options;
+
+// Returns the serial number for the next device. A counter file holds the
+// next number in decimal, and is counted up for each device.
+pub fn next_serial(options: &Options) -> Result<String, std::io::Error> {
+    static COUNTER: Mutex<()> = Mutex::new(());
+    if let Some(serial) = options.serial.as_ref() {
+        return Ok(serial.clone());
+    }
+    let Some(filename) = options.serial_counter.as_ref() else {
+        return Ok(String::new());
+    };
+    let _lock = COUNTER.lock().unwrap();
+    let number: u64 = match std::fs::read_to_string(filename) {
+        Ok(text) => text
+            .trim()
+            .parse()
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))?,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => 1,
+        Err(error) => return Err(error),
+    };
+    std::fs::write(filename, format!("{}\n", number + 1))?;
+    Ok(number.to_string())
+}
+
+// Expands a data template for the device, and writes it to the data area.
+// The remainder of the data area is filled with 0xff.
+pub fn write_template(ch559: &mut Ch559, filename: &str, options: &Options) -> Result<(), Error> {
+    let template = std::fs::read(filename)?;
+    let mut data = expand(&template, &next_serial(options)?)?;
+    let data_size = ch559.chip().data_size;
+    if data.len() < data_size {
+        data.resize(data_size, 0xff);
+    }
+    let image = Image::from_binary(data);
+    ch559.write_image(image.clone(), true, true, false)?;
+    ch559.write_image(image, false, true, false)
+}
+
+// Erases, writes, and compares each area that a bundle has an image for, and
+// writes the config byte if specified.
+pub fn write_bundle(ch559: &mut Ch559, bundle: Bundle, options: &Options) -> Result<(), Error> {
+    if let Some(image) = bundle.program {
+        ch559.erase()?;
+        ch559.write_image(image.clone(), true, false, options.fullfill)?;
+        ch559.write_image(image, false, false, options.fullfill)?;
+    }
+    if let Some(image) = bundle.data {
+        ch559.erase_data()?;
+        ch559.write_image(image.clone(), true, true, false)?;
+        ch559.write_image(image, false, true, false)?;
+    }
+    if let Some(config) = bundle.config {
+        ch559.write_config(config)?;
+    }
+    Ok(())
+}
+
+// Checks the device against a golden set of images and config described in a
+// project file, e.g. for incoming QA of returned units. Each region is
+// reported before the overall result.
+pub fn verify_golden(
+    ch559: &mut Ch559,
+    filename: &str,
+    options: &Options,
+    format: Option<Format>,
+    log: &dyn Fn(&str),
+) -> Result<(), exitcode::ExitCode> {
+    let project = Project::load(filename).map_err(|error| {
+        log(&format!("verify_golden: {}", error));
+        match error {
+            Error::Io(_) => exitcode::IOERR,
+            _ => exitcode::DATAERR,
+        }
+    })?;
+    // A random fill can be reproduced only with the seed in the project. The
+    // fill and seed are restored for following operations.
+    let (fill, seed) = (ch559.fill().clone(), ch559.seed());
+    let fullfill = project.fill.is_some();
+    if let Some(fill) = project.fill {
+        ch559.set_fill(fill);
+    }
+    if let Some(seed) = project.seed {
+        ch559.set_seed(seed);
+    }
+    let report = |region: &str, result: Result<Mismatch, Error>| match result {
+        Ok(mismatch) if mismatch.is_empty() => {
+            log(&format!("verify_golden: {} pass", region));
+            true
+        }
+        Ok(mismatch) => {
+            log(&format!(
+                "verify_golden: {} fail ({} bytes differ in {} chunks)",
+                region,
+                mismatch.bytes,
+                mismatch.chunks.len()
+            ));
+            false
+        }
+        Err(error) => {
+            log(&format!("verify_golden: {} fail ({})", region, error));
+            false
+        }
+    };
+    let mut passed = true;
+    if let Some(filename) = project.program.as_ref() {
+        let result = load_program(ch559, filename, options, format)
+            .and_then(|image| ch559.verify_image(image, false, fullfill));
+        passed &= report("program", result);
+    }
+    if let Some(filename) = project.data.as_ref() {
+        let result = load_data(ch559, filename, options, format, log)
+            .and_then(|image| ch559.verify_image(image, true, false));
+        passed &= report("data", result);
+    }
+    if let Some(config) = project.config {
+        match ch559.rom_config() {
+            Some(actual) if actual == config => log("verify_golden: config pass"),
+            actual => {
+                log(&format!(
+                    "verify_golden: config fail ({:02x} expected, {} found)",
+                    config,
+                    actual.map_or(String::from("none"), |actual| format!("{:02x}", actual))
+                ));
+                passed = false;
+            }
+        }
+    }
+    ch559.set_fill(fill);
+    ch559.set_seed(seed);
+    if passed {
+        log("verify_golden: pass");
+        Ok(())
+    } else {
+        log("verify_golden: fail");
+        Err(exitcode::DATAERR)
+    }
+}
+
+pub fn take_snapshot(ch559: &mut Ch559) -> Result<Snapshot, Error> {
+    let data = if ch559.chip().data_size > 0 {
+        let image = ch559.read_data_image()?;
+        Some(
+            (0..image.len())
+                .map(|addr| image.get(addr).unwrap_or(0xff))
+                .collect(),
+        )
+    } else {
+        None
+    };
+    Ok(Snapshot {
+        chip: ch559.chip().name.to_string(),
+        version: ch559.version().to_string(),
+        uid: ch559.uid().to_vec(),
+        config: ch559.rom_config(),
+        data,
+    })
+}
+
+// Writes a snapshot back to a device of the same chip. Config is written only
+// if it differs from the current one.
+pub fn restore(
+    ch559: &mut Ch559,
+    filename: &str,
+    options: &Options,
+    log: &dyn Fn(&str),
+) -> Result<(), Error> {
+    let snapshot = Snapshot::load(filename)?;
+    if snapshot.chip != ch559.chip().name {
+        return Err(Error::Snapshot(format!("taken from {}", snapshot.chip)));
+    }
+    if snapshot.uid != ch559.uid() {
+        log("warning: snapshot is taken from another device");
+    }
+    let bundle = Bundle {
+        program: None,
+        data: snapshot.data.map(Image::from_binary),
+        config: snapshot
+            .config
+            .filter(|config| Some(*config) != ch559.rom_config()),
+    };
+    write_bundle(ch559, bundle, options)
+}
+
+// Runs --on-success or --on-failure through the shell. The result is passed in
+// environment variables so that line control software can pick it up.
+pub fn run_hook(
+    options: &Options,
+    record: &Record,
+    uid: &str,
+    elapsed: Duration,
+    log: &dyn Fn(&str),
+) {
+    let (name, command) = if record.passed {
+        ("on_success", options.on_success.as_ref())
+    } else {
+        ("on_failure", options.on_failure.as_ref())
+    };
+    let Some(command) = command else {
+        return;
+    };
+    let mut shell = if cfg!(windows) {
+        let mut shell = std::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = std::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let result = shell
+        .arg(command)
+        .env("CH559_RESULT", if record.passed { "pass" } else { "fail" })
+        .env("CH559_DEVICE", &record.device)
+        .env("CH559_CHIP", &record.chip)
+        .env(
+            "CH559_CHIP_ID",
+            record
+                .chip_id
+                .map(|id| format!("{:02x}", id))
+                .unwrap_or_default(),
+        )
+        .env("CH559_VERSION", &record.version)
+        .env("CH559_UID", uid)
+        .env(
+            "CH559_SHA256",
+            record.written_sha256.as_deref().unwrap_or_default(),
+        )
+        .env("CH559_DURATION_MS", elapsed.as_millis().to_string())
+        .status();
+    match result {
+        Ok(status) if status.success() => (),
+        Ok(status) => log(&format!("{}: {}", name, status)),
+        Err(error) => log(&format!("{}: {}", name, error)),
+    }
+}
+
+// Shows digests of bytes left in flash by a write so that a record can tie the
+// device to a verifiable image.
+pub fn log_digest(written: &[u8], log: &dyn Fn(&str)) {
+    log(&format!(
+        "digest: sha256 {}, crc32 {:08x}",
+        sha256(written),
+        crc32(written)
+    ));
+}
+
+// Returns SHA-256 over bytes written to the program then data area.
+pub fn written_sha256(ch559: &Ch559) -> Option<String> {
+    let bytes = [ch559.written(false), ch559.written(true)].concat();
+    if bytes.is_empty() {
+        None
+    } else {
+        Some(sha256(&bytes))
+    }
+}
+
+// Returns CRC-32 over images written to the program then data area.
+pub fn image_crc32(ch559: &Ch559) -> Option<u32> {
+    let bytes = [ch559.image(false), ch559.image(true)].concat();
+    if bytes.is_empty() {
+        None
+    } else {
+        Some(crc32(&bytes))
+    }
+}
+
+#[cfg(all(test, feature = "simulator"))]
+mod tests {
+    use super::*;
+    use ch559flasher::ch559::chip;
+    use ch559flasher::ch559::Output;
+    use ch559flasher::transport::simulator::Simulator;
+    use ch559flasher::Fill;
+    use clap::Parser;
+
+    #[test]
+    fn verify_golden_keeps_fill() {
+        let dir = std::env::temp_dir().join(format!("ch559flasher-golden-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("fw.bin"), [1, 2, 3]).unwrap();
+        let golden = dir.join("golden.toml");
+        std::fs::write(
+            &golden,
+            "program = \"fw.bin\"\nfill = \"5a\"\nseed = \"7\"\n",
+        )
+        .unwrap();
+        let mut ch559 = Ch559::with_transport(Box::new(Simulator::new(&chip::CH559))).unwrap();
+        ch559.set_progress(Output::Stderr);
+        ch559.set_seed(3);
+        let options = Options::parse_from(["ch559flasher"]);
+        let log = |_: &str| {};
+        // The device is blank, and does not match.
+        assert!(verify_golden(&mut ch559, golden.to_str().unwrap(), &options, None, &log).is_err());
+        assert_eq!(ch559.fill(), &Fill::Random);
+        assert_eq!(ch559.seed(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
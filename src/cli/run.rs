@@ -0,0 +1,623 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use ch559flasher::ch559::chip::{self, Family};
+use ch559flasher::editor;
+use ch559flasher::image::Format;
+use ch559flasher::production::{crc32, sha256, Bundle, Layout};
+use ch559flasher::{Ch559, Error, Image};
+use std::io::{stdin, stdout, Write};
+use std::time::Duration;
+
+use crate::cli::flash::{load_data, load_data_at, load_program, verify, wait_app};
+use crate::cli::hexdump::hexdump;
+use crate::cli::monitor::monitor;
+use crate::cli::operation::Operation;
+use crate::cli::options::Options;
+use crate::cli::production::{
+    log_digest, next_serial, restore, take_snapshot, verify_golden, write_bundle, write_template,
+};
+use crate::cli::script::load_script;
+use crate::cli::shell::shell;
+
+// Shows what each bit of BOOT_CFG[15:8] means.
+pub fn log_config(ch559: &Ch559, config: u8, log: &dyn Fn(&str)) {
+    if ch559.chip().family == Family::Ch55x {
+        for line in chip::describe_config(config) {
+            log(&format!("  {}", line));
+        }
+    }
+}
+
+// Logs a readable report of the device without touching the flash.
+pub fn log_info(ch559: &mut Ch559, log: &dyn Fn(&str)) {
+    let chip = ch559.chip();
+    log(&format!(
+        "info: chip {} (type: {:02x}, id: {:02x})",
+        chip.name, chip.device_type, chip.id
+    ));
+    log(&format!(
+        "info: bootloader v{} ({:?} protocol)",
+        ch559.version(),
+        ch559.protocol()
+    ));
+    log(&format!(
+        "info: program area {:04x}-{:04x} ({} bytes)",
+        chip.code_base,
+        chip.code_base + chip.code_size - 1,
+        chip.code_size
+    ));
+    if chip.data_size == 0 {
+        log("info: data area none");
+    } else {
+        log(&format!(
+            "info: data area {:04x}-{:04x} ({} bytes)",
+            chip.data_addr,
+            chip.data_addr + chip.data_size - 1,
+            chip.data_size
+        ));
+    }
+    match ch559.read_config_word() {
+        Ok(word) => {
+            log(&format!("info: BOOT_CFG[15:0] {:04x}", word));
+            log_config(ch559, word.to_be_bytes()[0], log);
+        }
+        Err(error) => log(&format!("info: BOOT_CFG {}", error)),
+    }
+    if ch559.uid().is_empty() {
+        log("info: UID not reported");
+    } else {
+        log(&format!("info: UID {}", ch559.uid_string()));
+    }
+}
+
+// Logs a config write error. A refused config is explained in detail.
+fn config_error(ch559: &Ch559, error: Error, log: &dyn Fn(&str)) -> exitcode::ExitCode {
+    log(&format!("write_config: {}", error));
+    let Error::RiskyConfig(config) = error else {
+        return exitcode::IOERR;
+    };
+    for risk in chip::config_risks(config) {
+        log(&format!("  {}", risk));
+    }
+    log_config(ch559, config, log);
+    log("  use --i-know-what-i-am-doing to write it anyway");
+    exitcode::USAGE
+}
+
+// Runs an operation and returns an exit code on failure.
+pub fn run(
+    ch559: &mut Ch559,
+    operation: &Operation,
+    options: &Options,
+    log: &dyn Fn(&str),
+) -> Result<(), exitcode::ExitCode> {
+    let format: Option<Format> = options.format.map(|format| format.into());
+    match operation {
+        Operation::Erase => match ch559.erase() {
+            Ok(()) => log("erase: complete"),
+            Err(error) => {
+                log(&format!("erase: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        },
+        Operation::EraseAll => match ch559.erase_all() {
+            Ok(()) => log("erase_all: complete"),
+            Err(error) => {
+                log(&format!("erase_all: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        },
+        Operation::EraseSectors(start, count) => match ch559.erase_sectors(*start, *count) {
+            Ok(()) => log("erase_sectors: complete"),
+            Err(error) => {
+                log(&format!("erase_sectors: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        },
+        Operation::BlankCheck => match ch559.blank_check(false) {
+            Ok(()) => log("blank_check: complete"),
+            Err(error) => {
+                log(&format!("blank_check: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        },
+        Operation::WriteProgram(filename) => {
+            let result = load_program(ch559, filename, options, format)
+                .and_then(|image| ch559.write_image(image, true, false, options.fullfill));
+            match result {
+                Ok(()) => {
+                    log("write: complete");
+                    log_digest(ch559.written(false), log);
+                }
+                Err(error) => {
+                    log(&format!("write: {}", error));
+                    return Err(exitcode::IOERR);
+                }
+            }
+        }
+        Operation::CompareProgram(filename) if options.keep_going || options.diff.is_some() => {
+            let result = load_program(ch559, filename, options, format)
+                .and_then(|image| ch559.verify_image(image, false, options.fullfill));
+            verify("compare", result, options, log)?;
+        }
+        Operation::CompareProgram(filename) => {
+            let result = load_program(ch559, filename, options, format)
+                .and_then(|image| ch559.write_image(image, false, false, options.fullfill));
+            match result {
+                Ok(()) => log("compare: complete"),
+                Err(error) => {
+                    log(&format!("compare: {}", error));
+                    return Err(exitcode::IOERR);
+                }
+            }
+        }
+        Operation::Verify(filename) => {
+            let result = load_program(ch559, filename, options, format)
+                .and_then(|image| ch559.verify_image(image, false, options.fullfill));
+            verify("verify", result, options, log)?;
+        }
+        Operation::EraseData => match ch559.erase_data() {
+            Ok(()) => log("erase_data: complete"),
+            Err(error) => {
+                log(&format!("erase_data: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        },
+        Operation::BlankCheckData => match ch559.blank_check(true) {
+            Ok(()) => log("blank_check_data: complete"),
+            Err(error) => {
+                log(&format!("blank_check_data: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        },
+        Operation::ChecksumData => match ch559.read_data_image() {
+            Ok(image) => {
+                let bytes: Vec<u8> = (0..image.len())
+                    .map(|addr| image.get(addr).unwrap_or(0xff))
+                    .collect();
+                log(&format!(
+                    "checksum_data: sha256 {}, crc32 {:08x}",
+                    sha256(&bytes),
+                    crc32(&bytes)
+                ));
+            }
+            Err(error) => {
+                log(&format!("checksum_data: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        },
+        Operation::DumpData => match ch559.read_data_image() {
+            Ok(image) => {
+                let bytes: Vec<u8> = (0..image.len())
+                    .map(|addr| image.get(addr).unwrap_or(0xff))
+                    .collect();
+                for line in hexdump(&bytes, ch559.chip().data_addr) {
+                    log(&line);
+                }
+            }
+            Err(error) => {
+                log(&format!("dump_data: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        },
+        Operation::EditData => {
+            // Only the chunks that are modified in the editor are written.
+            let result = ch559.read_data_image().and_then(|image| {
+                let bytes: Vec<u8> = (0..image.len())
+                    .map(|addr| image.get(addr).unwrap_or(0xff))
+                    .collect();
+                match editor::edit(&bytes, ch559.chip().data_addr)? {
+                    Some(edited) => ch559
+                        .update_data_image(Image::from_binary(edited))
+                        .map(Some),
+                    None => Ok(None),
+                }
+            });
+            match result {
+                Ok(Some(written)) => {
+                    log(&format!("edit_data: complete ({} chunks written)", written))
+                }
+                Ok(None) => log("edit_data: no changes are written"),
+                Err(error) => {
+                    log(&format!("edit_data: {}", error));
+                    return Err(exitcode::IOERR);
+                }
+            }
+        }
+        Operation::DataGet => {
+            for (offset, length) in options.data_get.iter() {
+                match ch559.read_data_range(*offset, *length) {
+                    Ok(image) => {
+                        let bytes: String = (0..image.len())
+                            .map(|addr| format!("{:02x}", image.get(addr).unwrap_or(0xff)))
+                            .collect();
+                        log(&format!("data_get: {:x}:{:x} {}", offset, length, bytes));
+                    }
+                    Err(error) => {
+                        log(&format!("data_get: {}", error));
+                        return Err(exitcode::IOERR);
+                    }
+                }
+            }
+        }
+        Operation::DataSet => {
+            // All bytes are written in one read-modify-write of the data area.
+            let data_size = ch559.chip().data_size;
+            let result = ch559.read_data_image().and_then(|mut image| {
+                for (offset, bytes) in options.data_set.iter() {
+                    if offset + bytes.len() > data_size {
+                        return Err(Error::DataRange);
+                    }
+                    image.set(*offset, bytes);
+                }
+                ch559.update_data_image(image)
+            });
+            match result {
+                Ok(written) => log(&format!("data_set: complete ({} chunks written)", written)),
+                Err(error) => {
+                    log(&format!("data_set: {}", error));
+                    return Err(exitcode::IOERR);
+                }
+            }
+        }
+        Operation::DataReadField => {
+            let layout = load_layout(options, "data_read_field", log)?;
+            for name in options.data_read_field.iter() {
+                let result = layout.field(name).and_then(|field| {
+                    let image = ch559.read_data_range(field.offset, field.length)?;
+                    let bytes: Vec<u8> = (0..image.len())
+                        .map(|addr| image.get(addr).unwrap_or(0xff))
+                        .collect();
+                    Ok(field.decode(&bytes))
+                });
+                match result {
+                    Ok(value) => log(&format!("data_read_field: {} {}", name, value)),
+                    Err(error) => {
+                        log(&format!("data_read_field: {}", error));
+                        return Err(exitcode::IOERR);
+                    }
+                }
+            }
+        }
+        Operation::DataWriteField => {
+            let layout = load_layout(options, "data_write_field", log)?;
+            // Values are checked before the data area is read.
+            let mut fields = Vec::new();
+            for (name, value) in options.data_write_field.iter() {
+                let field = layout.field(name);
+                match field.and_then(|field| Ok((field.offset, field.encode(value)?))) {
+                    Ok(field) => fields.push(field),
+                    Err(error) => {
+                        log(&format!("data_write_field: {}", error));
+                        return Err(exitcode::DATAERR);
+                    }
+                }
+            }
+            let data_size = ch559.chip().data_size;
+            let result = ch559.read_data_image().and_then(|mut image| {
+                for (offset, bytes) in fields.iter() {
+                    if offset + bytes.len() > data_size {
+                        return Err(Error::DataRange);
+                    }
+                    image.set(*offset, bytes);
+                }
+                ch559.update_data_image(image)
+            });
+            match result {
+                Ok(written) => log(&format!(
+                    "data_write_field: complete ({} chunks written)",
+                    written
+                )),
+                Err(error) => {
+                    log(&format!("data_write_field: {}", error));
+                    return Err(exitcode::IOERR);
+                }
+            }
+        }
+        Operation::ReadData(filename) => {
+            let format = match options.read_format {
+                Some(format) => format.into(),
+                None => Format::from_filename(filename),
+            };
+            let result = match options.read_data_range {
+                Some((offset, length)) => ch559.read_data_range(offset, length).and_then(|image| {
+                    image.save(filename, format, ch559.chip().data_addr + offset)
+                }),
+                None => ch559.read_data(filename, format),
+            };
+            match result {
+                Ok(()) => log("read_data: complete"),
+                Err(error) => {
+                    log(&format!("read_data: {}", error));
+                    return Err(exitcode::IOERR);
+                }
+            }
+        }
+        Operation::WriteData(filename) if options.patch_data => {
+            let result = load_data(ch559, filename, options, format, log)
+                .and_then(|image| ch559.update_data_image(image));
+            match result {
+                Ok(written) => {
+                    log(&format!(
+                        "write_data: complete ({} chunks written)",
+                        written
+                    ));
+                    log_digest(ch559.written(true), log);
+                }
+                Err(error) => {
+                    log(&format!("write_data: {}", error));
+                    return Err(exitcode::IOERR);
+                }
+            }
+        }
+        Operation::WriteData(filename) => {
+            let result = match options.write_data_at {
+                Some(offset) => load_data_at(ch559, filename, format)
+                    .and_then(|image| ch559.write_data_image_at(offset, &image)),
+                None => load_data(ch559, filename, options, format, log)
+                    .and_then(|image| ch559.write_image(image, true, true, options.fullfill)),
+            };
+            match result {
+                Ok(()) => {
+                    log("write_data: complete");
+                    log_digest(ch559.written(true), log);
+                }
+                Err(error) => {
+                    log(&format!("write_data: {}", error));
+                    return Err(exitcode::IOERR);
+                }
+            }
+        }
+        Operation::CompareData(filename) if options.keep_going || options.diff.is_some() => {
+            let result = load_data(ch559, filename, options, format, log)
+                .and_then(|image| ch559.verify_image(image, true, options.fullfill));
+            verify("compare_data", result, options, log)?;
+        }
+        Operation::CompareData(filename) => {
+            let result = load_data(ch559, filename, options, format, log)
+                .and_then(|image| ch559.write_image(image, false, true, options.fullfill));
+            match result {
+                Ok(()) => log("compare_data: complete"),
+                Err(error) => {
+                    log(&format!("compare_data: {}", error));
+                    return Err(exitcode::IOERR);
+                }
+            }
+        }
+        Operation::VerifyData(filename) => {
+            let result = load_data(ch559, filename, options, format, log)
+                .and_then(|image| ch559.verify_image(image, true, options.fullfill));
+            verify("verify_data", result, options, log)?;
+        }
+        Operation::WriteTemplate(filename) => match write_template(ch559, filename, options) {
+            Ok(()) => log("write_template: complete"),
+            Err(error) => {
+                log(&format!("write_template: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        },
+        Operation::WriteSerial => {
+            let serial = match next_serial(options) {
+                Ok(serial) => serial,
+                Err(error) => {
+                    log(&format!("serial: {}", error));
+                    return Err(exitcode::IOERR);
+                }
+            };
+            match ch559.write_data_at(options.serial_offset, serial.as_bytes()) {
+                Ok(()) => log(&format!("write_serial: complete ({})", serial)),
+                Err(error) => {
+                    log(&format!("write_serial: {}", error));
+                    return Err(exitcode::IOERR);
+                }
+            }
+        }
+        Operation::Info => log_info(ch559, log),
+        Operation::Shell => shell(ch559, log)?,
+        Operation::ReadConfig => match ch559.read_config_word() {
+            Ok(word) => {
+                let config = word.to_be_bytes()[0];
+                log(&format!(
+                    "read_config: {:02x} (BOOT_CFG[15:0]: {:04x})",
+                    config, word
+                ));
+                log_config(ch559, config, log);
+            }
+            Err(error) => {
+                log(&format!("read_config: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        },
+        // Values over ff or --config-word specify the whole BOOT_CFG[15:0].
+        Operation::WriteConfig(config) => match u16::from_str_radix(config, 16) {
+            Ok(v) => {
+                let word = options.config_word || v > 0xff;
+                let result = if word {
+                    ch559.write_config_word(v)
+                } else {
+                    ch559.write_config(v as u8)
+                };
+                match result {
+                    Ok(()) if word => {
+                        log(&format!("write_config: complete ({:04x})", v));
+                        log_config(ch559, v.to_be_bytes()[0], log);
+                    }
+                    Ok(()) => {
+                        log(&format!("write_config: complete ({:02x})", v));
+                        log_config(ch559, v as u8, log);
+                    }
+                    Err(error) => return Err(config_error(ch559, error, log)),
+                }
+            }
+            Err(error) => {
+                log(&format!("config: {}", error));
+                return Err(exitcode::USAGE);
+            }
+        },
+        Operation::ModifyConfig => {
+            // Names are checked before anything is written.
+            let mut set = 0u8;
+            let mut clear = 0u8;
+            let names = options.config_set.iter().map(|name| (name, true));
+            for (name, value) in names.chain(options.config_clear.iter().map(|name| (name, false)))
+            {
+                let Some(bit) = chip::config_bit(name) else {
+                    let names: Vec<&str> = chip::CONFIG_BITS.iter().map(|bit| bit.name).collect();
+                    log(&format!(
+                        "config: unknown bit {}, use one of {}",
+                        name,
+                        names.join(", ")
+                    ));
+                    return Err(exitcode::USAGE);
+                };
+                if value {
+                    set |= 1 << bit.bit;
+                } else {
+                    clear |= 1 << bit.bit;
+                }
+            }
+            let result = ch559.read_config().and_then(|config| {
+                let modified = (config & !clear) | set;
+                ch559.write_config(modified).map(|()| (config, modified))
+            });
+            match result {
+                Ok((config, modified)) => {
+                    log(&format!(
+                        "write_config: complete ({:02x} to {:02x})",
+                        config, modified
+                    ));
+                    log_config(ch559, modified, log);
+                }
+                Err(error) => return Err(config_error(ch559, error, log)),
+            }
+        }
+        Operation::Bundle(filename) => {
+            match Bundle::load(filename).and_then(|bundle| write_bundle(ch559, bundle, options)) {
+                Ok(()) => log("bundle: complete"),
+                Err(error) => {
+                    log(&format!("bundle: {}", error));
+                    return Err(exitcode::IOERR);
+                }
+            }
+        }
+        Operation::Snapshot(filename) => {
+            let result = take_snapshot(ch559).and_then(|snapshot| snapshot.save(filename));
+            match result {
+                Ok(()) => log("snapshot: complete"),
+                Err(error) => {
+                    log(&format!("snapshot: {}", error));
+                    return Err(exitcode::IOERR);
+                }
+            }
+        }
+        Operation::VerifyGolden(filename) => verify_golden(ch559, filename, options, format, log)?,
+        Operation::Restore(filename) => match restore(ch559, filename, options, log) {
+            Ok(()) => log("restore: complete"),
+            Err(error) => {
+                log(&format!("restore: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        },
+        Operation::Protect => match ch559.protect() {
+            Ok(()) => log("protect: complete"),
+            Err(error) => {
+                log(&format!("protect: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        },
+        Operation::Unprotect => match ch559.unprotect() {
+            Ok(()) => log("unprotect: complete"),
+            Err(error) => {
+                log(&format!("unprotect: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        },
+        Operation::Script(filename) => {
+            // The whole script is checked before anything runs.
+            let operations = match load_script(filename, options) {
+                Ok(operations) => operations,
+                Err(Error::Io(error)) => {
+                    log(&format!("script: {}", error));
+                    return Err(exitcode::IOERR);
+                }
+                Err(error) => {
+                    log(&format!("script: {}", error));
+                    return Err(exitcode::DATAERR);
+                }
+            };
+            for operation in operations.iter() {
+                run(ch559, operation, options, log)?;
+            }
+            log("script: complete");
+        }
+        Operation::Sleep(milliseconds) => std::thread::sleep(Duration::from_millis(*milliseconds)),
+        Operation::Wait => {
+            print!("wait: press Enter to continue");
+            let _ = stdout().flush();
+            if let Err(error) = stdin().read_line(&mut String::new()) {
+                log(&format!("wait: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        }
+        Operation::Boot => match ch559.boot() {
+            Ok(()) => {
+                log("boot: complete");
+                if let Some((vid, pid)) = options.boot_app {
+                    wait_app(vid, pid, options.boot_timeout, log)?;
+                }
+                if let Some((port, baud)) = options.monitor.as_ref() {
+                    monitor(port, *baud, options.boot_timeout, log)?;
+                }
+            }
+            Err(error) => {
+                log(&format!("boot: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        },
+    }
+    Ok(())
+}
+
+fn load_layout(
+    options: &Options,
+    name: &str,
+    log: &dyn Fn(&str),
+) -> Result<Layout, exitcode::ExitCode> {
+    let filename = options.layout.as_deref().unwrap_or_default();
+    Layout::load(filename).map_err(|error| {
+        log(&format!("{}: {}", name, error));
+        match error {
+            Error::Io(_) => exitcode::IOERR,
+            _ => exitcode::DATAERR,
+        }
+    })
+}
+
+#[cfg(all(test, feature = "simulator"))]
+mod tests {
+    use super::*;
+    use ch559flasher::ch559::Output;
+    use ch559flasher::transport::simulator::Simulator;
+    use clap::Parser;
+
+    #[test]
+    fn config_word() {
+        let simulator = Simulator::new(&chip::CH559);
+        let mut ch559 = Ch559::with_transport(Box::new(simulator.clone())).unwrap();
+        ch559.set_progress(Output::Stderr);
+        // A word up to ff clears the Must_10 bits.
+        ch559.set_allow_risky_config(true);
+        let log = |_: &str| {};
+        let mut write = |args: &[&str]| {
+            let options = Options::parse_from(["ch559flasher"].iter().chain(args));
+            let operation = Operation::WriteConfig(options.config.clone().unwrap());
+            run(&mut ch559, &operation, &options, &log).unwrap();
+            simulator.config_word()
+        };
+        // Leading zeros do not make a word.
+        assert_eq!(write(&["-g", "004e"]), 0x4eff);
+        assert_eq!(write(&["-g", "4e00"]), 0x4e00);
+        assert_eq!(write(&["-g", "004e", "--config-word"]), 0x004e);
+    }
+}
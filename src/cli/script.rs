@@ -0,0 +1,114 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use ch559flasher::image;
+use ch559flasher::Error;
+
+use crate::cli::operation::Operation;
+use crate::cli::options::Options;
+
+// Reads a script that lists an operation per line, e.g. "write firmware.hex".
+// Empty lines and lines starting with '#' are ignored, and relative paths are
+// resolved from the script file. Writes are preceded by an implicit erase as
+// on the command line unless the area is erased earlier in the script.
+pub fn load_script(filename: &str, options: &Options) -> Result<Vec<Operation>, Error> {
+    let text = String::from_utf8(image::read(filename)?)
+        .map_err(|_| Error::Script(String::from("not a text file")))?;
+    let base = std::path::Path::new(filename)
+        .parent()
+        .unwrap_or(std::path::Path::new(""));
+    // Stdin, URLs, and absolute paths are taken as they are.
+    let path = |name: &str| {
+        if name == "-" || image::is_url(name) || std::path::Path::new(name).is_absolute() {
+            name.to_string()
+        } else {
+            base.join(name).to_string_lossy().into_owned()
+        }
+    };
+    let mut operations = Vec::new();
+    let mut program_erased = options.no_erase;
+    // A partial write erases the data area by itself.
+    let mut data_erased = options.write_data_at.is_some() || options.patch_data;
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let operation = match words.as_slice() {
+            ["erase"] => Operation::Erase,
+            ["erase-all"] => Operation::EraseAll,
+            ["erase-data"] => Operation::EraseData,
+            ["write", file] => Operation::WriteProgram(path(file)),
+            ["compare", file] => Operation::CompareProgram(path(file)),
+            ["verify", file] => Operation::Verify(path(file)),
+            ["write-data", file] => Operation::WriteData(path(file)),
+            ["compare-data", file] => Operation::CompareData(path(file)),
+            ["config", config] => Operation::WriteConfig(config.to_string()),
+            ["sleep", milliseconds] => match milliseconds.parse() {
+                Ok(milliseconds) => Operation::Sleep(milliseconds),
+                Err(_) => return Err(Error::Script(format!("line {}: {}", index + 1, line))),
+            },
+            ["wait"] => Operation::Wait,
+            ["boot"] => Operation::Boot,
+            _ => return Err(Error::Script(format!("line {}: {}", index + 1, line))),
+        };
+        match operation {
+            Operation::Erase => program_erased = true,
+            Operation::EraseAll => (program_erased, data_erased) = (true, true),
+            Operation::EraseData => data_erased = true,
+            Operation::WriteProgram(_) if !program_erased => {
+                operations.push(Operation::Erase);
+                program_erased = true;
+            }
+            Operation::WriteData(_) if !data_erased => {
+                operations.push(Operation::EraseData);
+                data_erased = true;
+            }
+            _ => (),
+        }
+        operations.push(operation);
+    }
+    Ok(operations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn script_paths() {
+        let dir = std::env::temp_dir().join(format!("ch559flasher-script-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("flash.txt");
+        std::fs::write(
+            &script,
+            "write fw.hex\ncompare -\nverify https://example.com/fw.bin\nwrite-data /tmp/d.bin\n",
+        )
+        .unwrap();
+        let options = Options::parse_from(["ch559flasher"]);
+        let operations = load_script(script.to_str().unwrap(), &options).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let files: Vec<&str> = operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::WriteProgram(file)
+                | Operation::CompareProgram(file)
+                | Operation::Verify(file)
+                | Operation::WriteData(file) => Some(file.as_str()),
+                _ => None,
+            })
+            .collect();
+        let local = dir.join("fw.hex");
+        assert_eq!(
+            files,
+            [
+                local.to_str().unwrap(),
+                "-",
+                "https://example.com/fw.bin",
+                "/tmp/d.bin"
+            ]
+        );
+    }
+}
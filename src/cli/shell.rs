@@ -0,0 +1,103 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use ch559flasher::Ch559;
+use std::io::{stdin, stdout, Write};
+
+use crate::cli::hexdump::hexdump;
+use crate::cli::options::{parse_byte, parse_bytes_at, parse_offset};
+use crate::cli::run::{log_config, log_info};
+
+const SHELL_HELP: [&str; 9] = [
+    "read OFFSET LEN    dump LEN bytes at OFFSET of data area",
+    "write OFFSET HEX   write bytes at OFFSET of data area",
+    "erase              erase program area",
+    "erase-data         erase data area",
+    "config [CONFIG]    read or write BOOT_CFG[15:8]",
+    "info               show chip, bootloader, and config",
+    "boot               boot application and quit",
+    "quit               quit",
+    "Numbers are in hex, e.g. read 3c0 10",
+];
+
+// Runs commands typed on stdin one by one on the same device, i.e. the key is
+// reset only once. A failed command is reported and the next one is read.
+pub fn shell(ch559: &mut Ch559, log: &dyn Fn(&str)) -> Result<(), exitcode::ExitCode> {
+    log("shell: type help for commands");
+    loop {
+        print!("ch559> ");
+        let _ = stdout().flush();
+        let mut line = String::new();
+        match stdin().read_line(&mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) => (),
+            Err(error) => {
+                log(&format!("shell: {}", error));
+                return Err(exitcode::IOERR);
+            }
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match shell_command(ch559, &words, log) {
+            Ok(true) => (),
+            Ok(false) => return Ok(()),
+            Err(error) => log(&format!("{}: {}", words[0], error)),
+        }
+    }
+}
+
+// Runs a shell command, and returns false if the shell should quit.
+fn shell_command(ch559: &mut Ch559, words: &[&str], log: &dyn Fn(&str)) -> Result<bool, String> {
+    match words {
+        [] => (),
+        ["help"] => SHELL_HELP.iter().for_each(|line| log(line)),
+        ["read", offset, length] => {
+            let offset = parse_offset(offset)?;
+            let image = ch559
+                .read_data_range(offset, parse_offset(length)?)
+                .map_err(|error| error.to_string())?;
+            let bytes: Vec<u8> = (0..image.len())
+                .map(|addr| image.get(addr).unwrap_or(0xff))
+                .collect();
+            for line in hexdump(&bytes, offset) {
+                log(&line);
+            }
+        }
+        ["write", offset, bytes] => {
+            let (offset, bytes) = parse_bytes_at(&format!("{}={}", offset, bytes))?;
+            ch559
+                .write_data_at(offset, &bytes)
+                .map_err(|error| error.to_string())?;
+            log("write: complete");
+        }
+        ["erase"] => {
+            ch559.erase().map_err(|error| error.to_string())?;
+            log("erase: complete");
+        }
+        ["erase-data"] => {
+            ch559.erase_data().map_err(|error| error.to_string())?;
+            log("erase-data: complete");
+        }
+        ["config"] => {
+            let config = ch559.read_config().map_err(|error| error.to_string())?;
+            log(&format!("config: {:02x}", config));
+            log_config(ch559, config, log);
+        }
+        ["config", config] => {
+            let config = parse_byte(config)?;
+            ch559
+                .write_config(config)
+                .map_err(|error| error.to_string())?;
+            log(&format!("config: complete ({:02x})", config));
+            log_config(ch559, config, log);
+        }
+        ["info"] => log_info(ch559, log),
+        ["boot"] => {
+            ch559.boot().map_err(|error| error.to_string())?;
+            log("boot: complete");
+            return Ok(false);
+        }
+        ["quit"] | ["exit"] => return Ok(false),
+        _ => return Err(String::from("unknown command, type help for commands")),
+    }
+    Ok(true)
+}
@@ -0,0 +1,70 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use ch559flasher::transport::{PID, VID};
+use std::io::{stdin, stdout, Write};
+
+use crate::cli::options::Options;
+
+const UDEV_RULES: &str = "/etc/udev/rules.d/50-ch559flasher.rules";
+
+// Returns a udev rule that lets the logged in user access the bootloader
+// device without root on Linux. A group also gets access if requested, e.g.
+// for users over SSH.
+pub fn udev_rule(options: &Options) -> String {
+    let group = options
+        .udev_group
+        .as_ref()
+        .map(|group| format!(", MODE=\"0660\", GROUP=\"{}\"", group))
+        .unwrap_or_default();
+    format!(
+        "# WCH bootloader for ch559flasher\n\
+         SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", \
+         ATTRS{{idProduct}}==\"{:04x}\", TAG+=\"uaccess\"{}\n",
+        options.vid.unwrap_or(VID),
+        options.pid.unwrap_or(PID),
+        group
+    )
+}
+
+// Writes the udev rule after the user confirms it, and reloads rules.
+pub fn install_udev(options: &Options) -> Result<(), String> {
+    let rule = udev_rule(options);
+    print!("{}Install this rule to {}? [y/N] ", rule, UDEV_RULES);
+    let _ = stdout().flush();
+    let mut answer = String::new();
+    stdin()
+        .read_line(&mut answer)
+        .map_err(|error| error.to_string())?;
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        return Err(String::from("cancelled"));
+    }
+    std::fs::write(UDEV_RULES, rule).map_err(|error| format!("{} (try with sudo)", error))?;
+    for args in [&["control", "--reload-rules"][..], &["trigger"][..]] {
+        std::process::Command::new("udevadm")
+            .args(args)
+            .status()
+            .map_err(|error| format!("udevadm: {}", error))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn rule() {
+        let rule = udev_rule(&Options::parse_from(["ch559flasher"]));
+        assert!(rule.ends_with(
+            "ATTRS{idVendor}==\"4348\", ATTRS{idProduct}==\"55e0\", TAG+=\"uaccess\"\n"
+        ));
+        let rule = udev_rule(&Options::parse_from([
+            "ch559flasher",
+            "--udev-group",
+            "plugdev",
+        ]));
+        assert!(rule.ends_with("TAG+=\"uaccess\", MODE=\"0660\", GROUP=\"plugdev\"\n"));
+    }
+}
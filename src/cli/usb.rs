@@ -0,0 +1,72 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+#[cfg(not(any(feature = "rusb", feature = "nusb")))]
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+#[cfg(not(any(feature = "rusb", feature = "nusb")))]
+use ch559flasher::transport::Location;
+use ch559flasher::transport::Selector;
+#[cfg(any(feature = "rusb", feature = "nusb"))]
+pub use ch559flasher::transport::UsbTransport;
+use ch559flasher::{Ch559, Error};
+
+// Builds without the rusb or nusb feature, e.g. only with the wch feature,
+// refuse USB operations with this error.
+#[cfg(not(any(feature = "rusb", feature = "nusb")))]
+const UNSUPPORTED: Error = Error::NotSupported("builds without the rusb or nusb feature");
+
+#[cfg(not(any(feature = "rusb", feature = "nusb")))]
+pub struct UsbTransport;
+
+#[cfg(not(any(feature = "rusb", feature = "nusb")))]
+impl UsbTransport {
+    pub fn list(_selector: &Selector) -> Result<Vec<Location>, Error> {
+        Err(UNSUPPORTED)
+    }
+
+    pub fn reset(_selector: &Selector) -> Result<(), Error> {
+        Err(UNSUPPORTED)
+    }
+
+    pub fn send_vendor_request(_selector: &Selector, _request: u8) -> Result<(), Error> {
+        Err(UNSUPPORTED)
+    }
+
+    pub fn power_cycle(_port_path: &str, _off: Duration) -> Result<(), Error> {
+        Err(UNSUPPORTED)
+    }
+
+    pub fn backend() -> String {
+        String::from("none")
+    }
+
+    pub fn kernel_driver_active(_selector: &Selector) -> Result<bool, Error> {
+        Err(UNSUPPORTED)
+    }
+
+    pub fn watch(_selector: &Selector) -> Result<Receiver<Location>, Error> {
+        Err(UNSUPPORTED)
+    }
+}
+
+#[cfg(any(feature = "rusb", feature = "nusb"))]
+pub fn open(selector: &Selector) -> Result<Ch559, Error> {
+    Ch559::open(selector)
+}
+
+#[cfg(not(any(feature = "rusb", feature = "nusb")))]
+pub fn open(_selector: &Selector) -> Result<Ch559, Error> {
+    Err(UNSUPPORTED)
+}
+
+#[cfg(any(feature = "rusb", feature = "nusb"))]
+pub fn wait(selector: &Selector, timeout: Option<Duration>) -> Result<Ch559, Error> {
+    Ch559::wait(selector, timeout)
+}
+
+#[cfg(not(any(feature = "rusb", feature = "nusb")))]
+pub fn wait(_selector: &Selector, _timeout: Option<Duration>) -> Result<Ch559, Error> {
+    Err(UNSUPPORTED)
+}
@@ -0,0 +1,198 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use crate::ch559::Error;
+
+// Lets the user edit `data` in a hex grid on the terminal. `base` is the
+// address of the first byte, and is only used for display. Returns the edited
+// bytes if the user writes them, or None if the user quits or nothing is
+// changed.
+#[cfg(feature = "tui")]
+pub fn edit(data: &[u8], base: usize) -> Result<Option<Vec<u8>>, Error> {
+    if data.is_empty() {
+        return Ok(None);
+    }
+    let _terminal = tui::Terminal::enter()?;
+    let mut editor = tui::Editor {
+        original: data.to_vec(),
+        data: data.to_vec(),
+        base,
+        cursor: 0,
+        low_nibble: false,
+        top: 0,
+    };
+    editor.run().map_err(Error::Io)
+}
+
+#[cfg(not(feature = "tui"))]
+pub fn edit(_data: &[u8], _base: usize) -> Result<Option<Vec<u8>>, Error> {
+    Err(Error::Editor(String::from("built without the tui feature")))
+}
+
+#[cfg(feature = "tui")]
+mod tui {
+    use crossterm::cursor::{Hide, MoveTo, Show};
+    use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+    use crossterm::style::{Attribute, Print, SetAttribute};
+    use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+    use crossterm::{execute, queue};
+    use std::io::{stdout, Result, Write};
+
+    const COLUMNS: usize = 16;
+
+    // Switches the terminal into raw mode on an alternate screen, and restores
+    // it on drop even if the editor fails.
+    pub struct Terminal;
+
+    impl Terminal {
+        pub fn enter() -> Result<Self> {
+            terminal::enable_raw_mode()?;
+            execute!(stdout(), EnterAlternateScreen, Hide)?;
+            Ok(Terminal)
+        }
+    }
+
+    impl Drop for Terminal {
+        fn drop(&mut self) {
+            let _ = execute!(stdout(), Show, LeaveAlternateScreen);
+            let _ = terminal::disable_raw_mode();
+        }
+    }
+
+    pub struct Editor {
+        pub original: Vec<u8>,
+        pub data: Vec<u8>,
+        pub base: usize,
+        pub cursor: usize,
+        pub low_nibble: bool,
+        pub top: usize,
+    }
+
+    impl Editor {
+        pub fn run(&mut self) -> Result<Option<Vec<u8>>> {
+            let mut out = stdout();
+            loop {
+                let (_, height) = terminal::size()?;
+                // The last line is for the status.
+                let rows = (height as usize).saturating_sub(1).max(1);
+                self.scroll(rows);
+                self.draw(&mut out, rows)?;
+                let Event::Key(key) = event::read()? else {
+                    continue;
+                };
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                let page = (rows * COLUMNS) as isize;
+                match key.code {
+                    KeyCode::Left => self.move_by(-1),
+                    KeyCode::Right => self.move_by(1),
+                    KeyCode::Up => self.move_by(-(COLUMNS as isize)),
+                    KeyCode::Down => self.move_by(COLUMNS as isize),
+                    KeyCode::PageUp => self.move_by(-page),
+                    KeyCode::PageDown => self.move_by(page),
+                    KeyCode::Home => self.move_by(-(self.cursor as isize)),
+                    KeyCode::End => self.move_by(self.data.len() as isize),
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        return Ok(None)
+                    }
+                    KeyCode::Char(c) if c.is_ascii_hexdigit() => {
+                        self.type_nibble(c.to_digit(16).unwrap() as u8)
+                    }
+                    KeyCode::Char('u') => {
+                        self.data[self.cursor] = self.original[self.cursor];
+                        self.low_nibble = false;
+                    }
+                    KeyCode::Char('w') if self.data != self.original => {
+                        return Ok(Some(self.data.clone()))
+                    }
+                    KeyCode::Char('w') | KeyCode::Char('q') | KeyCode::Esc => return Ok(None),
+                    _ => (),
+                }
+            }
+        }
+
+        fn move_by(&mut self, delta: isize) {
+            let last = self.data.len() as isize - 1;
+            self.cursor = (self.cursor as isize + delta).clamp(0, last) as usize;
+            self.low_nibble = false;
+        }
+
+        // Replaces the high nibble, then the low nibble, of the byte at the
+        // cursor, and moves to the next byte.
+        fn type_nibble(&mut self, nibble: u8) {
+            let byte = &mut self.data[self.cursor];
+            if self.low_nibble {
+                *byte = (*byte & 0xf0) | nibble;
+                self.move_by(1);
+            } else {
+                *byte = (*byte & 0x0f) | (nibble << 4);
+                self.low_nibble = true;
+            }
+        }
+
+        // Keeps the cursor row on the screen.
+        fn scroll(&mut self, rows: usize) {
+            let row = self.cursor / COLUMNS;
+            if row < self.top {
+                self.top = row;
+            } else if row >= self.top + rows {
+                self.top = row + 1 - rows;
+            }
+        }
+
+        fn draw(&self, out: &mut impl Write, rows: usize) -> Result<()> {
+            queue!(out, Clear(ClearType::All))?;
+            for row in 0..rows {
+                let start = (self.top + row) * COLUMNS;
+                if start >= self.data.len() {
+                    break;
+                }
+                let end = (start + COLUMNS).min(self.data.len());
+                queue!(
+                    out,
+                    MoveTo(0, row as u16),
+                    Print(format!("{:08x} ", self.base + start))
+                )?;
+                for addr in start..end {
+                    if addr % 8 == 0 {
+                        queue!(out, Print(" "))?;
+                    }
+                    // The cursor is reversed, and modified bytes are bold.
+                    if addr == self.cursor {
+                        queue!(out, SetAttribute(Attribute::Reverse))?;
+                    } else if self.data[addr] != self.original[addr] {
+                        queue!(out, SetAttribute(Attribute::Bold))?;
+                    }
+                    queue!(
+                        out,
+                        Print(format!("{:02x}", self.data[addr])),
+                        SetAttribute(Attribute::Reset),
+                        Print(" ")
+                    )?;
+                }
+                let ascii: String = self.data[start..end]
+                    .iter()
+                    .map(|byte| match byte {
+                        0x20..=0x7e => *byte as char,
+                        _ => '.',
+                    })
+                    .collect();
+                queue!(out, Print(format!(" |{}|", ascii)))?;
+            }
+            let modified = (0..self.data.len())
+                .filter(|addr| self.data[*addr] != self.original[*addr])
+                .count();
+            queue!(
+                out,
+                MoveTo(0, rows as u16),
+                Print(format!(
+                    "0x{:04x}  {} bytes modified  [0-f] edit [u] undo [w] write [q] quit",
+                    self.base + self.cursor,
+                    modified
+                ))
+            )?;
+            out.flush()
+        }
+    }
+}
@@ -0,0 +1,434 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{stdin, stdout, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::ch559::Error;
+
+pub mod elf;
+pub mod ihex;
+pub mod source;
+pub mod srec;
+pub mod uf2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Binary,
+    IntelHex,
+    SRecord,
+    Elf,
+    Uf2,
+    // Source code arrays, for output only.
+    CArray,
+    RustArray,
+}
+
+impl Format {
+    // Guesses the format from the file extension.
+    pub fn from_filename(filename: &str) -> Self {
+        let extension = Path::new(filename)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("hex") | Some("ihx") => Format::IntelHex,
+            Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => Format::SRecord,
+            Some("elf") => Format::Elf,
+            Some("uf2") => Format::Uf2,
+            Some("c") | Some("h") => Format::CArray,
+            Some("rs") => Format::RustArray,
+            _ => Format::Binary,
+        }
+    }
+
+    // Guesses the format from the file contents.
+    pub fn detect(bytes: &[u8]) -> Self {
+        if bytes.starts_with(&[0x7f, b'E', b'L', b'F']) {
+            return Format::Elf;
+        }
+        if bytes.starts_with(&[0x55, 0x46, 0x32, 0x0a, 0x57, 0x51, 0x5d, 0x9e]) {
+            return Format::Uf2;
+        }
+        let Some(first_line) = bytes
+            .split(|b| *b == b'\n')
+            .map(|line| line.trim_ascii())
+            .find(|line| !line.is_empty())
+        else {
+            return Format::Binary;
+        };
+        let is_record = |prefix: usize| {
+            first_line.len() > prefix && first_line[prefix..].iter().all(u8::is_ascii_hexdigit)
+        };
+        match first_line[0] {
+            b':' if is_record(1) => Format::IntelHex,
+            b'S' if first_line.len() > 1 && first_line[1].is_ascii_digit() && is_record(2) => {
+                Format::SRecord
+            }
+            _ => Format::Binary,
+        }
+    }
+}
+
+pub fn is_url(filename: &str) -> bool {
+    filename.starts_with("http://") || filename.starts_with("https://")
+}
+
+// Reads a file, stdin for "-", or an HTTP(S) URL. Stdin and URLs are read
+// once and kept so that they can be used by several operations, e.g. to write
+// and compare.
+pub fn read(filename: &str) -> Result<Vec<u8>, Error> {
+    static CACHE: Mutex<Option<HashMap<String, Vec<u8>>>> = Mutex::new(None);
+    let is_url = is_url(filename);
+    if filename == "-" || is_url {
+        let mut cache = CACHE.lock().unwrap();
+        let cache = cache.get_or_insert_with(HashMap::new);
+        if let Some(bytes) = cache.get(filename) {
+            return Ok(bytes.clone());
+        }
+        let bytes = if is_url {
+            download(filename)?
+        } else {
+            let mut bytes = Vec::new();
+            stdin().read_to_end(&mut bytes)?;
+            bytes
+        };
+        cache.insert(filename.to_string(), bytes.clone());
+        return Ok(bytes);
+    }
+    let mut file = File::open(filename)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(Error::InvalidFile);
+    }
+    let mut bytes: Vec<u8> = Vec::with_capacity(metadata.len() as usize);
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    fn detect(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if bytes.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+// Expands gzip, xz, or zstd compressed bytes that are detected by the magic
+// bytes. Other bytes are returned as is.
+pub fn decompress(bytes: Vec<u8>) -> Result<Vec<u8>, Error> {
+    match Compression::detect(&bytes) {
+        Some(compression) => expand(compression, &bytes),
+        None => Ok(bytes),
+    }
+}
+
+#[cfg(feature = "compression")]
+fn expand(compression: Compression, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut expanded = Vec::new();
+    match compression {
+        Compression::Gzip => {
+            flate2::read::MultiGzDecoder::new(bytes).read_to_end(&mut expanded)?;
+        }
+        Compression::Xz => lzma_rs::xz_decompress(&mut &bytes[..], &mut expanded)
+            .map_err(|error| Error::Decompress(format!("{:?}", error)))?,
+        Compression::Zstd => {
+            ruzstd::StreamingDecoder::new(bytes)
+                .map_err(|error| Error::Decompress(format!("{:?}", error)))?
+                .read_to_end(&mut expanded)?;
+        }
+    }
+    Ok(expanded)
+}
+
+#[cfg(not(feature = "compression"))]
+fn expand(compression: Compression, _bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(Error::Decompress(format!(
+        "{:?} needs the compression feature",
+        compression
+    )))
+}
+
+#[cfg(feature = "http")]
+fn download(url: &str) -> Result<Vec<u8>, Error> {
+    let response = ureq::get(url)
+        .call()
+        .map_err(|error| Error::Download(error.to_string()))?;
+    let mut bytes = Vec::new();
+    response.into_reader().read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(not(feature = "http"))]
+fn download(_url: &str) -> Result<Vec<u8>, Error> {
+    Err(Error::Download(String::from(
+        "built without the http feature",
+    )))
+}
+
+// CH55x flash is mapped into the 64KB code space, and CH32 flash is mapped
+// at 0x08000000.
+const FLASH_RANGES: [(usize, usize); 2] = [(0, 0x10000), (0x08000000, 0x08080000)];
+
+// Returns true if `addr..addr + size` fits in one of the flash ranges.
+fn in_flash(addr: usize, size: usize) -> bool {
+    FLASH_RANGES.iter().any(|(start, end)| {
+        *start <= addr && addr.checked_add(size).is_some_and(|last| last <= *end)
+    })
+}
+
+// Decodes hex digit pairs of a text record, e.g. Intel HEX and S-record.
+fn hex_bytes(line: &str) -> Option<Vec<u8>> {
+    // Slicing by 2 bytes below needs ASCII characters.
+    if line.len() & 1 != 0 || !line.is_ascii() {
+        return None;
+    }
+    (0..line.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&line[i..i + 2], 16).ok())
+        .collect()
+}
+
+// Sparse memory image loaded from a firmware file. Bytes that are not
+// specified by the file are tracked so that they can be skipped or filled.
+// `data` holds bytes from `base` so that images linked at a high address, e.g.
+// 0x08000000 for CH32 chips, do not allocate memory below it.
+#[derive(Clone, Default)]
+pub struct Image {
+    base: usize,
+    data: Vec<u8>,
+    mask: Vec<bool>,
+}
+
+impl Image {
+    pub fn new() -> Self {
+        Image::default()
+    }
+
+    pub fn from_binary(data: Vec<u8>) -> Self {
+        let mask = vec![true; data.len()];
+        Image {
+            base: 0,
+            data,
+            mask,
+        }
+    }
+
+    // Loads a firmware file, or stdin for "-". Compressed files are expanded,
+    // and the format is detected from the contents unless `format` is
+    // specified.
+    pub fn load(filename: &str, format: Option<Format>) -> Result<Self, Error> {
+        Image::parse(decompress(read(filename)?)?, format)
+    }
+
+    // Parses firmware file contents. The format is detected from the contents
+    // unless `format` is specified.
+    pub fn parse(bytes: Vec<u8>, format: Option<Format>) -> Result<Self, Error> {
+        match format.unwrap_or_else(|| Format::detect(&bytes)) {
+            Format::IntelHex => ihex::parse(&bytes),
+            Format::SRecord => srec::parse(&bytes),
+            Format::Elf => elf::parse(&bytes),
+            Format::Uf2 => uf2::parse(&bytes),
+            Format::Binary => Ok(Image::from_binary(bytes)),
+            Format::CArray | Format::RustArray => Err(Error::UnsupportedFormat),
+        }
+    }
+
+    // Writes the image into a file, or stdout for "-". `base` is the address
+    // that the first byte of the image is mapped to, and is used by address
+    // aware formats.
+    pub fn save(&self, filename: &String, format: Format, base: usize) -> Result<(), Error> {
+        let bytes = match format {
+            Format::Binary => {
+                let mut bytes = vec![0xff; self.base];
+                bytes.extend_from_slice(&self.data);
+                bytes
+            }
+            Format::IntelHex => ihex::emit(self, base).into_bytes(),
+            Format::SRecord => srec::emit(self, base).into_bytes(),
+            Format::CArray => source::emit_c(self, base).into_bytes(),
+            Format::RustArray => source::emit_rust(self, base).into_bytes(),
+            Format::Elf | Format::Uf2 => return Err(Error::UnsupportedFormat),
+        };
+        if filename == "-" {
+            let mut out = stdout().lock();
+            out.write_all(&bytes)?;
+            out.flush()?;
+        } else {
+            File::create(filename)?.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+
+    // Extends `data` so that it covers `addr..end`.
+    fn grow(&mut self, addr: usize, end: usize) {
+        if self.data.is_empty() {
+            self.base = addr;
+        } else if addr < self.base {
+            let size = self.base - addr;
+            self.data.splice(0..0, std::iter::repeat_n(0xff, size));
+            self.mask.splice(0..0, std::iter::repeat_n(false, size));
+            self.base = addr;
+        }
+        if end > self.len() {
+            self.data.resize(end - self.base, 0xff);
+            self.mask.resize(end - self.base, false);
+        }
+    }
+
+    pub fn set(&mut self, addr: usize, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        let end = addr + bytes.len();
+        self.grow(addr, end);
+        let (addr, end) = (addr - self.base, end - self.base);
+        self.data[addr..end].copy_from_slice(bytes);
+        for m in &mut self.mask[addr..end] {
+            *m = true;
+        }
+    }
+
+    pub fn get(&self, addr: usize) -> Option<u8> {
+        let index = addr.checked_sub(self.base)?;
+        if index < self.data.len() && self.mask[index] {
+            Some(self.data[index])
+        } else {
+            None
+        }
+    }
+
+    // Returns the address next to the last specified byte.
+    pub fn len(&self) -> usize {
+        if self.data.is_empty() {
+            0
+        } else {
+            self.base + self.data.len()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    // Returns the lowest specified address, if any.
+    pub fn start(&self) -> Option<usize> {
+        self.mask
+            .iter()
+            .position(|m| *m)
+            .map(|index| self.base + index)
+    }
+
+    // Drops bytes at `size` and above.
+    pub fn truncate(&mut self, size: usize) {
+        let size = size.saturating_sub(self.base);
+        self.data.truncate(size);
+        self.mask.truncate(size);
+    }
+
+    // Fills unspecified bytes below `size` with `byte`, and extends the image
+    // to `size` if it is shorter.
+    pub fn pad(&mut self, size: usize, byte: u8) {
+        if size == 0 {
+            return;
+        }
+        self.grow(0, size);
+        for index in 0..size {
+            if !self.mask[index] {
+                self.data[index] = byte;
+                self.mask[index] = true;
+            }
+        }
+    }
+
+    // Moves all specified bytes down by `offset`. Bytes below `offset` are
+    // dropped.
+    pub fn rebase(&mut self, offset: usize) {
+        if offset <= self.base {
+            self.base -= offset;
+            return;
+        }
+        let size = (offset - self.base).min(self.data.len());
+        self.data.drain(0..size);
+        self.mask.drain(0..size);
+        self.base = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn high_base_is_sparse() {
+        let mut image = Image::new();
+        image.set(0x0800_0010, &[1, 2]);
+        image.set(0x0800_0000, &[3]);
+        assert!(image.data.len() < 0x100);
+        assert_eq!(image.start(), Some(0x0800_0000));
+        assert_eq!(image.len(), 0x0800_0012);
+        assert_eq!(image.get(0x0800_0000), Some(3));
+        assert_eq!(image.get(0x0800_0001), None);
+        assert_eq!(image.get(0x0800_0011), Some(2));
+        assert_eq!(image.get(0), None);
+
+        image.rebase(0x0800_0000);
+        assert_eq!(image.start(), Some(0));
+        assert_eq!(image.len(), 0x12);
+        assert_eq!(image.get(0x10), Some(1));
+    }
+
+    #[test]
+    fn rebase_drops_lower_bytes() {
+        let mut image = Image::new();
+        image.set(0x10, &[1, 2, 3, 4]);
+        image.rebase(0x12);
+        assert_eq!(image.start(), Some(0));
+        assert_eq!(image.get(0), Some(3));
+        assert_eq!(image.len(), 2);
+    }
+
+    #[test]
+    fn non_ascii_records() {
+        let ihex = ":0100000000FF\n:0\u{e9}0\n".as_bytes().to_vec();
+        assert!(matches!(
+            Image::parse(ihex, Some(Format::IntelHex)),
+            Err(Error::IntelHex(2))
+        ));
+        let srec = "S104000000FB\nS10\u{e9}0\n".as_bytes().to_vec();
+        assert!(matches!(
+            Image::parse(srec, Some(Format::SRecord)),
+            Err(Error::SRecord(2))
+        ));
+    }
+
+    #[test]
+    fn pad_and_truncate() {
+        let mut image = Image::new();
+        image.set(4, &[1, 2, 3, 4]);
+        image.truncate(6);
+        assert_eq!(image.len(), 6);
+        image.pad(8, 0xee);
+        assert_eq!(image.start(), Some(0));
+        assert_eq!(image.get(0), Some(0xee));
+        assert_eq!(image.get(5), Some(2));
+        assert_eq!(image.get(6), Some(0xee));
+        assert_eq!(image.len(), 8);
+    }
+}
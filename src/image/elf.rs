@@ -0,0 +1,130 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use crate::ch559::Error;
+use crate::image::{in_flash, Image};
+
+const PT_LOAD: u32 = 1;
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    little_endian: bool,
+}
+
+impl Reader<'_> {
+    fn read(&self, offset: usize, size: usize) -> Result<u64, Error> {
+        let end = offset.checked_add(size).ok_or(Error::Elf)?;
+        let bytes = self.bytes.get(offset..end).ok_or(Error::Elf)?;
+        let value = if self.little_endian {
+            bytes.iter().rev().fold(0u64, |a, b| (a << 8) | *b as u64)
+        } else {
+            bytes.iter().fold(0u64, |a, b| (a << 8) | *b as u64)
+        };
+        Ok(value)
+    }
+}
+
+// Extracts PT_LOAD segments and places them at their physical (load)
+// addresses. Zero-initialized parts of segments are not part of the image.
+// Segments outside of the flash are refused.
+pub fn parse(bytes: &[u8]) -> Result<Image, Error> {
+    if bytes.len() < 0x34 || bytes[0..4] != [0x7f, b'E', b'L', b'F'] {
+        return Err(Error::Elf);
+    }
+    let is_64 = match bytes[4] {
+        1 => false,
+        2 => true,
+        _ => return Err(Error::Elf),
+    };
+    let little_endian = match bytes[5] {
+        1 => true,
+        2 => false,
+        _ => return Err(Error::Elf),
+    };
+    let reader = Reader {
+        bytes,
+        little_endian,
+    };
+    let (phoff, phentsize, phnum) = if is_64 {
+        (
+            reader.read(0x20, 8)? as usize,
+            reader.read(0x36, 2)? as usize,
+            reader.read(0x38, 2)? as usize,
+        )
+    } else {
+        (
+            reader.read(0x1c, 4)? as usize,
+            reader.read(0x2a, 2)? as usize,
+            reader.read(0x2c, 2)? as usize,
+        )
+    };
+    let mut image = Image::new();
+    for i in 0..phnum {
+        let header = i
+            .checked_mul(phentsize)
+            .and_then(|offset| offset.checked_add(phoff))
+            .ok_or(Error::Elf)?;
+        if reader.read(header, 4)? as u32 != PT_LOAD {
+            continue;
+        }
+        let (offset, paddr, filesz) = if is_64 {
+            (
+                reader.read(header + 0x08, 8)? as usize,
+                reader.read(header + 0x18, 8)? as usize,
+                reader.read(header + 0x20, 8)? as usize,
+            )
+        } else {
+            (
+                reader.read(header + 0x04, 4)? as usize,
+                reader.read(header + 0x0c, 4)? as usize,
+                reader.read(header + 0x10, 4)? as usize,
+            )
+        };
+        if filesz == 0 {
+            continue;
+        }
+        let end = offset.checked_add(filesz).ok_or(Error::Elf)?;
+        let segment = bytes.get(offset..end).ok_or(Error::Elf)?;
+        if !in_flash(paddr, filesz) {
+            return Err(Error::Elf);
+        }
+        image.set(paddr, segment);
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a little endian ELF32 with one PT_LOAD segment of 4 bytes.
+    fn elf32(phoff: u32, offset: u32, paddr: u32) -> Vec<u8> {
+        let mut bytes = vec![0; 0x58];
+        bytes[0..6].copy_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1]);
+        bytes[0x1c..0x20].copy_from_slice(&phoff.to_le_bytes());
+        bytes[0x2a..0x2c].copy_from_slice(&32u16.to_le_bytes());
+        bytes[0x2c..0x2e].copy_from_slice(&1u16.to_le_bytes());
+        bytes[0x34..0x38].copy_from_slice(&PT_LOAD.to_le_bytes());
+        bytes[0x38..0x3c].copy_from_slice(&offset.to_le_bytes());
+        bytes[0x40..0x44].copy_from_slice(&paddr.to_le_bytes());
+        bytes[0x44..0x48].copy_from_slice(&4u32.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn places_segments() {
+        let image = parse(&elf32(0x34, 0x54, 0x100)).unwrap();
+        assert_eq!(image.start(), Some(0x100));
+        assert_eq!(image.len(), 0x104);
+    }
+
+    #[test]
+    fn refuses_bad_offsets() {
+        assert!(matches!(parse(&elf32(u32::MAX, 0x54, 0)), Err(Error::Elf)));
+        assert!(matches!(parse(&elf32(0x34, u32::MAX, 0)), Err(Error::Elf)));
+        assert!(matches!(
+            parse(&elf32(0x34, 0x54, 0x20000)),
+            Err(Error::Elf)
+        ));
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use crate::ch559::Error;
+use crate::image::{hex_bytes, Image};
+
+// Parses Intel HEX records as SDCC emits them. Record addresses are honored
+// so that sparse images are placed at the right offsets.
+pub fn parse(bytes: &[u8]) -> Result<Image, Error> {
+    let text = std::str::from_utf8(bytes).map_err(|_| Error::IntelHex(0))?;
+    let mut image = Image::new();
+    let mut base: usize = 0;
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some(record) = line.strip_prefix(':') else {
+            return Err(Error::IntelHex(line_number));
+        };
+        let record = hex_bytes(record).ok_or(Error::IntelHex(line_number))?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(Error::IntelHex(line_number));
+        }
+        let sum = record.iter().fold(0u8, |a, b| a.wrapping_add(*b));
+        if sum != 0 {
+            return Err(Error::IntelHex(line_number));
+        }
+        let addr = ((record[1] as usize) << 8) | record[2] as usize;
+        let payload = &record[4..record.len() - 1];
+        match record[3] {
+            0x00 => image.set(base + addr, payload),
+            0x01 => break,
+            0x02 if payload.len() == 2 => {
+                base = (((payload[0] as usize) << 8) | payload[1] as usize) << 4;
+            }
+            0x04 if payload.len() == 2 => {
+                base = (((payload[0] as usize) << 8) | payload[1] as usize) << 16;
+            }
+            0x03 | 0x05 => {}
+            _ => return Err(Error::IntelHex(line_number)),
+        }
+    }
+    Ok(image)
+}
+
+fn record(kind: u8, addr: u16, payload: &[u8]) -> String {
+    let mut bytes = vec![payload.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+    bytes.extend_from_slice(payload);
+    let sum = bytes.iter().fold(0u8, |a, b| a.wrapping_add(*b));
+    bytes.push(sum.wrapping_neg());
+    let mut line = String::from(":");
+    for byte in bytes {
+        line.push_str(&format!("{:02X}", byte));
+    }
+    line.push('\n');
+    line
+}
+
+// Emits specified bytes of the image as Intel HEX records, placing the image
+// at `base`.
+pub fn emit(image: &Image, base: usize) -> String {
+    let mut text = String::new();
+    let mut segment: usize = 0;
+    let mut addr = 0;
+    while addr < image.len() {
+        if image.get(addr).is_none() {
+            addr += 1;
+            continue;
+        }
+        let mut payload: Vec<u8> = Vec::with_capacity(16);
+        let start = base + addr;
+        while let Some(byte) = image.get(addr) {
+            payload.push(byte);
+            addr += 1;
+            if payload.len() == 16 || (base + addr) & 0xffff == 0 {
+                break;
+            }
+        }
+        if start >> 16 != segment {
+            segment = start >> 16;
+            text.push_str(&record(0x04, 0, &[(segment >> 8) as u8, segment as u8]));
+        }
+        text.push_str(&record(0x00, start as u16, &payload));
+    }
+    text.push_str(&record(0x01, 0, &[]));
+    text
+}
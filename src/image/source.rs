@@ -0,0 +1,38 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use crate::image::Image;
+
+// Formats bytes as array elements, 12 per line. Unspecified bytes are 0xff.
+fn elements(image: &Image, indent: &str) -> String {
+    let bytes: Vec<u8> = (0..image.len())
+        .map(|addr| image.get(addr).unwrap_or(0xff))
+        .collect();
+    let mut text = String::new();
+    for line in bytes.chunks(12) {
+        let line: Vec<String> = line.iter().map(|byte| format!("0x{:02x},", byte)).collect();
+        text.push_str(&format!("{}{}\n", indent, line.join(" ")));
+    }
+    text
+}
+
+// Emits a C array, e.g. to embed factory defaults into firmware sources.
+pub fn emit_c(image: &Image, base: usize) -> String {
+    format!(
+        "// Data at 0x{:04x}\n#include <stdint.h>\n\n\
+         const uint8_t eeprom[{}] = {{\n{}}};\n",
+        base,
+        image.len(),
+        elements(image, "  ")
+    )
+}
+
+// Emits a Rust array in the same way as `emit_c`.
+pub fn emit_rust(image: &Image, base: usize) -> String {
+    format!(
+        "// Data at 0x{:04x}\npub const EEPROM: [u8; {}] = [\n{}];\n",
+        base,
+        image.len(),
+        elements(image, "    ")
+    )
+}
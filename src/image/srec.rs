@@ -0,0 +1,97 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use crate::ch559::Error;
+use crate::image::{hex_bytes, Image};
+
+// Parses Motorola S-records. S1/S2/S3 data records are placed at their own
+// addresses, and other records are ignored after the checksum validation.
+pub fn parse(bytes: &[u8]) -> Result<Image, Error> {
+    let text = std::str::from_utf8(bytes).map_err(|_| Error::SRecord(0))?;
+    let mut image = Image::new();
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some(record) = line.strip_prefix('S') else {
+            return Err(Error::SRecord(line_number));
+        };
+        let Some(kind) = record.chars().next().and_then(|c| c.to_digit(10)) else {
+            return Err(Error::SRecord(line_number));
+        };
+        let record = hex_bytes(&record[1..]).ok_or(Error::SRecord(line_number))?;
+        if record.is_empty() || record.len() != record[0] as usize + 1 {
+            return Err(Error::SRecord(line_number));
+        }
+        let sum = record.iter().fold(0u8, |a, b| a.wrapping_add(*b));
+        if sum != 0xff {
+            return Err(Error::SRecord(line_number));
+        }
+        let address_size = match kind {
+            1 => 2,
+            2 => 3,
+            3 => 4,
+            0 | 4..=9 => continue,
+            _ => return Err(Error::SRecord(line_number)),
+        };
+        if record.len() < address_size + 2 {
+            return Err(Error::SRecord(line_number));
+        }
+        let addr = record[1..=address_size]
+            .iter()
+            .fold(0usize, |a, b| (a << 8) | *b as usize);
+        image.set(addr, &record[address_size + 1..record.len() - 1]);
+    }
+    Ok(image)
+}
+
+fn record(kind: u8, address_size: usize, addr: usize, payload: &[u8]) -> String {
+    let mut bytes = vec![(address_size + payload.len() + 1) as u8];
+    for i in (0..address_size).rev() {
+        bytes.push((addr >> (i * 8)) as u8);
+    }
+    bytes.extend_from_slice(payload);
+    let sum = bytes.iter().fold(0u8, |a, b| a.wrapping_add(*b));
+    bytes.push(!sum);
+    let mut line = format!("S{}", kind);
+    for byte in bytes {
+        line.push_str(&format!("{:02X}", byte));
+    }
+    line.push('\n');
+    line
+}
+
+// Emits specified bytes of the image as S-records, placing the image at
+// `base`. The shortest address field that covers the image is used.
+pub fn emit(image: &Image, base: usize) -> String {
+    let end = base + image.len();
+    let (data_kind, end_kind, address_size) = if end <= 0x10000 {
+        (1, 9, 2)
+    } else if end <= 0x1000000 {
+        (2, 8, 3)
+    } else {
+        (3, 7, 4)
+    };
+    let mut text = record(0, 2, 0, b"ch559flasher");
+    let mut addr = 0;
+    while addr < image.len() {
+        if image.get(addr).is_none() {
+            addr += 1;
+            continue;
+        }
+        let start = base + addr;
+        let mut payload: Vec<u8> = Vec::with_capacity(16);
+        while let Some(byte) = image.get(addr) {
+            payload.push(byte);
+            addr += 1;
+            if payload.len() == 16 {
+                break;
+            }
+        }
+        text.push_str(&record(data_kind, address_size, start, &payload));
+    }
+    text.push_str(&record(end_kind, address_size, 0, &[]));
+    text
+}
@@ -0,0 +1,90 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use crate::ch559::Error;
+use crate::image::{in_flash, Image};
+
+const BLOCK_SIZE: usize = 512;
+const MAGIC_START0: u32 = 0x0a324655;
+const MAGIC_START1: u32 = 0x9e5d5157;
+const MAGIC_END: u32 = 0x0ab16f30;
+const FLAG_NOT_MAIN_FLASH: u32 = 0x00000001;
+const FLAG_FAMILY_ID_PRESENT: u32 = 0x00002000;
+
+// Family IDs from uf2families.json that this tool can flash. CH55x has no
+// family ID, and its files leave the family flag unset.
+const FAMILIES: [u32; 1] = [
+    0x699b62ec, // WCH CH32V2xx and CH32V3xx
+];
+
+fn word(block: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        block[offset],
+        block[offset + 1],
+        block[offset + 2],
+        block[offset + 3],
+    ])
+}
+
+// Collects payloads of UF2 blocks that target the flash. Blocks marked as
+// not for the main flash, or addressed outside of it, are skipped. Blocks for
+// other families are refused.
+pub fn parse(bytes: &[u8]) -> Result<Image, Error> {
+    let blocks = bytes.chunks_exact(BLOCK_SIZE);
+    if bytes.is_empty() || !blocks.remainder().is_empty() {
+        return Err(Error::Uf2);
+    }
+    let mut image = Image::new();
+    for block in blocks {
+        if word(block, 0) != MAGIC_START0
+            || word(block, 4) != MAGIC_START1
+            || word(block, BLOCK_SIZE - 4) != MAGIC_END
+        {
+            return Err(Error::Uf2);
+        }
+        let flags = word(block, 8);
+        let addr = word(block, 12) as usize;
+        let size = word(block, 16) as usize;
+        if size > 476 {
+            return Err(Error::Uf2);
+        }
+        let family = word(block, 28);
+        if flags & FLAG_FAMILY_ID_PRESENT != 0 && !FAMILIES.contains(&family) {
+            return Err(Error::Uf2Family(family));
+        }
+        if flags & FLAG_NOT_MAIN_FLASH != 0 || !in_flash(addr, size) {
+            continue;
+        }
+        image.set(addr, &block[32..32 + size]);
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(flags: u32, addr: u32, family: u32) -> Vec<u8> {
+        let mut block = vec![0; BLOCK_SIZE];
+        block[0..4].copy_from_slice(&MAGIC_START0.to_le_bytes());
+        block[4..8].copy_from_slice(&MAGIC_START1.to_le_bytes());
+        block[8..12].copy_from_slice(&flags.to_le_bytes());
+        block[12..16].copy_from_slice(&addr.to_le_bytes());
+        block[16..20].copy_from_slice(&4u32.to_le_bytes());
+        block[28..32].copy_from_slice(&family.to_le_bytes());
+        block[BLOCK_SIZE - 4..].copy_from_slice(&MAGIC_END.to_le_bytes());
+        block
+    }
+
+    #[test]
+    fn family_ids() {
+        let image = parse(&block(0, 0x100, 0xe48bff56)).unwrap();
+        assert_eq!(image.len(), 0x104);
+        let image = parse(&block(FLAG_FAMILY_ID_PRESENT, 0x08000000, FAMILIES[0])).unwrap();
+        assert_eq!(image.start(), Some(0x08000000));
+        assert!(matches!(
+            parse(&block(FLAG_FAMILY_ID_PRESENT, 0x100, 0xe48bff56)),
+            Err(Error::Uf2Family(0xe48bff56))
+        ));
+    }
+}
@@ -0,0 +1,14 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+pub mod ch559;
+pub mod editor;
+pub mod image;
+pub mod production;
+pub mod transport;
+#[cfg(feature = "async")]
+pub use crate::ch559::asynchronous::{AsyncCh559, Progress};
+pub use crate::ch559::chip::Chip;
+pub use crate::ch559::{Ch559, Difference, Error, Fill, Mismatch, Protocol};
+pub use crate::image::Image;
+pub use crate::transport::Transport;
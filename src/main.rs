@@ -1,141 +1,170 @@
 // Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
 // Use of this source code is governed by a BSD-style license that can be found
 // in the LICENSE file.
-use clap::Parser;
+mod cli;
 
-mod ch559;
-use crate::ch559::Ch559;
+use ch559flasher::ch559;
+use ch559flasher::production::{Project, Record};
+use ch559flasher::transport::serial::parse_line_steps;
+use ch559flasher::transport::Selector;
+use ch559flasher::{Ch559, Error};
+use clap::{CommandFactory, FromArgMatches};
+use std::time::{Duration, Instant};
 
-#[derive(Parser)]
-#[command(author, version, about, long_about = None)]
-struct Options {
-    #[arg(short, long, help = "Erase program area")]
-    erase: bool,
-    #[arg(short = 'w', long, help = "Write a specified file to program area")]
-    write_program: Option<String>,
-    #[arg(short = 'c', long, help = "Compare program area with a specified file")]
-    compare_program: Option<String>,
-
-    #[arg(short = 'E', long, help = "Erase data area")]
-    erase_data: bool,
-    #[arg(short = 'R', long, help = "Read data area to a specified file")]
-    read_data: Option<String>,
-    #[arg(short = 'W', long, help = "Write a specified file to data area")]
-    write_data: Option<String>,
-    #[arg(short = 'C', long, help = "Compare data area with a specified file")]
-    compare_data: Option<String>,
-
-    #[arg(short, long, help = "Fullfill unused area with randomized values")]
-    fullfill: bool,
-    #[arg(short, long, help = "Random seed")]
-    seed: Option<u64>,
-
-    #[arg(short = 'g', long, help = "Write BOOT_CFG[15:8] in hex (i.e. 4e)")]
-    config: Option<String>,
-
-    #[arg(short, long, help = "Boot application")]
-    boot: bool,
-}
+use crate::cli::doctor::{doctor, find_port, list};
+use crate::cli::flash::flash;
+use crate::cli::fleet::{all, daemon, repeat};
+use crate::cli::operation::check_erase_order;
+use crate::cli::options::{apply_project, selector, stdout_is_data, Options};
+use crate::cli::production::{image_crc32, run_hook, written_sha256};
+use crate::cli::udev::{install_udev, udev_rule};
+use crate::cli::usb::{self, UsbTransport};
 
 fn main() {
-    let options = Options::parse();
-    let mut ch559 = match Ch559::new() {
-        Ok(ch559) => ch559,
-        Err(e) => {
-            println!("{}", e);
-            std::process::exit(exitcode::USAGE);
-        }
+    let matches = Options::command().get_matches();
+    let mut options = match Options::from_arg_matches(&matches) {
+        Ok(options) => options,
+        Err(error) => error.exit(),
     };
-    if let Some(seed) = options.seed {
-        println!("random seed: {}", seed);
-        ch559.set_seed(seed);
-    }
-    if options.erase || options.write_program.is_some() {
-        match ch559.erase() {
-            Ok(()) => println!("erase: complete"),
+    ch559::set_dump_responses(options.dump_detect);
+    if let Some(filename) = options.project.clone() {
+        match Project::load(&filename) {
+            Ok(project) => apply_project(&mut options, project),
             Err(error) => {
-                println!("erase: {}", error);
-                std::process::exit(exitcode::IOERR);
+                println!("project: {}", error);
+                std::process::exit(match error {
+                    Error::Io(_) => exitcode::IOERR,
+                    _ => exitcode::DATAERR,
+                });
             }
         }
     }
-    if let Some(filename) = options.write_program.as_ref() {
-        match ch559.write(filename, true, false, options.fullfill) {
-            Ok(()) => println!("write: complete"),
-            Err(error) => {
-                println!("write: {}", error);
-                std::process::exit(exitcode::IOERR);
-            }
-        }
+    if let Err(error) = check_erase_order(&options, &matches) {
+        println!("usage: {}", error);
+        std::process::exit(exitcode::USAGE);
     }
-    if let Some(filename) = options.compare_program.as_ref() {
-        match ch559.write(filename, false, false, options.fullfill) {
-            Ok(()) => println!("compare: complete"),
-            Err(error) => {
-                println!("compare: {}", error);
-                std::process::exit(exitcode::IOERR);
-            }
-        }
+    if options.list {
+        list(&options);
+        std::process::exit(exitcode::OK);
     }
-    if options.erase_data || options.write_data.is_some() {
-        match ch559.erase_data() {
-            Ok(()) => println!("erase_data: complete"),
-            Err(error) => {
-                println!("erase_data: {}", error);
-                std::process::exit(exitcode::IOERR);
-            }
-        }
+    if options.doctor {
+        std::process::exit(doctor(&options));
+    }
+    if options.setup_udev {
+        print!("{}", udev_rule(&options));
+        std::process::exit(exitcode::OK);
     }
-    if let Some(filename) = options.read_data.as_ref() {
-        match ch559.read_data(filename) {
-            Ok(()) => println!("read_data: complete"),
+    if options.install_udev {
+        match install_udev(&options) {
+            Ok(()) => println!("install_udev: complete, replug the device"),
             Err(error) => {
-                println!("read_data: {}", error);
-                std::process::exit(exitcode::IOERR);
+                println!("install_udev: {}", error);
+                std::process::exit(exitcode::CANTCREAT);
             }
         }
+        std::process::exit(exitcode::OK);
     }
-    if let Some(filename) = options.write_data.as_ref() {
-        match ch559.write(filename, true, true, options.fullfill) {
-            Ok(()) => println!("write_data: complete"),
-            Err(error) => {
-                println!("write_data: {}", error);
-                std::process::exit(exitcode::IOERR);
-            }
+    if options.port.as_deref() == Some("auto") {
+        options.port = Some(find_port());
+    }
+    if options.all {
+        all(&options, &matches);
+    }
+    if options.daemon {
+        daemon(&options, &matches);
+    }
+    if options.repeat {
+        repeat(&options, &matches);
+    }
+    let log = |message: &str| {
+        if stdout_is_data(&options) {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    };
+    let start = Instant::now();
+    let mut wait = options.wait;
+    // The board is reset, and the bootloader appears again if it is entered.
+    if options.power_cycle {
+        let port_path = options.port_path.as_deref().unwrap_or_default();
+        if let Err(error) = UsbTransport::power_cycle(port_path, Duration::from_secs(1)) {
+            log(&format!("power_cycle: {}", error));
+            std::process::exit(exitcode::IOERR);
         }
+        log("power_cycle: complete");
+        wait = wait.or(Some(Some(5)));
     }
-    if let Some(filename) = options.compare_data.as_ref() {
-        match ch559.write(filename, false, true, options.fullfill) {
-            Ok(()) => println!("compare_data: complete"),
-            Err(error) => {
-                println!("compare_data: {}", error);
-                std::process::exit(exitcode::IOERR);
-            }
+    if options.usb_reset {
+        if let Err(error) = UsbTransport::reset(&selector(&options)) {
+            log(&format!("usb_reset: {}", error));
+            std::process::exit(exitcode::IOERR);
         }
+        log("usb_reset: complete");
+        wait = wait.or(Some(Some(5)));
     }
-    if let Some(config) = options.config.as_ref() {
-        match u8::from_str_radix(config, 16) {
-            Ok(v) => match ch559.write_config(v) {
-                Ok(()) => println!("write_config: complete ({:02x})", v),
-                Err(error) => {
-                    println!("write_config: {}", error);
-                    std::process::exit(exitcode::IOERR);
-                }
-            },
-            Err(error) => {
-                println!("config: {}", error);
-                std::process::exit(exitcode::USAGE);
-            }
+    // The application leaves the bus, and the bootloader appears instead.
+    if let Some((vid, pid, request)) = options.enter_isp {
+        let selector = Selector {
+            vid: Some(vid),
+            pid: Some(pid),
+            ..Default::default()
+        };
+        if let Err(error) = UsbTransport::send_vendor_request(&selector, request) {
+            log(&format!("enter_isp: {}", error));
+            std::process::exit(exitcode::IOERR);
         }
+        log("enter_isp: request sent");
+        wait = wait.or(Some(Some(5)));
     }
-    if options.boot {
-        match ch559.boot() {
-            Ok(()) => println!("boot: complete"),
-            Err(error) => {
-                println!("boot: {}", error);
-                std::process::exit(exitcode::IOERR);
+    let ch559 = match options.port.as_ref() {
+        _ if options.wch => Ch559::open_wch(options.device.unwrap_or(0) as u32),
+        Some(port) => options
+            .serial_reset
+            .as_deref()
+            .map_or(Ok(Vec::new()), parse_line_steps)
+            .and_then(|steps| Ch559::new_serial_with(port, &steps)),
+        None => match wait {
+            Some(timeout) => {
+                log("waiting for a device");
+                usb::wait(&selector(&options), timeout.map(Duration::from_secs))
             }
+            None => usb::open(&selector(&options)),
+        },
+    };
+    let mut ch559 = match ch559 {
+        Ok(ch559) => ch559,
+        Err(e) => {
+            log(&e.to_string());
+            run_hook(&options, &Record::default(), "", start.elapsed(), &log);
+            std::process::exit(exitcode::USAGE);
         }
+    };
+    if stdout_is_data(&options) {
+        ch559.set_warning(Box::new(|message| eprintln!("warning: {}", message)));
+    } else {
+        ch559.set_warning(Box::new(|message| println!("warning: {}", message)));
+    }
+    let result = flash(&mut ch559, &options, &matches, &log);
+    let record = Record {
+        device: options.port.clone().unwrap_or_default(),
+        chip: ch559.chip().name.to_string(),
+        chip_id: Some(ch559.chip_id()),
+        version: ch559.version().to_string(),
+        image_crc32: image_crc32(&ch559),
+        passed: result.is_ok(),
+        written_sha256: written_sha256(&ch559),
+        ..Default::default()
+    };
+    run_hook(
+        &options,
+        &record,
+        &ch559.uid_string(),
+        start.elapsed(),
+        &log,
+    );
+    if let Err(code) = result {
+        std::process::exit(code);
     }
     std::process::exit(exitcode::OK);
 }
@@ -0,0 +1,116 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+
+use crate::ch559::Error;
+
+pub mod bundle;
+pub mod csv;
+pub mod hash;
+pub mod layout;
+pub mod project;
+pub mod snapshot;
+pub mod template;
+
+pub use bundle::Bundle;
+pub use csv::{Fleet, FleetEntry, Record};
+pub use hash::{crc32, sha256};
+pub use layout::{Field, FieldType, Layout};
+pub use project::Project;
+pub use snapshot::Snapshot;
+pub use template::expand;
+
+// Parses a UID in hex that may contain ':' or '-' separators.
+fn parse_uid(text: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = text
+        .trim()
+        .bytes()
+        .filter(|c| *c != b':' && *c != b'-')
+        .collect();
+    if digits.is_empty() || digits.len() & 1 != 0 {
+        return None;
+    }
+    digits
+        .chunks_exact(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+// Parses a manifest in TOML, e.g. "manifest.toml", into `T`.
+fn manifest<T: DeserializeOwned>(text: &str) -> Result<T, Error> {
+    toml::from_str(text).map_err(|error| Error::Manifest(error.message().to_string()))
+}
+
+// Formats a time in ISO 8601 UTC, e.g. "2022-01-31T12:34:56Z".
+fn timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+    // Converts days since 1970-01-01 into a civil date.
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(deny_unknown_fields)]
+    struct Manifest {
+        name: Option<String>,
+        boot: Option<bool>,
+    }
+
+    #[test]
+    fn manifest_errors() {
+        let parsed: Manifest = manifest("# comment\nname = 'c:\\dir' # comment\n").unwrap();
+        assert_eq!(
+            parsed,
+            Manifest {
+                name: Some(String::from("c:\\dir")),
+                boot: None,
+            }
+        );
+        for text in [
+            "unknown = 1",
+            "name = 1",
+            "boot = \"true\"",
+            "name = \"unterminated",
+        ] {
+            let error = manifest::<Manifest>(text).unwrap_err();
+            assert!(matches!(error, Error::Manifest(_)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn timestamp_dates() {
+        let at = |seconds| timestamp(UNIX_EPOCH + std::time::Duration::from_secs(seconds));
+        assert_eq!(at(0), "1970-01-01T00:00:00Z");
+        assert_eq!(at(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(at(1_000_000_000), "2001-09-09T01:46:40Z");
+        assert_eq!(at(4_107_542_399), "2100-02-28T23:59:59Z");
+    }
+}
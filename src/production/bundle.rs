@@ -0,0 +1,89 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::ch559::Error;
+use crate::image::{self, Image};
+use crate::production::manifest;
+
+// Complete device image distributed as a single zip archive, e.g.
+// "firmware.ch559pkg". The "manifest.toml" in the archive names the images in
+// the archive and the BOOT_CFG byte to write, e.g.
+//   program = "firmware.hex"
+//   data = "eeprom.bin"
+//   config = "4e"
+// All of them are optional.
+#[derive(Default)]
+pub struct Bundle {
+    pub program: Option<Image>,
+    pub data: Option<Image>,
+    pub config: Option<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    program: Option<String>,
+    data: Option<String>,
+    config: Option<String>,
+}
+
+impl Bundle {
+    pub fn load(filename: &str) -> Result<Self, Error> {
+        let files = unzip(image::read(filename)?)?;
+        let file = |name: &str| {
+            files
+                .get(name)
+                .cloned()
+                .ok_or_else(|| Error::Bundle(format!("{} is missing", name)))
+        };
+        let text = String::from_utf8(file("manifest.toml")?)
+            .map_err(|_| Error::Bundle(String::from("manifest.toml is not UTF-8")))?;
+        let manifest: Manifest = manifest(&text)?;
+        let image = |name: Option<String>| {
+            name.map(|name| Image::parse(file(&name)?, None))
+                .transpose()
+        };
+        let config = manifest
+            .config
+            .map(|value| {
+                u8::from_str_radix(&value, 16)
+                    .map_err(|_| Error::Bundle(format!("invalid config {}", value)))
+            })
+            .transpose()?;
+        Ok(Bundle {
+            program: image(manifest.program)?,
+            data: image(manifest.data)?,
+            config,
+        })
+    }
+}
+
+// Returns the files in a zip archive by name.
+#[cfg(feature = "bundle")]
+fn unzip(bytes: Vec<u8>) -> Result<HashMap<String, Vec<u8>>, Error> {
+    use std::io::Read;
+    let error = |error: zip::result::ZipError| Error::Bundle(error.to_string());
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes)).map_err(error)?;
+    let mut files = HashMap::new();
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index).map_err(error)?;
+        if file.is_dir() {
+            continue;
+        }
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        files.insert(file.name().to_string(), bytes);
+    }
+    Ok(files)
+}
+
+#[cfg(not(feature = "bundle"))]
+fn unzip(_bytes: Vec<u8>) -> Result<HashMap<String, Vec<u8>>, Error> {
+    Err(Error::Bundle(String::from(
+        "built without the bundle feature",
+    )))
+}
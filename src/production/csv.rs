@@ -0,0 +1,175 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::ch559::Error;
+use crate::production::{parse_uid, timestamp};
+
+const HEADER: &str =
+    "timestamp,device,chip,chip_id,version,image_crc32,result,seed,written_sha256\n";
+
+// One line of the production log. Fields that are unknown, e.g. because the
+// device could not be opened, are left empty.
+#[derive(Clone, Debug, Default)]
+pub struct Record {
+    pub device: String,
+    pub chip: String,
+    pub chip_id: Option<u8>,
+    pub version: String,
+    pub image_crc32: Option<u32>,
+    pub passed: bool,
+    // Seed of the random fill, if used.
+    pub seed: Option<u64>,
+    // SHA-256 of bytes written to the program then data area, including fill.
+    pub written_sha256: Option<String>,
+}
+
+impl Record {
+    // Appends the record with the current time to a CSV file. A header is
+    // written first if the file is new.
+    pub fn append(&self, filename: &str) -> Result<(), Error> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(filename)?;
+        let mut line = String::new();
+        if file.metadata()?.len() == 0 {
+            line.push_str(HEADER);
+        }
+        line.push_str(&self.line(SystemTime::now()));
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    fn line(&self, time: SystemTime) -> String {
+        let fields = [
+            timestamp(time),
+            self.device.clone(),
+            self.chip.clone(),
+            self.chip_id
+                .map(|id| format!("{:02x}", id))
+                .unwrap_or_default(),
+            self.version.clone(),
+            self.image_crc32
+                .map(|crc| format!("{:08x}", crc))
+                .unwrap_or_default(),
+            String::from(if self.passed { "pass" } else { "fail" }),
+            self.seed.map(|seed| seed.to_string()).unwrap_or_default(),
+            self.written_sha256.clone().unwrap_or_default(),
+        ];
+        // Fields are quoted as RFC 4180 requires, e.g. if they contain a comma.
+        let mut writer = csv::WriterBuilder::new()
+            .terminator(csv::Terminator::Any(b'\n'))
+            .from_writer(Vec::new());
+        // Writing into a Vec does not fail.
+        writer.write_record(&fields).unwrap();
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    }
+}
+
+// Images to program into the device of `uid`.
+#[derive(Clone, Debug)]
+pub struct FleetEntry {
+    pub uid: Vec<u8>,
+    pub program: Option<String>,
+    pub data: Option<String>,
+}
+
+// Maps chip unique IDs to images. Each line of the CSV file is
+// "uid,program,data", e.g. "12345678,rev_a.hex,calib_a.bin". The UID is in
+// hex and may contain ':' or '-' separators. Empty files are skipped, and
+// relative paths are resolved from the CSV file. Fields may be quoted as RFC
+// 4180 specifies. Lines starting with '#' and a "uid" header line are ignored.
+pub struct Fleet {
+    entries: Vec<FleetEntry>,
+}
+
+impl Fleet {
+    pub fn load(filename: &str) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(filename)?;
+        let base = Path::new(filename).parent().unwrap_or(Path::new(""));
+        let file = |name: &str| {
+            if name.is_empty() {
+                None
+            } else {
+                Some(base.join(name).to_string_lossy().into_owned())
+            }
+        };
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .comment(Some(b'#'))
+            .trim(csv::Trim::All)
+            .from_reader(text.as_bytes());
+        let mut entries = Vec::new();
+        for record in reader.records() {
+            let line = |position: Option<&csv::Position>| {
+                position.map_or(0, |position| position.line() as usize)
+            };
+            let record = record.map_err(|error| Error::Fleet(line(error.position())))?;
+            if record.get(0) == Some("uid") {
+                continue;
+            }
+            let line = line(record.position());
+            if record.len() > 3 {
+                return Err(Error::Fleet(line));
+            }
+            let uid = parse_uid(&record[0]).ok_or(Error::Fleet(line))?;
+            entries.push(FleetEntry {
+                uid,
+                program: record.get(1).and_then(file),
+                data: record.get(2).and_then(file),
+            });
+        }
+        Ok(Fleet { entries })
+    }
+
+    pub fn lookup(&self, uid: &[u8]) -> Option<&FleetEntry> {
+        self.entries.iter().find(|entry| entry.uid == uid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn record_quotes_fields() {
+        let record = Record {
+            device: String::from("port \"A\", 1-2"),
+            chip: String::from("CH559"),
+            passed: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            record.line(UNIX_EPOCH),
+            "1970-01-01T00:00:00Z,\"port \"\"A\"\", 1-2\",CH559,,,,pass,,\n"
+        );
+    }
+
+    #[test]
+    fn fleet_quoted_fields() {
+        let path = std::env::temp_dir().join(format!("ch559flasher-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "uid,program,data\n# comment\n\n12:34,\"rev, a.hex\", \n\"56-78\",,\"\"\"q\"\".bin\"\n",
+        )
+        .unwrap();
+        let fleet = Fleet::load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let fleet = fleet.unwrap();
+        let base = std::env::temp_dir();
+        let entry = fleet.lookup(&[0x12, 0x34]).unwrap();
+        let name = |name: &str| Some(base.join(name).to_string_lossy().into_owned());
+        assert_eq!(entry.program, name("rev, a.hex"));
+        assert_eq!(entry.data, None);
+        let entry = fleet.lookup(&[0x56, 0x78]).unwrap();
+        assert_eq!(entry.program, None);
+        assert_eq!(entry.data, name("\"q\".bin"));
+    }
+}
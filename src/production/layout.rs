@@ -0,0 +1,244 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use serde::Deserialize;
+
+use crate::ch559::Error;
+use crate::production::manifest;
+
+// Named settings in the data area, e.g. "layout.toml".
+//   [backlight]
+//   offset = "10"      # in hex
+//   type = "u8"        # u8, u16, u32, i8, i16, i32, bytes, or string
+//   length = 4         # for bytes and string
+//   endian = "little"  # or "big"
+#[derive(Clone, Debug)]
+pub struct Layout {
+    pub fields: Vec<Field>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldType {
+    Unsigned,
+    Signed,
+    Bytes,
+    String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Field {
+    pub name: String,
+    pub offset: usize,
+    pub length: usize,
+    pub kind: FieldType,
+    pub big_endian: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FieldFile {
+    offset: Option<String>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    length: Option<usize>,
+    endian: Option<String>,
+}
+
+impl Layout {
+    pub fn load(filename: &str) -> Result<Self, Error> {
+        Layout::parse(&std::fs::read_to_string(filename)?)
+    }
+
+    fn parse(text: &str) -> Result<Self, Error> {
+        let invalid = |key: &str, value: &str| Error::Manifest(format!("{} = {}", key, value));
+        let mut fields: Vec<Field> = Vec::new();
+        // Tables keep their order in the file.
+        for (name, table) in manifest::<toml::Table>(text)? {
+            if !table.is_table() {
+                return Err(Error::Manifest(format!("{} is not in a field", name)));
+            }
+            let file: FieldFile = table.try_into().map_err(|error: toml::de::Error| {
+                Error::Manifest(format!("{}: {}", name, error.message()))
+            })?;
+            let key = |attribute: &str| format!("{}.{}", name, attribute);
+            let mut field = Field {
+                name: name.clone(),
+                offset: 0,
+                length: 1,
+                kind: FieldType::Unsigned,
+                big_endian: false,
+            };
+            if let Some(value) = file.offset {
+                let digits = value.trim_start_matches("0x");
+                field.offset = usize::from_str_radix(digits, 16)
+                    .map_err(|_| invalid(&key("offset"), &value))?;
+            }
+            match file.kind.as_deref() {
+                None => (),
+                Some("bytes") => field.kind = FieldType::Bytes,
+                Some("string") => field.kind = FieldType::String,
+                Some(kind) => {
+                    let (signed, bits) = match (kind.strip_prefix('u'), kind.strip_prefix('i')) {
+                        (Some(bits), _) => (false, bits),
+                        (_, Some(bits)) => (true, bits),
+                        _ => return Err(invalid(&key("type"), kind)),
+                    };
+                    field.length = match bits {
+                        "8" => 1,
+                        "16" => 2,
+                        "32" => 4,
+                        _ => return Err(invalid(&key("type"), kind)),
+                    };
+                    field.kind = if signed {
+                        FieldType::Signed
+                    } else {
+                        FieldType::Unsigned
+                    };
+                }
+            }
+            if let Some(length) = file.length {
+                field.length = length;
+            }
+            match file.endian.as_deref() {
+                None | Some("little") => (),
+                Some("big") => field.big_endian = true,
+                Some(endian) => return Err(invalid(&key("endian"), endian)),
+            }
+            fields.push(field);
+        }
+        // Integers are converted through i64.
+        let integer = |kind| kind == FieldType::Unsigned || kind == FieldType::Signed;
+        if let Some(field) = fields
+            .iter()
+            .find(|field| integer(field.kind) && ![1, 2, 4].contains(&field.length))
+        {
+            return Err(invalid(
+                &format!("{}.length", field.name),
+                &field.length.to_string(),
+            ));
+        }
+        Ok(Layout { fields })
+    }
+
+    pub fn field(&self, name: &str) -> Result<&Field, Error> {
+        self.fields
+            .iter()
+            .find(|field| field.name == name)
+            .ok_or_else(|| Error::Field(format!("unknown field {}", name)))
+    }
+}
+
+impl Field {
+    // Formats bytes of the field. Integers are in decimal, bytes are in hex,
+    // and strings end at the first 0x00 or 0xff.
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        match self.kind {
+            FieldType::Unsigned | FieldType::Signed => {
+                let mut value = 0u64;
+                for i in 0..self.length {
+                    let byte = if self.big_endian {
+                        bytes[i]
+                    } else {
+                        bytes[self.length - 1 - i]
+                    };
+                    value = (value << 8) | byte as u64;
+                }
+                let bits = self.length * 8;
+                if self.kind == FieldType::Signed && value >> (bits - 1) != 0 {
+                    (value as i64 - (1i64 << bits)).to_string()
+                } else {
+                    value.to_string()
+                }
+            }
+            FieldType::Bytes => bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            FieldType::String => {
+                let end = bytes
+                    .iter()
+                    .position(|byte| *byte == 0x00 || *byte == 0xff)
+                    .unwrap_or(bytes.len());
+                String::from_utf8_lossy(&bytes[..end]).into_owned()
+            }
+        }
+    }
+
+    // Converts a value in the format of `decode` into bytes of the field.
+    // Strings shorter than the field are terminated with 0x00.
+    pub fn encode(&self, value: &str) -> Result<Vec<u8>, Error> {
+        let invalid = || Error::Field(format!("{} = {}", self.name, value));
+        let bits = self.length * 8;
+        match self.kind {
+            FieldType::Unsigned | FieldType::Signed => {
+                let number = match value.strip_prefix("0x") {
+                    Some(digits) => i64::from_str_radix(digits, 16),
+                    None => value.parse::<i64>(),
+                }
+                .map_err(|_| invalid())?;
+                let (min, max) = if self.kind == FieldType::Signed {
+                    (-(1i64 << (bits - 1)), (1i64 << (bits - 1)) - 1)
+                } else {
+                    (0, (1i64 << bits) - 1)
+                };
+                if number < min || number > max {
+                    return Err(invalid());
+                }
+                let mut bytes: Vec<u8> = (0..self.length)
+                    .map(|i| (number >> (i * 8)) as u8)
+                    .collect();
+                if self.big_endian {
+                    bytes.reverse();
+                }
+                Ok(bytes)
+            }
+            FieldType::Bytes => {
+                if value.len() != self.length * 2 || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(invalid());
+                }
+                (0..value.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&value[i..i + 2], 16).map_err(|_| invalid()))
+                    .collect()
+            }
+            FieldType::String => {
+                if value.len() > self.length {
+                    return Err(invalid());
+                }
+                let mut bytes = value.as_bytes().to_vec();
+                bytes.resize(self.length, 0x00);
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_integer_length() {
+        let layout = Layout::parse("[id]\noffset = \"0\"\ntype = \"u16\"\n").unwrap();
+        assert_eq!(layout.field("id").unwrap().length, 2);
+        for length in ["0", "3", "8"] {
+            let text = format!("[id]\ntype = \"i32\"\nlength = {}\n", length);
+            let error = Layout::parse(&text).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("invalid manifest (id.length = {})", length)
+            );
+        }
+        // Lengths of bytes and strings are free.
+        Layout::parse("[name]\ntype = \"string\"\nlength = 16\n").unwrap();
+    }
+
+    #[test]
+    fn layout_invalid_types() {
+        for kind in ["", "u", "i64", "f32", "é8", "ü16", "u8é"] {
+            let text = format!("[id]\ntype = \"{}\"\n", kind);
+            let error = Layout::parse(&text).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("invalid manifest (id.type = {})", kind)
+            );
+        }
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::ch559::{Error, Fill};
+use crate::production::manifest;
+
+// Flashing recipe that a repository can check in, e.g. "flash.toml".
+//   program = "build/firmware.hex"
+//   data = "eeprom.bin"
+//   config = "4e"
+//   fill = "random"  # or a byte in hex, e.g. "ff"
+//   fill_pattern = "pattern.bin"  # instead of fill
+//   seed = "1234"
+//   boot = true
+// Relative paths are resolved from the project file. All keys are optional.
+#[derive(Clone, Debug, Default)]
+pub struct Project {
+    pub program: Option<String>,
+    pub data: Option<String>,
+    pub config: Option<u8>,
+    pub fill: Option<Fill>,
+    // File that `Fill::Pattern` is read from.
+    pub fill_pattern: Option<String>,
+    pub seed: Option<u64>,
+    pub boot: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProjectFile {
+    program: Option<String>,
+    data: Option<String>,
+    config: Option<String>,
+    fill: Option<String>,
+    fill_pattern: Option<String>,
+    seed: Option<String>,
+    #[serde(default)]
+    boot: bool,
+}
+
+impl Project {
+    pub fn load(filename: &str) -> Result<Self, Error> {
+        let file: ProjectFile = manifest(&std::fs::read_to_string(filename)?)?;
+        let base = Path::new(filename).parent().unwrap_or(Path::new(""));
+        let path = |name: String| base.join(name).to_string_lossy().into_owned();
+        let invalid = |key: &str, value: &str| Error::Manifest(format!("{} = {}", key, value));
+        let hex =
+            |key: &str, value: &str| u8::from_str_radix(value, 16).map_err(|_| invalid(key, value));
+        let fill_pattern = file.fill_pattern.map(path);
+        let fill = match (file.fill.as_deref(), fill_pattern.as_ref()) {
+            (Some(_), Some(_)) => {
+                return Err(Error::Manifest(String::from(
+                    "fill and fill_pattern are exclusive",
+                )))
+            }
+            (Some("random"), None) => Some(Fill::Random),
+            (Some(value), None) => Some(Fill::Byte(hex("fill", value)?)),
+            (None, Some(pattern)) => Some(Fill::Pattern(std::fs::read(pattern)?)),
+            (None, None) => None,
+        };
+        Ok(Project {
+            program: file.program.map(path),
+            data: file.data.map(path),
+            config: file.config.map(|value| hex("config", &value)).transpose()?,
+            fill,
+            fill_pattern,
+            seed: file
+                .seed
+                .map(|value| value.parse().map_err(|_| invalid("seed", &value)))
+                .transpose()?,
+            boot: file.boot,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_fill() {
+        let dir = std::env::temp_dir().join(format!("ch559flasher-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("pattern.bin"), [0x12, 0x34]).unwrap();
+        let load = |text: &str| {
+            let filename = dir.join("flash.toml");
+            std::fs::write(&filename, text).unwrap();
+            Project::load(filename.to_str().unwrap())
+        };
+        let project = load("fill_pattern = \"pattern.bin\"\nseed = \"7\"\n").unwrap();
+        assert_eq!(project.fill, Some(Fill::Pattern(vec![0x12, 0x34])));
+        assert!(project.fill_pattern.unwrap().ends_with("pattern.bin"));
+        assert_eq!(project.seed, Some(7));
+        assert_eq!(
+            load("fill = \"5a\"\n").unwrap().fill,
+            Some(Fill::Byte(0x5a))
+        );
+        for text in [
+            "fill = \"5a\"\nfill_pattern = \"pattern.bin\"\n",
+            "fill = \"ramdom\"\n",
+            "config = 4e\n",
+            "boot = \"yes\"\n",
+            "unknown = true\n",
+        ] {
+            assert!(load(text).is_err(), "{}", text);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
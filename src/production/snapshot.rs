@@ -0,0 +1,167 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Component;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ch559::Error;
+use crate::image;
+use crate::production::{manifest, parse_uid, timestamp};
+
+// Device state saved before a risky upgrade as a tar archive. The archive has
+// the data area in "data.bin" and a "manifest.toml" such as
+//   chip = "CH559"
+//   version = "2.31"
+//   uid = "01-23-45-67"
+//   config = "4e"
+//   timestamp = "2022-01-31T12:34:56Z"
+// The data area and config are missing if the device does not report them.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    pub chip: String,
+    pub version: String,
+    pub uid: Vec<u8>,
+    pub config: Option<u8>,
+    pub data: Option<Vec<u8>>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct Manifest {
+    chip: String,
+    version: String,
+    uid: String,
+    config: Option<String>,
+    timestamp: Option<String>,
+}
+
+impl Snapshot {
+    pub fn save(&self, filename: &str) -> Result<(), Error> {
+        let uid: Vec<String> = self.uid.iter().map(|b| format!("{:02x}", b)).collect();
+        let manifest = Manifest {
+            chip: self.chip.clone(),
+            version: self.version.clone(),
+            uid: uid.join("-"),
+            config: self.config.map(|config| format!("{:02x}", config)),
+            timestamp: Some(timestamp(SystemTime::now())),
+        };
+        let manifest =
+            toml::to_string(&manifest).map_err(|error| Error::Snapshot(error.to_string()))?;
+        let mut files = vec![("manifest.toml", manifest.into_bytes())];
+        if let Some(data) = self.data.as_ref() {
+            files.push(("data.bin", data.clone()));
+        }
+        std::fs::write(filename, tar(&files)?)?;
+        Ok(())
+    }
+
+    pub fn load(filename: &str) -> Result<Self, Error> {
+        let mut files = untar(&image::read(filename)?)?;
+        let text = files
+            .get("manifest.toml")
+            .ok_or_else(|| Error::Snapshot(String::from("manifest.toml is missing")))?;
+        let manifest: Manifest = manifest(&String::from_utf8_lossy(text))?;
+        let config = manifest
+            .config
+            .map(|value| {
+                u8::from_str_radix(&value, 16)
+                    .map_err(|_| Error::Snapshot(format!("invalid config {}", value)))
+            })
+            .transpose()?;
+        Ok(Snapshot {
+            chip: manifest.chip,
+            version: manifest.version,
+            uid: parse_uid(&manifest.uid).unwrap_or_default(),
+            config,
+            data: files.remove("data.bin"),
+        })
+    }
+}
+
+// Packs files into a ustar archive.
+fn tar(files: &[(&str, Vec<u8>)]) -> Result<Vec<u8>, Error> {
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut builder = tar::Builder::new(Vec::new());
+    for (name, bytes) in files {
+        let mut header = tar::Header::new_ustar();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        builder.append_data(&mut header, name, bytes.as_slice())?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+// Returns regular files in a tar archive by name. Absolute paths or ones with
+// ".." are refused as they may point outside of the archive.
+fn untar(bytes: &[u8]) -> Result<HashMap<String, Vec<u8>>, Error> {
+    let invalid =
+        |error: std::io::Error| Error::Snapshot(format!("broken tar archive ({})", error));
+    let mut archive = tar::Archive::new(bytes);
+    let mut files = HashMap::new();
+    for entry in archive.entries().map_err(invalid)? {
+        let mut entry = entry.map_err(invalid)?;
+        let path = entry.path().map_err(invalid)?.into_owned();
+        let name = path.to_string_lossy().into_owned();
+        if !path
+            .components()
+            .all(|part| matches!(part, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(Error::Snapshot(format!("unsafe path {}", name)));
+        }
+        if entry.header().entry_type().is_file() {
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes).map_err(invalid)?;
+            files.insert(name, bytes);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tar_round_trip() {
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let archive = tar(&[
+            ("manifest.toml", b"chip = \"CH559\"\n".to_vec()),
+            ("data.bin", data.clone()),
+        ])
+        .unwrap();
+        assert_eq!(archive.len() % 512, 0);
+        let files = untar(&archive).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files["manifest.toml"], b"chip = \"CH559\"\n");
+        assert_eq!(files["data.bin"], data);
+    }
+
+    #[test]
+    fn untar_checks_headers() {
+        let mut archive = tar(&[("data.bin", vec![1, 2, 3])]).unwrap();
+        archive[0] = b'D';
+        assert!(untar(&archive).is_err());
+        // Truncated data.
+        let archive = tar(&[("data.bin", vec![0; 1024])]).unwrap();
+        assert!(untar(&archive[..1024]).is_err());
+        // The tar crate refuses to write such paths, so headers are made by
+        // hand.
+        for name in ["/etc/passwd", "../data.bin", "a/../../b"] {
+            let mut header = tar::Header::new_ustar();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(1);
+            header.set_cksum();
+            let mut builder = tar::Builder::new(Vec::new());
+            builder.append(&header, [0u8].as_slice()).unwrap();
+            assert!(untar(&builder.into_inner().unwrap()).is_err(), "{}", name);
+        }
+    }
+}
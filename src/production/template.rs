@@ -0,0 +1,44 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use std::time::SystemTime;
+
+use rand::Rng;
+
+use crate::ch559::Error;
+use crate::production::timestamp;
+
+// Expands placeholders in a data flash template. {{serial}} is replaced with
+// `serial`, {{date}} with today in YYYY-MM-DD, and {{random:N}} with N random
+// bytes. Other bytes are copied as is.
+pub fn expand(template: &[u8], serial: &str) -> Result<Vec<u8>, Error> {
+    let mut expanded = Vec::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = find(rest, b"{{") {
+        expanded.extend_from_slice(&rest[..start]);
+        rest = &rest[start + 2..];
+        let end = find(rest, b"}}").ok_or(Error::Template(String::from("{{")))?;
+        let name = String::from_utf8_lossy(&rest[..end]).into_owned();
+        rest = &rest[end + 2..];
+        match name.split_once(':') {
+            None if name == "serial" => expanded.extend_from_slice(serial.as_bytes()),
+            None if name == "date" => {
+                expanded.extend_from_slice(&timestamp(SystemTime::now()).as_bytes()[..10])
+            }
+            Some(("random", size)) => {
+                let size: usize = size.parse().map_err(|_| Error::Template(name.clone()))?;
+                let mut rng = rand::thread_rng();
+                expanded.extend((0..size).map(|_| rng.gen::<u8>()));
+            }
+            _ => return Err(Error::Template(name)),
+        }
+    }
+    expanded.extend_from_slice(rest);
+    Ok(expanded)
+}
+
+fn find(bytes: &[u8], pattern: &[u8]) -> Option<usize> {
+    bytes
+        .windows(pattern.len())
+        .position(|window| window == pattern)
+}
@@ -0,0 +1,126 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+#[cfg(feature = "async")]
+use std::future::Future;
+
+use crate::ch559::Error;
+
+pub mod mock;
+#[cfg(feature = "nusb")]
+pub mod nusb;
+pub mod serial;
+#[cfg(feature = "simulator")]
+pub mod simulator;
+#[cfg(feature = "rusb")]
+pub mod usb;
+#[cfg(feature = "wch")]
+pub mod wch;
+
+pub use crate::transport::mock::MockTransport;
+#[cfg(all(feature = "nusb", not(feature = "rusb")))]
+pub use crate::transport::nusb::UsbTransport;
+#[cfg(feature = "serial")]
+pub use crate::transport::serial::SerialTransport;
+#[cfg(feature = "simulator")]
+pub use crate::transport::simulator::Simulator;
+#[cfg(feature = "rusb")]
+pub use crate::transport::usb::UsbTransport;
+#[cfg(feature = "wch")]
+pub use crate::transport::wch::WchTransport;
+
+// USB IDs of the WCH bootloader.
+pub const VID: u16 = 0x4348;
+pub const PID: u16 = 0x55e0;
+
+// How to fix failures to access a bootloader device on this OS.
+#[cfg(target_os = "linux")]
+pub const ACCESS_HINT: &str =
+    "install a udev rule with `sudo ch559flasher --install-udev`, or run as root";
+#[cfg(not(target_os = "linux"))]
+pub const ACCESS_HINT: &str = "close other applications that use the device";
+#[cfg(target_os = "windows")]
+pub const DRIVER_HINT: &str = "bind the WinUSB driver to the device with Zadig";
+#[cfg(not(target_os = "windows"))]
+pub const DRIVER_HINT: &str = "unbind the kernel driver from the device";
+#[cfg(target_os = "linux")]
+pub const NOT_FOUND_HINT: &str = "check `lsusb` for 4348:55e0, and the USB cable";
+#[cfg(target_os = "windows")]
+pub const NOT_FOUND_HINT: &str = "check the Device Manager for 4348:55e0, and the USB cable";
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+pub const NOT_FOUND_HINT: &str = "check the system report for 4348:55e0, and the USB cable";
+
+// Where a bootloader device is connected. `port_path` is the bus number
+// followed by the hub port chain, e.g. "1-2.4".
+#[derive(Clone, Debug)]
+pub struct Location {
+    pub bus: u8,
+    pub address: u8,
+    pub port_path: String,
+}
+
+impl Location {
+    pub fn new(bus: u8, address: u8, ports: &[u8]) -> Self {
+        let ports: Vec<String> = ports.iter().map(|port| port.to_string()).collect();
+        Location {
+            bus,
+            address,
+            port_path: format!("{}-{}", bus, ports.join(".")),
+        }
+    }
+}
+
+// Chooses a bootloader device among connected ones. Unset conditions match
+// any device.
+#[derive(Clone, Debug, Default)]
+pub struct Selector {
+    // Bus number and device address.
+    pub address: Option<(u8, u8)>,
+    // Position among matching devices in enumeration order, from 0.
+    pub index: Option<usize>,
+    // Physical port, e.g. "1-2.4", that stays the same across reconnection.
+    pub port_path: Option<String>,
+    // Overrides VID and PID for clones that enumerate with other IDs.
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+}
+
+impl Selector {
+    // Narrows down to the device at `location`.
+    pub fn at(&self, location: &Location) -> Selector {
+        Selector {
+            address: Some((location.bus, location.address)),
+            index: None,
+            port_path: None,
+            ..self.clone()
+        }
+    }
+
+    pub fn matches_ids(&self, vid: u16, pid: u16) -> bool {
+        vid == self.vid.unwrap_or(VID) && pid == self.pid.unwrap_or(PID)
+    }
+
+    pub fn matches(&self, location: &Location) -> bool {
+        self.address
+            .is_none_or(|a| a == (location.bus, location.address))
+            && self
+                .port_path
+                .as_ref()
+                .is_none_or(|path| *path == location.port_path)
+    }
+}
+
+// Carries bootloader requests and responses. Requests and responses are in
+// the USB framing, and transports wrap them as their links require.
+pub trait Transport: Send {
+    fn send(&mut self, request: &[u8]) -> Result<(), Error>;
+    fn receive(&mut self, response: &mut [u8]) -> Result<(), Error>;
+}
+
+// Non-blocking counterpart of `Transport` used by `AsyncCh559`. `receive`
+// returns `size` bytes at most.
+#[cfg(feature = "async")]
+pub trait AsyncTransport: Send {
+    fn send(&mut self, request: Vec<u8>) -> impl Future<Output = Result<(), Error>> + Send;
+    fn receive(&mut self, size: usize) -> impl Future<Output = Result<Vec<u8>, Error>> + Send;
+}
@@ -0,0 +1,151 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::ch559::Error;
+use crate::transport::Transport;
+
+#[derive(Default)]
+struct State {
+    requests: Vec<Vec<u8>>,
+    responses: VecDeque<Vec<u8>>,
+}
+
+// Replays queued responses and records requests. Clones share the same state
+// so that a clone can inspect requests after the original is handed over to
+// `Ch559`.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        MockTransport::default()
+    }
+
+    pub fn push_response(&self, response: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.responses.push_back(response.to_vec());
+    }
+
+    pub fn requests(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Transport for MockTransport {
+    fn send(&mut self, request: &[u8]) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        state.requests.push(request.to_vec());
+        Ok(())
+    }
+
+    fn receive(&mut self, response: &mut [u8]) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let Some(queued) = state.responses.pop_front() else {
+            return Err(Error::BulkRead(String::from("timeout")));
+        };
+        let size = response.len().min(queued.len());
+        response[..size].copy_from_slice(&queued[..size]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ch559::chip;
+    use crate::Ch559;
+
+    const UID: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+    // Connects to a CH559 with a bootloader of `version`, e.g. [2, 4, 0].
+    fn connect(version: [u8; 3]) -> (MockTransport, Ch559) {
+        let mock = MockTransport::new();
+        mock.push_response(&[0xa1, 0x00, 0x02, 0x00, 0x59, 0x11]);
+        let mut identify = [0; 30];
+        identify[0] = 0xa7;
+        identify[19..22].copy_from_slice(&version);
+        identify[22..26].copy_from_slice(&UID);
+        mock.push_response(&identify);
+        let ch559 = Ch559::with_transport(Box::new(mock.clone())).unwrap();
+        (mock, ch559)
+    }
+
+    #[test]
+    fn reset_key_v231() {
+        let (mock, mut ch559) = connect([2, 3, 1]);
+        // The key is the chip ID in the last byte, and its sum is 0x59.
+        mock.push_response(&[0xa3, 0x00, 0x02, 0x00, 0x59, 0x00]);
+        ch559.reset_key().unwrap();
+        let request = mock.requests().pop().unwrap();
+        assert_eq!(request.len(), 0x33);
+        assert_eq!(&request[..3], &[0xa3, 0x30, 0x00]);
+        assert!(request[3..].iter().all(|byte| *byte == 0x14));
+    }
+
+    #[test]
+    fn reset_key_v240() {
+        let (mock, mut ch559) = connect([2, 4, 0]);
+        // The key is the UID sum 0x14 in every byte, plus the chip ID in the
+        // last byte, i.e. 0x14 * 7 + 0x6d.
+        mock.push_response(&[0xa3, 0x00, 0x02, 0x00, 0xf9, 0x00]);
+        ch559.reset_key().unwrap();
+        let request = mock.requests().pop().unwrap();
+        assert_eq!(request.len(), 0x21);
+        assert_eq!(&request[..3], &[0xa3, 0x1e, 0x00]);
+        assert!(request[3..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn reset_key_checksum_mismatch() {
+        let (mock, mut ch559) = connect([2, 4, 0]);
+        mock.push_response(&[0xa3, 0x00, 0x02, 0x00, 0x59, 0x00]);
+        assert!(matches!(ch559.reset_key(), Err(Error::ResetKey)));
+    }
+
+    #[test]
+    fn write_verify_xors_data_with_key() {
+        let (mock, mut ch559) = connect([2, 4, 0]);
+        mock.push_response(&[0xa3, 0x00, 0x02, 0x00, 0xf9, 0x00]);
+        ch559.reset_key().unwrap();
+        mock.push_response(&[0xa5, 0x00, 0x02, 0x00, 0x00, 0x00]);
+        let data: Vec<u8> = (1..=10).collect();
+        ch559
+            .write_verify_in_range(0x1234, &data, true, false)
+            .unwrap();
+        let request = mock.requests().pop().unwrap();
+        // Data is padded with 0xff to 8 bytes units.
+        assert_eq!(
+            &request[..8],
+            &[0xa5, 0x15, 0x00, 0x34, 0x12, 0x00, 0x00, 0x10]
+        );
+        let mut key = [0x14; 8];
+        key[7] = 0x14 + chip::CH559.id;
+        for i in 0..16 {
+            let byte = data.get(i).copied().unwrap_or(0xff);
+            assert_eq!(request[8 + i], byte ^ key[i & 7], "at {}", i);
+        }
+    }
+
+    #[test]
+    fn write_verify_status() {
+        let (mock, mut ch559) = connect([2, 3, 1]);
+        mock.push_response(&[0xa3, 0x00, 0x02, 0x00, 0x59, 0x00]);
+        ch559.reset_key().unwrap();
+        // A non-zero status at response[4] fails the request.
+        mock.push_response(&[0xa5, 0x00, 0x02, 0x00, 0xfe, 0x00]);
+        let result = ch559.write_verify_in_range(0, &[0; 8], true, false);
+        assert!(matches!(result, Err(Error::Flash)));
+        mock.push_response(&[0xa6, 0x00, 0x02, 0x00, 0xf5, 0x00]);
+        let result = ch559.write_verify_in_range(0, &[0; 8], false, false);
+        assert!(matches!(result, Err(Error::Verify)));
+        mock.push_response(&[0xa6, 0x00, 0x02, 0x00, 0x00, 0x00]);
+        ch559
+            .write_verify_in_range(0, &[0; 8], false, false)
+            .unwrap();
+    }
+}
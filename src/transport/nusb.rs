@@ -0,0 +1,324 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures_lite::future::{block_on, or};
+use nusb::transfer::{
+    Completion, Control, ControlType, Direction, EndpointType, Recipient, RequestBuffer,
+};
+
+use crate::ch559::Error;
+#[cfg(feature = "async")]
+use crate::transport::AsyncTransport;
+use crate::transport::{Location, Selector, Transport};
+
+// Transfers time out in the same way as the libusb based transport does.
+const TIMEOUT: Duration = Duration::new(1, 0);
+
+// Pure Rust USB transport that does not depend on libusb.
+pub struct UsbTransport {
+    interface: nusb::Interface,
+    ep_in: u8,
+    ep_out: u8,
+}
+
+impl UsbTransport {
+    // Opens the first bootloader device found.
+    pub fn open() -> Result<Self, Error> {
+        UsbTransport::open_with(&Selector::default())
+    }
+
+    // Opens the bootloader device that `selector` chooses.
+    pub fn open_with(selector: &Selector) -> Result<Self, Error> {
+        let info = nusb::list_devices()?
+            .filter(|info| selector.matches_ids(info.vendor_id(), info.product_id()))
+            .filter(|info| selector.matches(&location(info)))
+            .nth(selector.index.unwrap_or(0))
+            .ok_or(Error::NotFound)?;
+        UsbTransport::new(open(&info)?)
+    }
+
+    // Lists bootloader devices that `selector` matches in enumeration order.
+    // `selector.index` is not taken into account.
+    pub fn list(selector: &Selector) -> Result<Vec<Location>, Error> {
+        Ok(nusb::list_devices()?
+            .filter(|info| selector.matches_ids(info.vendor_id(), info.product_id()))
+            .map(|info| location(&info))
+            .filter(|location| selector.matches(location))
+            .collect())
+    }
+
+    // Resets the port of the bootloader device that `selector` chooses, e.g.
+    // to recover endpoints that an aborted session left in a bad state.
+    pub fn reset(selector: &Selector) -> Result<(), Error> {
+        let info = nusb::list_devices()?
+            .filter(|info| selector.matches_ids(info.vendor_id(), info.product_id()))
+            .filter(|info| selector.matches(&location(info)))
+            .nth(selector.index.unwrap_or(0))
+            .ok_or(Error::NotFound)?;
+        open(&info)?.reset().map_err(|_| Error::Reset)
+    }
+
+    // Sends a vendor request without data to the first device that `selector`
+    // matches, e.g. to let an application jump to the bootloader.
+    pub fn send_vendor_request(selector: &Selector, request: u8) -> Result<(), Error> {
+        let info = nusb::list_devices()?
+            .filter(|info| selector.matches_ids(info.vendor_id(), info.product_id()))
+            .find(|info| selector.matches(&location(info)))
+            .ok_or(Error::NotFound)?;
+        let interface = open(&info)?
+            .detach_and_claim_interface(0)
+            .map_err(|_| Error::ClaimInterface)?;
+        let control = Control {
+            control_type: ControlType::Vendor,
+            recipient: Recipient::Device,
+            request,
+            value: 0,
+            index: 0,
+        };
+        // The device may leave the bus before it completes the request, so the
+        // result is not checked.
+        let _ = interface.control_out_blocking(control, &[], TIMEOUT);
+        Ok(())
+    }
+
+    // Port power switching needs libusb.
+    pub fn power_cycle(_port_path: &str, _off: core::time::Duration) -> Result<(), Error> {
+        Err(Error::NotSupported("nusb"))
+    }
+
+    pub fn backend() -> String {
+        String::from("nusb")
+    }
+
+    // Kernel driver queries need libusb.
+    pub fn kernel_driver_active(_selector: &Selector) -> Result<bool, Error> {
+        Err(Error::NotSupported("nusb"))
+    }
+
+    // Hotplug notifications need libusb.
+    pub fn watch(_selector: &Selector) -> Result<Receiver<Location>, Error> {
+        Err(Error::NotSupported("nusb"))
+    }
+
+    pub fn new(device: nusb::Device) -> Result<Self, Error> {
+        let config = device
+            .active_configuration()
+            .map_err(|_| Error::CheckConfiguration)?;
+        let Some(desc) = config.interface_alt_settings().next() else {
+            return Err(Error::CheckInterface);
+        };
+        let mut ep_in = None;
+        let mut ep_out = None;
+        for ep in desc.endpoints() {
+            if ep.transfer_type() != EndpointType::Bulk {
+                return Err(Error::DetectEp);
+            }
+            match ep.direction() {
+                Direction::In => ep_in = Some(ep.address()),
+                Direction::Out => ep_out = Some(ep.address()),
+            }
+        }
+        let (Some(ep_in), Some(ep_out)) = (ep_in, ep_out) else {
+            return Err(Error::DetectEp);
+        };
+        let interface =
+            device
+                .claim_interface(desc.interface_number())
+                .map_err(|error| match error.kind() {
+                    std::io::ErrorKind::PermissionDenied => Error::AccessDenied,
+                    std::io::ErrorKind::Unsupported => Error::NoDriver,
+                    _ => Error::ClaimInterface,
+                })?;
+        Ok(UsbTransport {
+            interface,
+            ep_in,
+            ep_out,
+        })
+    }
+}
+
+// Tells a device that can not be accessed from one without a usable driver.
+fn open(info: &nusb::DeviceInfo) -> Result<nusb::Device, Error> {
+    info.open().map_err(|error| match error.kind() {
+        std::io::ErrorKind::PermissionDenied => Error::AccessDenied,
+        std::io::ErrorKind::Unsupported | std::io::ErrorKind::NotFound => Error::NoDriver,
+        _ => Error::Io(error),
+    })
+}
+
+// Waits for `transfer` up to `TIMEOUT`. The transfer is cancelled on timeout
+// as nusb cancels transfers that are dropped.
+async fn with_timeout<T>(transfer: impl Future<Output = Completion<T>>) -> Option<Completion<T>> {
+    or(async { Some(transfer.await) }, async {
+        Timer::new(TIMEOUT).await;
+        None
+    })
+    .await
+}
+
+// Resolves after a duration. nusb has no timer, so a thread wakes the task,
+// and it quits early once the timer is dropped.
+struct Timer {
+    deadline: Instant,
+    waker: Arc<Mutex<Option<Waker>>>,
+    dropped: Arc<AtomicBool>,
+    thread: Option<std::thread::Thread>,
+}
+
+impl Timer {
+    fn new(duration: Duration) -> Self {
+        Timer {
+            deadline: Instant::now() + duration,
+            waker: Arc::new(Mutex::new(None)),
+            dropped: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        }
+    }
+}
+
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        if self.thread.is_none() {
+            let deadline = self.deadline;
+            let waker = self.waker.clone();
+            let dropped = self.dropped.clone();
+            let handle = std::thread::spawn(move || loop {
+                if dropped.load(Ordering::Relaxed) {
+                    return;
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    if let Some(waker) = waker.lock().unwrap().take() {
+                        waker.wake();
+                    }
+                    return;
+                }
+                std::thread::park_timeout(deadline - now);
+            });
+            self.thread = Some(handle.thread().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.dropped.store(true, Ordering::Relaxed);
+        if let Some(thread) = &self.thread {
+            thread.unpark();
+        }
+    }
+}
+
+fn location(info: &nusb::DeviceInfo) -> Location {
+    #[allow(unused_mut)]
+    let mut location = Location::new(info.bus_number(), info.device_address(), &[]);
+    // The sysfs directory is named after the port path, e.g. "1-2.4". Other
+    // platforms leave it to the bus number.
+    #[cfg(target_os = "linux")]
+    if let Some(name) = info.sysfs_path().file_name() {
+        location.port_path = name.to_string_lossy().into_owned();
+    }
+    location
+}
+
+impl Transport for UsbTransport {
+    fn send(&mut self, request: &[u8]) -> Result<(), Error> {
+        let transfer = self.interface.bulk_out(self.ep_out, request.to_vec());
+        let completion = block_on(with_timeout(transfer)).ok_or(Error::BulkWrite)?;
+        completion.status.map_err(|_| Error::BulkWrite)?;
+        if completion.data.actual_length() != request.len() {
+            return Err(Error::BulkWriteAll);
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, response: &mut [u8]) -> Result<(), Error> {
+        // Requests a full packet at least so that a response never overflows.
+        let size = response.len().max(64);
+        let transfer = self.interface.bulk_in(self.ep_in, RequestBuffer::new(size));
+        let completion = block_on(with_timeout(transfer))
+            .ok_or_else(|| Error::BulkRead(String::from("timeout")))?;
+        completion
+            .status
+            .map_err(|e| Error::BulkRead(e.to_string()))?;
+        // Callers index the response, so a short one is refused.
+        if completion.data.len() < response.len() {
+            return Err(Error::BulkRead(format!(
+                "{} of {} bytes",
+                completion.data.len(),
+                response.len()
+            )));
+        }
+        let size = response.len();
+        response.copy_from_slice(&completion.data[..size]);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncTransport for UsbTransport {
+    async fn send(&mut self, request: Vec<u8>) -> Result<(), Error> {
+        let size = request.len();
+        let transfer = self.interface.bulk_out(self.ep_out, request);
+        let completion = with_timeout(transfer).await.ok_or(Error::BulkWrite)?;
+        completion.status.map_err(|_| Error::BulkWrite)?;
+        if completion.data.actual_length() != size {
+            return Err(Error::BulkWriteAll);
+        }
+        Ok(())
+    }
+
+    async fn receive(&mut self, size: usize) -> Result<Vec<u8>, Error> {
+        let request = RequestBuffer::new(size.max(64));
+        let transfer = self.interface.bulk_in(self.ep_in, request);
+        let completion = with_timeout(transfer)
+            .await
+            .ok_or_else(|| Error::BulkRead(String::from("timeout")))?;
+        completion
+            .status
+            .map_err(|e| Error::BulkRead(e.to_string()))?;
+        let mut response = completion.data;
+        response.truncate(size);
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfer_times_out() {
+        let start = Instant::now();
+        let transfer = std::future::pending::<Completion<Vec<u8>>>();
+        assert!(block_on(with_timeout(transfer)).is_none());
+        assert!(start.elapsed() >= TIMEOUT);
+    }
+
+    #[test]
+    fn transfer_completes() {
+        let transfer = async {
+            Completion {
+                data: vec![1],
+                status: Ok(()),
+            }
+        };
+        let completion = block_on(with_timeout(transfer)).unwrap();
+        assert_eq!(completion.data, vec![1]);
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use crate::ch559::Error;
+#[cfg(feature = "serial")]
+use crate::transport::Transport;
+
+pub const BAUD_RATE: u32 = 57600;
+
+#[cfg(feature = "serial")]
+pub struct SerialTransport {
+    port: Box<dyn serialport::SerialPort>,
+}
+
+// A step to drive control lines that boards for auto-programming wire to the
+// boot pin and the reset line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineStep {
+    Dtr(bool),
+    Rts(bool),
+    // Milliseconds to wait.
+    Sleep(u64),
+}
+
+// Parses comma separated steps, e.g. "D0R1,100,R0,50,D1". A step is DTR (D)
+// and RTS (R) levels, or milliseconds to wait.
+pub fn parse_line_steps(text: &str) -> Result<Vec<LineStep>, Error> {
+    let mut steps = Vec::new();
+    for item in text.split(',') {
+        if let Ok(milliseconds) = item.parse() {
+            steps.push(LineStep::Sleep(milliseconds));
+            continue;
+        }
+        let bytes = item.as_bytes();
+        if bytes.is_empty() || bytes.len() % 2 != 0 {
+            return Err(Error::LineSteps(item.to_string()));
+        }
+        for pair in bytes.chunks_exact(2) {
+            let level = match pair[1] {
+                b'0' => false,
+                b'1' => true,
+                _ => return Err(Error::LineSteps(item.to_string())),
+            };
+            steps.push(match pair[0].to_ascii_uppercase() {
+                b'D' => LineStep::Dtr(level),
+                b'R' => LineStep::Rts(level),
+                _ => return Err(Error::LineSteps(item.to_string())),
+            });
+        }
+    }
+    Ok(steps)
+}
+
+// USB IDs of WCH USB serial adapters.
+#[cfg(feature = "serial")]
+const ADAPTERS: [(u16, u16, &str); 4] = [
+    (0x1a86, 0x7523, "CH340"),
+    (0x1a86, 0x5523, "CH341"),
+    (0x1a86, 0x55d3, "CH343"),
+    (0x1a86, 0x55d4, "CH9102"),
+];
+
+// Lists serial ports on WCH USB serial adapters, e.g. "COM3" on Windows, with
+// the adapter name.
+#[cfg(feature = "serial")]
+pub fn find_ports() -> Result<Vec<(String, &'static str)>, Error> {
+    let ports = serialport::available_ports().map_err(Error::SerialOpen)?;
+    Ok(ports
+        .into_iter()
+        .filter_map(|port| {
+            let serialport::SerialPortType::UsbPort(usb) = port.port_type else {
+                return None;
+            };
+            ADAPTERS
+                .iter()
+                .find(|(vid, pid, _)| *vid == usb.vid && *pid == usb.pid)
+                .map(|(_, _, name)| (port.port_name, *name))
+        })
+        .collect())
+}
+
+#[cfg(not(feature = "serial"))]
+pub fn find_ports() -> Result<Vec<(String, &'static str)>, Error> {
+    Err(Error::NotSupported("builds without the serial feature"))
+}
+
+#[cfg(feature = "serial")]
+impl SerialTransport {
+    // Opens a serial port, e.g. "/dev/ttyUSB0".
+    pub fn open(path: &str) -> Result<Self, Error> {
+        SerialTransport::open_with(path, &[])
+    }
+
+    // Opens a serial port, and drives control lines as `steps` specify, e.g.
+    // to reset the board into the bootloader.
+    pub fn open_with(path: &str, steps: &[LineStep]) -> Result<Self, Error> {
+        let mut port = serialport::new(path, BAUD_RATE)
+            .timeout(core::time::Duration::new(1, 0))
+            .open()
+            .map_err(Error::SerialOpen)?;
+        for step in steps {
+            match step {
+                LineStep::Dtr(level) => port.write_data_terminal_ready(*level),
+                LineStep::Rts(level) => port.write_request_to_send(*level),
+                LineStep::Sleep(milliseconds) => {
+                    std::thread::sleep(core::time::Duration::from_millis(*milliseconds));
+                    Ok(())
+                }
+            }
+            .map_err(Error::SerialOpen)?;
+        }
+        if !steps.is_empty() {
+            // Drops what the board may send while it is reset.
+            let _ = port.clear(serialport::ClearBuffer::All);
+        }
+        Ok(SerialTransport { port })
+    }
+}
+
+#[cfg(feature = "serial")]
+impl Transport for SerialTransport {
+    fn send(&mut self, request: &[u8]) -> Result<(), Error> {
+        send(self.port.as_mut(), request)
+    }
+
+    fn receive(&mut self, response: &mut [u8]) -> Result<(), Error> {
+        receive(self.port.as_mut(), response)
+    }
+}
+
+// Serial ISP wraps each USB request with a 0x57 0xab header and a trailing
+// checksum. Responses are wrapped with a 0x55 0xaa header in the same way.
+#[cfg(feature = "serial")]
+fn send(port: &mut dyn serialport::SerialPort, request: &[u8]) -> Result<(), Error> {
+    let mut frame: Vec<u8> = Vec::with_capacity(request.len() + 3);
+    frame.push(0x57);
+    frame.push(0xab);
+    frame.extend_from_slice(request);
+    frame.push(checksum(request));
+    port.write_all(&frame)?;
+    port.flush()?;
+    Ok(())
+}
+
+#[cfg(feature = "serial")]
+fn receive(port: &mut dyn serialport::SerialPort, response: &mut [u8]) -> Result<(), Error> {
+    let mut header: [u8; 6] = [0; 6];
+    port.read_exact(&mut header)?;
+    if header[0] != 0x55 || header[1] != 0xaa {
+        return Err(Error::SerialResponse);
+    }
+    let length = header[4] as usize | (header[5] as usize) << 8;
+    let mut body: Vec<u8> = vec![0; 4 + length + 1];
+    body[0..4].copy_from_slice(&header[2..6]);
+    port.read_exact(&mut body[4..])?;
+    let sum = body.pop().unwrap_or(0);
+    if sum != checksum(&body) {
+        return Err(Error::SerialResponse);
+    }
+    let size = response.len().min(body.len());
+    response[..size].copy_from_slice(&body[..size]);
+    Ok(())
+}
+
+#[cfg(feature = "serial")]
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |a, b| a.wrapping_add(*b))
+}
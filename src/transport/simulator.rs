@@ -0,0 +1,524 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::ch559::chip::Chip;
+use crate::ch559::Error;
+#[cfg(feature = "async")]
+use crate::transport::AsyncTransport;
+use crate::transport::Transport;
+
+struct State {
+    chip: &'static Chip,
+    flash: Vec<u8>,
+    config: u16,
+    key: [u8; 8],
+    booted: bool,
+    programs: usize,
+    responses: VecDeque<Vec<u8>>,
+}
+
+// Simulates a v2.31 bootloader against an in-memory flash. Clones share the
+// same flash so that a clone can inspect it after the original is handed over
+// to `Ch559`.
+#[derive(Clone)]
+pub struct Simulator {
+    state: Arc<Mutex<State>>,
+}
+
+impl Simulator {
+    pub fn new(chip: &'static Chip) -> Self {
+        let state = State {
+            chip,
+            flash: vec![0xff; 0x10000],
+            config: 0x4eff,
+            key: [0; 8],
+            booted: false,
+            programs: 0,
+            responses: VecDeque::new(),
+        };
+        Simulator {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    // Returns a copy of the flash contents in the code address space.
+    pub fn flash(&self) -> Vec<u8> {
+        self.state.lock().unwrap().flash.clone()
+    }
+
+    // Returns BOOT_CFG[15:8].
+    pub fn config(&self) -> u8 {
+        self.state.lock().unwrap().config.to_be_bytes()[0]
+    }
+
+    // Returns BOOT_CFG[15:0].
+    pub fn config_word(&self) -> u16 {
+        self.state.lock().unwrap().config
+    }
+
+    pub fn booted(&self) -> bool {
+        self.state.lock().unwrap().booted
+    }
+
+    // Returns the number of program requests, i.e. chunks written so far.
+    pub fn programs(&self) -> usize {
+        self.state.lock().unwrap().programs
+    }
+}
+
+impl State {
+    fn respond(&mut self, command: u8, payload: &[u8]) {
+        let mut response = vec![command, 0x00, payload.len() as u8, 0x00];
+        response.extend_from_slice(payload);
+        self.responses.push_back(response);
+    }
+
+    // Programs decoded data. Flash bits can only be cleared by programming.
+    fn program(&mut self, addr: usize, data: &[u8]) -> u8 {
+        if addr + data.len() > self.flash.len() {
+            return 0xfe;
+        }
+        self.programs += 1;
+        for (i, byte) in data.iter().enumerate() {
+            self.flash[addr + i] &= byte ^ self.key[i & 7];
+        }
+        0x00
+    }
+
+    fn verify(&self, addr: usize, data: &[u8]) -> u8 {
+        if addr + data.len() > self.flash.len() {
+            return 0xfe;
+        }
+        let matched = data
+            .iter()
+            .enumerate()
+            .all(|(i, byte)| self.flash[addr + i] == byte ^ self.key[i & 7]);
+        if matched {
+            0x00
+        } else {
+            0xf5
+        }
+    }
+
+    fn handle(&mut self, request: &[u8]) {
+        let command = request[0];
+        let addr = request
+            .get(3..7)
+            .map(|a| u32::from_le_bytes([a[0], a[1], a[2], a[3]]) as usize)
+            .unwrap_or(0);
+        let data = request.get(8..).unwrap_or(&[]);
+        let chip = self.chip;
+        match command {
+            0xa1 => self.respond(command, &[chip.id, chip.device_type]),
+            0xa2 => self.booted = true,
+            0xa3 => {
+                self.key = [0; 8];
+                self.key[7] = chip.id;
+                self.respond(command, &[chip.id, 0x00]);
+            }
+            0xa4 => {
+                // The sector count is in little endian, and is 1 or 4 bytes long.
+                let count = request
+                    .iter()
+                    .skip(3)
+                    .take(request[1] as usize)
+                    .rev()
+                    .fold(0, |a, b| (a << 8) | *b as usize);
+                let size = (count << 10).min(self.flash.len());
+                self.flash[0..size].fill(0xff);
+                self.respond(command, &[0x00, 0x00]);
+            }
+            0xa5 => {
+                let status = self.program(addr, data);
+                self.respond(command, &[status, 0x00]);
+            }
+            0xa6 => {
+                let status = self.verify(addr, data);
+                self.respond(command, &[status, 0x00]);
+            }
+            0xa7 => {
+                let mut payload = [0u8; 26];
+                [payload[11], payload[10]] = self.config.to_be_bytes();
+                payload[14..18].copy_from_slice(&[0x00, 0x02, 0x03, 0x01]);
+                payload[18..22].copy_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+                self.respond(command, &payload);
+            }
+            0xa8 => {
+                if let Some(config) = request.get(13..15) {
+                    self.config = u16::from_be_bytes([config[1], config[0]]);
+                }
+                self.respond(command, &[0x00, 0x00]);
+            }
+            0xa9 => {
+                let start = chip.data_addr;
+                self.flash[start..start + chip.data_size].fill(0xff);
+                self.respond(command, &[0x00, 0x00]);
+            }
+            0xaa => {
+                let status = self.program(chip.data_addr + addr, data);
+                self.respond(command, &[status, 0x00]);
+            }
+            0xab => {
+                let addr = chip.data_addr + (addr & 0xffff);
+                let size = request.get(7).copied().unwrap_or(0) as usize;
+                if addr + size > self.flash.len() {
+                    self.respond(command, &[0xfe, 0x00]);
+                    return;
+                }
+                let mut payload = vec![0x00, 0x00];
+                payload.extend_from_slice(&self.flash[addr..addr + size]);
+                self.respond(command, &payload);
+            }
+            _ => self.respond(command, &[0xfe, 0x00]),
+        }
+    }
+}
+
+impl Transport for Simulator {
+    fn send(&mut self, request: &[u8]) -> Result<(), Error> {
+        if request.is_empty() {
+            return Err(Error::BulkWrite);
+        }
+        self.state.lock().unwrap().handle(request);
+        Ok(())
+    }
+
+    fn receive(&mut self, response: &mut [u8]) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let Some(queued) = state.responses.pop_front() else {
+            return Err(Error::BulkRead(String::from("timeout")));
+        };
+        let size = response.len().min(queued.len());
+        response[..size].copy_from_slice(&queued[..size]);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncTransport for Simulator {
+    async fn send(&mut self, request: Vec<u8>) -> Result<(), Error> {
+        Transport::send(self, &request)
+    }
+
+    async fn receive(&mut self, size: usize) -> Result<Vec<u8>, Error> {
+        let mut response = vec![0; size];
+        Transport::receive(self, &mut response)?;
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ch559::chip;
+    use crate::ch559::Output;
+    use crate::{Ch559, Fill, Image};
+
+    fn connect() -> (Simulator, Ch559) {
+        let simulator = Simulator::new(&chip::CH559);
+        let mut ch559 = Ch559::with_transport(Box::new(simulator.clone())).unwrap();
+        ch559.set_progress(Output::Stderr);
+        (simulator, ch559)
+    }
+
+    #[test]
+    fn write_and_compare() {
+        let (simulator, mut ch559) = connect();
+        // Runs over a 0x38 bytes chunk boundary, and ends in a partial one.
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        ch559.erase().unwrap();
+        let image = Image::from_binary(data.clone());
+        ch559
+            .write_image(image.clone(), true, false, false)
+            .unwrap();
+        ch559
+            .write_image(image.clone(), false, false, false)
+            .unwrap();
+        assert_eq!(&simulator.flash()[..300], &data[..]);
+        assert!(simulator.flash()[300..].iter().all(|byte| *byte == 0xff));
+        assert!(ch559.verify_image(image, false, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn compare_reports_differences() {
+        let (_, mut ch559) = connect();
+        ch559.erase().unwrap();
+        let mut image = Image::new();
+        image.set(0x120, &[0, 0, 0, 1]);
+        image.set(0x200, &[5]);
+        assert!(ch559
+            .write_image(image.clone(), false, false, false)
+            .is_err());
+        // The program area is compared in 8 bytes units.
+        let mismatch = ch559.verify_image(image, false, false).unwrap();
+        assert_eq!(mismatch.chunks, vec![0x118, 0x1f8]);
+        assert_eq!(mismatch.bytes, 9);
+    }
+
+    #[test]
+    fn fullfill() {
+        let (simulator, mut ch559) = connect();
+        ch559.erase().unwrap();
+        ch559.set_fill(Fill::Byte(0x5a));
+        let image = Image::from_binary(vec![1, 2, 3]);
+        ch559.write_image(image.clone(), true, false, true).unwrap();
+        ch559.write_image(image, false, false, true).unwrap();
+        let flash = simulator.flash();
+        assert_eq!(&flash[..3], &[1, 2, 3]);
+        assert!(flash[3..chip::CH559.code_size]
+            .iter()
+            .all(|byte| *byte == 0x5a));
+        // The data area is preserved.
+        assert!(flash[chip::CH559.data_addr..]
+            .iter()
+            .all(|byte| *byte == 0xff));
+        assert_eq!(ch559.written(false).len(), chip::CH559.code_size);
+    }
+
+    #[test]
+    fn random_fill_is_reproducible() {
+        let (simulator, mut ch559) = connect();
+        ch559.erase().unwrap();
+        ch559.set_seed(7);
+        let image = Image::from_binary(vec![0; 8]);
+        ch559.write_image(image.clone(), true, false, true).unwrap();
+        // The same seed fills the same values.
+        ch559
+            .write_image(image.clone(), false, false, true)
+            .unwrap();
+        assert_eq!(
+            ch559.written(false),
+            &simulator.flash()[..chip::CH559.code_size]
+        );
+        ch559.set_seed(8);
+        assert!(ch559.write_image(image, false, false, true).is_err());
+    }
+
+    #[test]
+    fn compare_specified_skips_fill() {
+        let (_, mut ch559) = connect();
+        ch559.erase().unwrap();
+        ch559.erase_data().unwrap();
+        ch559.set_seed(7);
+        let image = Image::from_binary(vec![0; 8]);
+        ch559.write_image(image.clone(), true, false, true).unwrap();
+        let data = Image::from_binary(vec![1, 2, 3]);
+        ch559.write_image(data.clone(), true, true, true).unwrap();
+        // Another seed fills other values, but file bytes still match.
+        ch559.set_seed(8);
+        assert!(ch559.compare_specified(image, false).unwrap());
+        assert!(ch559.compare_specified(data, true).unwrap());
+        assert!(!ch559
+            .compare_specified(Image::from_binary(vec![1; 8]), false)
+            .unwrap());
+        assert!(!ch559
+            .compare_specified(Image::from_binary(vec![1, 2, 4]), true)
+            .unwrap());
+    }
+
+    #[test]
+    fn data_read_back() {
+        let (simulator, mut ch559) = connect();
+        let data: Vec<u8> = (0..chip::CH559.data_size).map(|i| (i * 7) as u8).collect();
+        ch559.erase_data().unwrap();
+        ch559
+            .write_image(Image::from_binary(data.clone()), true, true, false)
+            .unwrap();
+        let image = ch559.read_data_image().unwrap();
+        assert_eq!(image.len(), data.len());
+        assert!((0..data.len()).all(|addr| image.get(addr) == Some(data[addr])));
+        let part = ch559.read_data_range(0x3c0, 0x20).unwrap();
+        assert_eq!(part.get(0), Some(data[0x3c0]));
+        assert!(ch559.read_data_range(0x3f0, 0x20).is_err());
+        let start = chip::CH559.data_addr;
+        assert_eq!(&simulator.flash()[start..start + data.len()], &data[..]);
+    }
+
+    #[test]
+    fn data_write_is_read_back() {
+        let (_, mut ch559) = connect();
+        ch559.erase_data().unwrap();
+        ch559
+            .write_image(Image::from_binary(vec![0; 0x400]), true, true, false)
+            .unwrap();
+        // Programming can not set bits, so the read back detects it.
+        let error = ch559
+            .write_image(Image::from_binary(vec![0xff; 0x400]), true, true, false)
+            .unwrap_err();
+        assert!(matches!(error, Error::ReadBack(0xf000)));
+    }
+
+    #[test]
+    fn out_of_range_read_is_an_error() {
+        let (_, mut ch559) = connect();
+        ch559.reset_key().unwrap();
+        let mut buffer = [0; 0x38];
+        assert!(matches!(
+            ch559.read_data_in_range(0x0ff0, &mut buffer),
+            Err(Error::Read)
+        ));
+    }
+
+    #[test]
+    fn pattern_fill() {
+        let (simulator, mut ch559) = connect();
+        ch559.erase().unwrap();
+        ch559.set_fill(Fill::Pattern(vec![0xde, 0xad]));
+        let image = Image::from_binary(vec![1, 2, 3]);
+        ch559.write_image(image.clone(), true, false, true).unwrap();
+        ch559.write_image(image, false, false, true).unwrap();
+        // The pattern is aligned to address 0.
+        let flash = simulator.flash();
+        assert_eq!(&flash[..6], &[1, 2, 3, 0xad, 0xde, 0xad]);
+        assert_eq!(flash[chip::CH559.code_size - 1], 0xad);
+    }
+
+    #[test]
+    fn code_over_data_area() {
+        let (simulator, mut ch559) = connect();
+        ch559.erase().unwrap();
+        ch559.erase_data().unwrap();
+        let mut image = Image::new();
+        image.set(chip::CH559.code_size, &[1, 2, 3, 4]);
+        // The data area is guarded by default.
+        assert!(matches!(
+            ch559.write_image(image.clone(), true, false, true),
+            Err(Error::DataOverwrite(0xf000))
+        ));
+        assert_eq!(simulator.programs(), 0);
+        // Fill stops at the end of the code, and leaves the rest of the data
+        // area.
+        ch559.set_allow_data_overwrite(true);
+        ch559.set_fill(Fill::Byte(0x5a));
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let sink = warnings.clone();
+        ch559.set_warning(Box::new(move |message| {
+            sink.lock().unwrap().push(message.to_string())
+        }));
+        ch559.write_image(image.clone(), true, false, true).unwrap();
+        assert_eq!(warnings.lock().unwrap().len(), 1);
+        let flash = simulator.flash();
+        assert_eq!(flash[0], 0x5a);
+        assert_eq!(&flash[0xf000..0xf005], &[1, 2, 3, 4, 0xff]);
+        assert_eq!(ch559.written(false).len(), 0xf004);
+        // Filling over the data area fills up to its end.
+        ch559.set_fill_over_data(true);
+        ch559.write_image(image, true, false, true).unwrap();
+        assert_eq!(ch559.written(false).len(), 0xf400);
+        assert_eq!(simulator.flash()[0xf3ff], 0x5a);
+    }
+
+    #[test]
+    fn sparse_write_skips_chunks() {
+        let (simulator, mut ch559) = connect();
+        ch559.erase().unwrap();
+        let mut image = Image::new();
+        image.set(0, &[1]);
+        image.set(0x1000, &[2]);
+        ch559
+            .write_image(image.clone(), true, false, false)
+            .unwrap();
+        // Only chunks that contain specified bytes are programmed.
+        assert_eq!(simulator.programs(), 2);
+        // The image is kept without fill for logs.
+        assert_eq!(ch559.image(false).len(), 0x1001);
+        assert_eq!(ch559.image(false)[..2], [1, 0xff]);
+        ch559.write_image(image, false, false, false).unwrap();
+        let flash = simulator.flash();
+        assert_eq!((flash[0], flash[0x1000]), (1, 2));
+        // Chunks of 0xff only are skipped as well.
+        let image = Image::from_binary(vec![0xff; 0x100]);
+        ch559.write_image(image, true, false, false).unwrap();
+        assert_eq!(simulator.programs(), 2);
+    }
+
+    #[test]
+    fn compare_continues_and_masks() {
+        let (_, mut ch559) = connect();
+        ch559.erase_data().unwrap();
+        let data: Vec<u8> = (0..0x400).map(|i| i as u8).collect();
+        ch559
+            .write_image(Image::from_binary(data.clone()), true, true, false)
+            .unwrap();
+        let mut expected = data.clone();
+        expected[0x10] = 0xaa;
+        expected[0x300] = 0xbb;
+        let mismatch = ch559
+            .verify_image(Image::from_binary(expected.clone()), true, false)
+            .unwrap();
+        // Both differences are reported with their actual values.
+        assert_eq!(mismatch.chunks, vec![0xf000, 0xf2d8]);
+        assert_eq!(mismatch.bytes, 2);
+        assert_eq!(mismatch.differences[1].address, 0xf300);
+        assert_eq!(mismatch.differences[1].expected, 0xbb);
+        assert_eq!(mismatch.differences[1].actual, Some(0));
+        // Masked bytes are not compared.
+        ch559.set_ignore(vec![(0xf010, 1), (0xf300, 1)]);
+        let mismatch = ch559
+            .verify_image(Image::from_binary(expected.clone()), true, false)
+            .unwrap();
+        assert!(mismatch.chunks.is_empty());
+        ch559
+            .write_image(Image::from_binary(expected), false, true, false)
+            .unwrap();
+    }
+
+    #[test]
+    fn patch_keeps_other_bytes() {
+        let (simulator, mut ch559) = connect();
+        ch559.erase_data().unwrap();
+        let data: Vec<u8> = (0..0x400).map(|i| i as u8).collect();
+        ch559
+            .write_image(Image::from_binary(data.clone()), true, true, false)
+            .unwrap();
+        let programs = simulator.programs();
+        let mut patch = Image::new();
+        patch.set(0, &[0x00]);
+        patch.set(2, &[0x00]);
+        ch559.write_data_image_at(0x100, &patch).unwrap();
+        // Clearing bits needs no erase, and only the chunk is programmed.
+        assert_eq!(simulator.programs(), programs + 1);
+        let flash = &simulator.flash()[0xf000..0xf400];
+        assert_eq!(&flash[0x100..0x103], &[0x00, 0x01, 0x00]);
+        assert_eq!(&flash[0x103..], &data[0x103..]);
+        // Setting bits erases and rewrites the whole data area.
+        ch559.write_data_at(0x3ff, &[0xff]).unwrap();
+        let flash = &simulator.flash()[0xf000..0xf400];
+        assert_eq!(flash[0x3ff], 0xff);
+        assert_eq!(&flash[0x103..0x3ff], &data[0x103..0x3ff]);
+        assert!(ch559.write_data_at(0x3ff, &[0, 0]).is_err());
+    }
+
+    #[test]
+    fn risky_config_needs_allow() {
+        let (simulator, mut ch559) = connect();
+        // Clearing No_Boot_Load is refused unless allowed.
+        assert!(matches!(
+            ch559.write_config(0x0e),
+            Err(Error::RiskyConfig(0x0e))
+        ));
+        assert_eq!(simulator.config(), 0x4e);
+        ch559.set_allow_risky_config(true);
+        ch559.write_config(0x0e).unwrap();
+        assert_eq!(simulator.config(), 0x0e);
+    }
+
+    #[test]
+    fn protect_and_unprotect() {
+        let (simulator, mut ch559) = connect();
+        ch559.write_config(0xce).unwrap();
+        ch559.protect().unwrap();
+        assert_eq!(simulator.config() & chip::CODE_PROTECT, 0);
+        ch559.erase().unwrap();
+        ch559
+            .write_image(Image::from_binary(vec![0; 8]), true, false, false)
+            .unwrap();
+        ch559.unprotect().unwrap();
+        // Both areas are erased before the protection is removed.
+        assert_eq!(simulator.config() & chip::CODE_PROTECT, chip::CODE_PROTECT);
+        assert!(simulator.flash().iter().all(|byte| *byte == 0xff));
+    }
+}
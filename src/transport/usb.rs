@@ -0,0 +1,319 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use rusb::{Hotplug, HotplugBuilder, UsbContext};
+
+use crate::ch559::Error;
+use crate::transport::{Location, Selector, Transport, PID, VID};
+
+// USB hub class, requests, and the port feature to switch the port power.
+const HUB_CLASS: u8 = 0x09;
+const CLEAR_FEATURE: u8 = 0x01;
+const SET_FEATURE: u8 = 0x03;
+const PORT_POWER: u16 = 0x08;
+
+pub struct UsbTransport {
+    handle: rusb::DeviceHandle<rusb::GlobalContext>,
+    ep_in: u8,
+    ep_out: u8,
+}
+
+impl UsbTransport {
+    // Opens the first bootloader device found.
+    pub fn open() -> Result<Self, Error> {
+        UsbTransport::open_with(&Selector::default())
+    }
+
+    // Opens the bootloader device that `selector` chooses.
+    pub fn open_with(selector: &Selector) -> Result<Self, Error> {
+        let (device, _) = UsbTransport::devices(selector)?
+            .into_iter()
+            .nth(selector.index.unwrap_or(0))
+            .ok_or(Error::NotFound)?;
+        let handle = device.open().map_err(open_error)?;
+        UsbTransport::new(handle)
+    }
+
+    // Lists bootloader devices that `selector` matches in enumeration order.
+    // `selector.index` is not taken into account.
+    pub fn list(selector: &Selector) -> Result<Vec<Location>, Error> {
+        let devices = UsbTransport::devices(selector)?;
+        Ok(devices.into_iter().map(|(_, location)| location).collect())
+    }
+
+    // Resets the port of the bootloader device that `selector` chooses, e.g.
+    // to recover endpoints that an aborted session left in a bad state.
+    pub fn reset(selector: &Selector) -> Result<(), Error> {
+        let (device, _) = UsbTransport::devices(selector)?
+            .into_iter()
+            .nth(selector.index.unwrap_or(0))
+            .ok_or(Error::NotFound)?;
+        let handle = device.open().map_err(open_error)?;
+        match handle.reset() {
+            // The device is enumerated again after the reset.
+            Ok(()) | Err(rusb::Error::NotFound) => Ok(()),
+            Err(_) => Err(Error::Reset),
+        }
+    }
+
+    // Sends a vendor request without data to the first device that `selector`
+    // matches, e.g. to let an application jump to the bootloader.
+    pub fn send_vendor_request(selector: &Selector, request: u8) -> Result<(), Error> {
+        let (device, _) = UsbTransport::devices(selector)?
+            .into_iter()
+            .next()
+            .ok_or(Error::NotFound)?;
+        let handle = device.open().map_err(open_error)?;
+        let request_type = rusb::request_type(
+            rusb::Direction::Out,
+            rusb::RequestType::Vendor,
+            rusb::Recipient::Device,
+        );
+        // The device may leave the bus before it completes the request, so the
+        // result is not checked.
+        let _ = handle.write_control(
+            request_type,
+            request,
+            0,
+            0,
+            &[],
+            core::time::Duration::new(1, 0),
+        );
+        Ok(())
+    }
+
+    // Turns off the power of the hub port at `port_path`, e.g. "1-2.4", for
+    // `off`, and turns it on again. Only hubs that switch power per port can
+    // do this.
+    pub fn power_cycle(port_path: &str, off: core::time::Duration) -> Result<(), Error> {
+        let (hub_path, port) = match port_path.rsplit_once('.') {
+            Some((hub_path, port)) => (hub_path.to_string(), port),
+            None => {
+                let (bus, port) = port_path.split_once('-').ok_or(Error::NotFound)?;
+                (format!("{}-", bus), port)
+            }
+        };
+        let port: u16 = port.parse().map_err(|_| Error::NotFound)?;
+        let devices = rusb::devices().map_err(|_| Error::ListDevices)?;
+        let hub = devices
+            .iter()
+            .filter(|device| {
+                device
+                    .device_descriptor()
+                    .is_ok_and(|desc| desc.class_code() == HUB_CLASS)
+            })
+            .find(|device| {
+                let ports = device.port_numbers().unwrap_or_default();
+                Location::new(device.bus_number(), device.address(), &ports).port_path == hub_path
+            })
+            .ok_or(Error::NotFound)?;
+        let handle = hub.open().map_err(open_error)?;
+        let request_type = rusb::request_type(
+            rusb::Direction::Out,
+            rusb::RequestType::Class,
+            rusb::Recipient::Other,
+        );
+        let timeout = core::time::Duration::new(1, 0);
+        handle
+            .write_control(request_type, CLEAR_FEATURE, PORT_POWER, port, &[], timeout)
+            .map_err(|_| Error::PortPower)?;
+        std::thread::sleep(off);
+        handle
+            .write_control(request_type, SET_FEATURE, PORT_POWER, port, &[], timeout)
+            .map_err(|_| Error::PortPower)?;
+        Ok(())
+    }
+
+    // Returns the USB library in use and its version.
+    pub fn backend() -> String {
+        let version = rusb::version();
+        format!(
+            "libusb {}.{}.{}",
+            version.major(),
+            version.minor(),
+            version.micro()
+        )
+    }
+
+    // Returns true if a kernel driver is bound to the interface of the
+    // bootloader device that `selector` chooses, and blocks claiming it.
+    pub fn kernel_driver_active(selector: &Selector) -> Result<bool, Error> {
+        let (device, _) = UsbTransport::devices(selector)?
+            .into_iter()
+            .nth(selector.index.unwrap_or(0))
+            .ok_or(Error::NotFound)?;
+        let handle = device.open().map_err(open_error)?;
+        match handle.kernel_driver_active(0) {
+            Ok(active) => Ok(active),
+            // Only Linux can tell.
+            Err(rusb::Error::NotSupported) => Ok(false),
+            Err(_) => Err(Error::CheckInterface),
+        }
+    }
+
+    // Notifies bootloader devices that `selector` matches as they are
+    // connected, including ones that are already connected.
+    pub fn watch(selector: &Selector) -> Result<Receiver<Location>, Error> {
+        if !rusb::has_hotplug() {
+            return Err(Error::NotSupported("this platform"));
+        }
+        let context = rusb::Context::new().map_err(|_| Error::ListDevices)?;
+        let (sender, receiver) = channel();
+        let watcher: Box<dyn Hotplug<rusb::Context>> = Box::new(Watcher {
+            selector: selector.clone(),
+            sender,
+        });
+        let registration = HotplugBuilder::new()
+            .vendor_id(selector.vid.unwrap_or(VID))
+            .product_id(selector.pid.unwrap_or(PID))
+            .enumerate(true)
+            .register(&context, watcher)
+            .map_err(|_| Error::ListDevices)?;
+        std::thread::spawn(move || {
+            // Callbacks are called while events are handled.
+            let _registration = registration;
+            while context.handle_events(None).is_ok() {}
+        });
+        Ok(receiver)
+    }
+
+    fn devices(
+        selector: &Selector,
+    ) -> Result<Vec<(rusb::Device<rusb::GlobalContext>, Location)>, Error> {
+        let devices = rusb::devices().map_err(|_| Error::ListDevices)?;
+        Ok(devices
+            .iter()
+            .filter(|device| {
+                device
+                    .device_descriptor()
+                    .is_ok_and(|desc| selector.matches_ids(desc.vendor_id(), desc.product_id()))
+            })
+            .map(|device| {
+                let ports = device.port_numbers().unwrap_or_default();
+                let location = Location::new(device.bus_number(), device.address(), &ports);
+                (device, location)
+            })
+            .filter(|(_, location)| selector.matches(location))
+            .collect())
+    }
+
+    pub fn new(handle: rusb::DeviceHandle<rusb::GlobalContext>) -> Result<Self, Error> {
+        let device = handle.device();
+        let config = device.config_descriptor(0);
+        let config_number;
+        let interface_number;
+        let mut ep_in = 0;
+        let mut ep_out = 0;
+        if let Ok(config) = config {
+            config_number = config.number();
+            if let Some(interface) = config.interfaces().next() {
+                interface_number = interface.number();
+                if let Some(desc) = interface.descriptors().next() {
+                    let mut ep_in_found = false;
+                    let mut ep_in_type = rusb::TransferType::Bulk;
+                    let mut ep_out_found = false;
+                    let mut ep_out_type = rusb::TransferType::Bulk;
+                    for ep in desc.endpoint_descriptors() {
+                        match ep.direction() {
+                            rusb::Direction::In => {
+                                ep_in = ep.address();
+                                ep_in_type = ep.transfer_type();
+                                ep_in_found = true;
+                            }
+                            rusb::Direction::Out => {
+                                ep_out = ep.address();
+                                ep_out_type = ep.transfer_type();
+                                ep_out_found = true;
+                            }
+                        }
+                    }
+                    if !ep_in_found
+                        || !ep_out_found
+                        || ep_in_type != rusb::TransferType::Bulk
+                        || ep_out_type != rusb::TransferType::Bulk
+                    {
+                        return Err(Error::DetectEp);
+                    }
+                }
+            } else {
+                return Err(Error::CheckInterface);
+            }
+        } else {
+            return Err(Error::CheckConfiguration);
+        }
+        if handle.set_active_configuration(config_number).is_err() {
+            return Err(Error::ActivateConfiguration);
+        }
+        match handle.claim_interface(interface_number) {
+            Ok(()) => {}
+            Err(rusb::Error::Access) => return Err(Error::AccessDenied),
+            Err(rusb::Error::NotSupported) | Err(rusb::Error::NotFound) => {
+                return Err(Error::NoDriver)
+            }
+            Err(_) => return Err(Error::ClaimInterface),
+        }
+        Ok(UsbTransport {
+            handle,
+            ep_in,
+            ep_out,
+        })
+    }
+}
+
+// Tells a device that can not be accessed from one without a driver that
+// libusb can use, e.g. WinUSB on Windows.
+fn open_error(error: rusb::Error) -> Error {
+    match error {
+        rusb::Error::Access => Error::AccessDenied,
+        rusb::Error::NotSupported | rusb::Error::NotFound => Error::NoDriver,
+        _ => Error::Open,
+    }
+}
+
+struct Watcher {
+    selector: Selector,
+    sender: Sender<Location>,
+}
+
+impl<T: UsbContext> Hotplug<T> for Watcher {
+    fn device_arrived(&mut self, device: rusb::Device<T>) {
+        let ports = device.port_numbers().unwrap_or_default();
+        let location = Location::new(device.bus_number(), device.address(), &ports);
+        if self.selector.matches(&location) {
+            let _ = self.sender.send(location);
+        }
+    }
+
+    fn device_left(&mut self, _device: rusb::Device<T>) {}
+}
+
+impl Transport for UsbTransport {
+    fn send(&mut self, request: &[u8]) -> Result<(), Error> {
+        let size = self
+            .handle
+            .write_bulk(self.ep_out, request, core::time::Duration::new(1, 0))
+            .map_err(|_| Error::BulkWrite)?;
+        if size != request.len() {
+            return Err(Error::BulkWriteAll);
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, response: &mut [u8]) -> Result<(), Error> {
+        let size = self
+            .handle
+            .read_bulk(self.ep_in, response, core::time::Duration::new(1, 0))
+            .map_err(|e| Error::BulkRead(e.to_string()))?;
+        // Callers index the response, so a short one is refused.
+        if size < response.len() {
+            return Err(Error::BulkRead(format!(
+                "{} of {} bytes",
+                size,
+                response.len()
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright 2022 Takashi Toyoshima <toyoshim@gmail.com>.
+// Use of this source code is governed by a BSD-style license that can be found
+// in the LICENSE file.
+use std::ffi::c_void;
+
+use crate::ch559::Error;
+use crate::transport::Transport;
+
+// DLL that comes with the WCH vendor driver, i.e. CH375WDM.
+#[cfg(target_pointer_width = "64")]
+const LIBRARY: &str = "CH375DLL64.dll";
+#[cfg(not(target_pointer_width = "64"))]
+const LIBRARY: &str = "CH375DLL.dll";
+
+// Returns INVALID_HANDLE_VALUE, i.e. -1, on failures.
+type OpenDevice = unsafe extern "system" fn(index: u32) -> isize;
+type CloseDevice = unsafe extern "system" fn(index: u32);
+type SetTimeout = unsafe extern "system" fn(index: u32, write: u32, read: u32) -> i32;
+// Transfers data over the bulk endpoints. `length` is updated to the size
+// actually transferred.
+type Transfer = unsafe extern "system" fn(index: u32, buffer: *mut c_void, length: *mut u32) -> i32;
+
+// USB transport over the WCH vendor driver, so that Windows users do not have
+// to replace it with WinUSB for libusb. `index` is the device number that the
+// driver assigns from 0.
+pub struct WchTransport {
+    index: u32,
+    close: CloseDevice,
+    write: Transfer,
+    read: Transfer,
+    // Keeps the functions above loaded.
+    _library: libloading::Library,
+}
+
+impl WchTransport {
+    pub fn open(index: u32) -> Result<Self, Error> {
+        let error = |e: libloading::Error| Error::VendorDriver(format!("{}: {}", LIBRARY, e));
+        // SAFETY: the DLL does not run initialization that has requirements,
+        // and the signatures follow its header, CH375DLL.H.
+        unsafe {
+            let library = libloading::Library::new(LIBRARY).map_err(error)?;
+            let open = *library
+                .get::<OpenDevice>(b"CH375OpenDevice\0")
+                .map_err(error)?;
+            let close = *library
+                .get::<CloseDevice>(b"CH375CloseDevice\0")
+                .map_err(error)?;
+            let set_timeout = *library
+                .get::<SetTimeout>(b"CH375SetTimeout\0")
+                .map_err(error)?;
+            let write = *library
+                .get::<Transfer>(b"CH375WriteData\0")
+                .map_err(error)?;
+            let read = *library.get::<Transfer>(b"CH375ReadData\0").map_err(error)?;
+            if open(index) == -1 {
+                return Err(Error::NotFound);
+            }
+            set_timeout(index, 1000, 1000);
+            Ok(WchTransport {
+                index,
+                close,
+                write,
+                read,
+                _library: library,
+            })
+        }
+    }
+}
+
+impl Drop for WchTransport {
+    fn drop(&mut self) {
+        // SAFETY: the device is opened in `open`.
+        unsafe { (self.close)(self.index) }
+    }
+}
+
+impl Transport for WchTransport {
+    fn send(&mut self, request: &[u8]) -> Result<(), Error> {
+        let mut buffer = request.to_vec();
+        let mut length = buffer.len() as u32;
+        // SAFETY: `length` is the size of `buffer`.
+        let ok = unsafe { (self.write)(self.index, buffer.as_mut_ptr().cast(), &mut length) };
+        if ok == 0 {
+            return Err(Error::BulkWrite);
+        }
+        if length as usize != request.len() {
+            return Err(Error::BulkWriteAll);
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, response: &mut [u8]) -> Result<(), Error> {
+        // Requests a full packet at least so that a response never overflows.
+        let mut buffer = vec![0; response.len().max(64)];
+        let mut length = buffer.len() as u32;
+        // SAFETY: `length` is the size of `buffer`.
+        let ok = unsafe { (self.read)(self.index, buffer.as_mut_ptr().cast(), &mut length) };
+        if ok == 0 {
+            return Err(Error::BulkRead(String::from("vendor driver")));
+        }
+        // Callers index the response, so one of another size is refused
+        // rather than truncated or left short.
+        let size = length as usize;
+        if size != response.len() {
+            return Err(Error::BulkRead(format!(
+                "{} of {} bytes",
+                size,
+                response.len()
+            )));
+        }
+        response.copy_from_slice(&buffer[..size]);
+        Ok(())
+    }
+}